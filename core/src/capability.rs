@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
+use crate::{Controllable, EventKind, EventPublisher};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    Occupancy,
+    OnOff,
+    Brightness,
+    ColorTemperature,
+    Color,
+    Contact,
+    Temperature,
+    Humidity,
+    Illuminance,
+    WindowCovering,
+    Lock,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 11] = [
+        Capability::Occupancy,
+        Capability::OnOff,
+        Capability::Brightness,
+        Capability::ColorTemperature,
+        Capability::Color,
+        Capability::Contact,
+        Capability::Temperature,
+        Capability::Humidity,
+        Capability::Illuminance,
+        Capability::WindowCovering,
+        Capability::Lock,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Occupancy => "occupancy",
+            Capability::OnOff => "on_off",
+            Capability::Brightness => "brightness",
+            Capability::ColorTemperature => "color_temperature",
+            Capability::Color => "color",
+            Capability::Contact => "contact",
+            Capability::Temperature => "temperature",
+            Capability::Humidity => "humidity",
+            Capability::Illuminance => "illuminance",
+            Capability::WindowCovering => "window_covering",
+            Capability::Lock => "lock",
+        }
+    }
+
+    /// Whether the capability describes something that can be set, rather than only sensed.
+    pub fn is_controllable(&self) -> bool {
+        matches!(self,
+            Capability::OnOff
+            | Capability::Brightness
+            | Capability::ColorTemperature
+            | Capability::Color
+            | Capability::WindowCovering
+            | Capability::Lock)
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Capability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Capability::ALL.into_iter()
+            .find(|capability| capability.name() == s)
+            .ok_or_else(|| format!("Unknown capability: {}", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct HueSaturation {
+    /// Degrees, 0-360.
+    pub hue: f32,
+    /// Percent, 0-100.
+    pub saturation: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContactState {
+    Open,
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockState {
+    Unlocked,
+    Locked,
+    Jammed,
+}
+
+/// A reading of a single capability.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "capability", content = "value", rename_all = "snake_case")]
+pub enum CapabilityValue {
+    Occupancy(bool),
+    OnOff(bool),
+    /// Percent, 0-100.
+    Brightness(u8),
+    /// Kelvin.
+    ColorTemperature(u16),
+    Color(HueSaturation),
+    Contact(ContactState),
+    /// Degrees celsius.
+    Temperature(f32),
+    /// Relative humidity in percent.
+    Humidity(f32),
+    /// Lux.
+    Illuminance(f32),
+    /// Position in percent, 0 is closed and 100 fully open.
+    WindowCovering(u8),
+    Lock(LockState),
+}
+
+impl CapabilityValue {
+    pub fn capability(&self) -> Capability {
+        match self {
+            CapabilityValue::Occupancy(_) => Capability::Occupancy,
+            CapabilityValue::OnOff(_) => Capability::OnOff,
+            CapabilityValue::Brightness(_) => Capability::Brightness,
+            CapabilityValue::ColorTemperature(_) => Capability::ColorTemperature,
+            CapabilityValue::Color(_) => Capability::Color,
+            CapabilityValue::Contact(_) => Capability::Contact,
+            CapabilityValue::Temperature(_) => Capability::Temperature,
+            CapabilityValue::Humidity(_) => Capability::Humidity,
+            CapabilityValue::Illuminance(_) => Capability::Illuminance,
+            CapabilityValue::WindowCovering(_) => Capability::WindowCovering,
+            CapabilityValue::Lock(_) => Capability::Lock,
+        }
+    }
+}
+
+// typed capabilities, `None` until the device has reported a value
+
+pub trait Occupancy {
+    fn occupied(&self) -> Option<bool>;
+}
+
+pub trait OnOff {
+    fn is_on(&self) -> Option<bool>;
+}
+
+pub trait Brightness {
+    fn brightness(&self) -> Option<u8>;
+}
+
+pub trait ColorTemperature {
+    fn color_temperature(&self) -> Option<u16>;
+}
+
+pub trait Color {
+    fn color(&self) -> Option<HueSaturation>;
+}
+
+pub trait Contact {
+    fn contact(&self) -> Option<ContactState>;
+}
+
+pub trait Temperature {
+    fn temperature(&self) -> Option<f32>;
+}
+
+pub trait Humidity {
+    fn humidity(&self) -> Option<f32>;
+}
+
+pub trait Illuminance {
+    fn illuminance(&self) -> Option<f32>;
+}
+
+pub trait WindowCovering {
+    fn position(&self) -> Option<u8>;
+}
+
+pub trait Lock {
+    fn lock_state(&self) -> Option<LockState>;
+}
+
+/// Runtime view of what a device can do. Devices return themselves from the accessors of the capabilities they have.
+pub trait Capabilities {
+    fn as_occupancy(&self) -> Option<&dyn Occupancy> { None }
+    fn as_on_off(&self) -> Option<&dyn OnOff> { None }
+    fn as_brightness(&self) -> Option<&dyn Brightness> { None }
+    fn as_color_temperature(&self) -> Option<&dyn ColorTemperature> { None }
+    fn as_color(&self) -> Option<&dyn Color> { None }
+    fn as_contact(&self) -> Option<&dyn Contact> { None }
+    fn as_temperature(&self) -> Option<&dyn Temperature> { None }
+    fn as_humidity(&self) -> Option<&dyn Humidity> { None }
+    fn as_illuminance(&self) -> Option<&dyn Illuminance> { None }
+    fn as_window_covering(&self) -> Option<&dyn WindowCovering> { None }
+    fn as_lock(&self) -> Option<&dyn Lock> { None }
+
+    /// How the device takes commands, `None` for devices that only sense.
+    fn as_controllable(&self) -> Option<&dyn Controllable> { None }
+
+    fn has_capability(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Occupancy => self.as_occupancy().is_some(),
+            Capability::OnOff => self.as_on_off().is_some(),
+            Capability::Brightness => self.as_brightness().is_some(),
+            Capability::ColorTemperature => self.as_color_temperature().is_some(),
+            Capability::Color => self.as_color().is_some(),
+            Capability::Contact => self.as_contact().is_some(),
+            Capability::Temperature => self.as_temperature().is_some(),
+            Capability::Humidity => self.as_humidity().is_some(),
+            Capability::Illuminance => self.as_illuminance().is_some(),
+            Capability::WindowCovering => self.as_window_covering().is_some(),
+            Capability::Lock => self.as_lock().is_some(),
+        }
+    }
+
+    fn capabilities(&self) -> Vec<Capability> {
+        Capability::ALL.into_iter()
+            .filter(|capability| self.has_capability(*capability))
+            .collect()
+    }
+
+    /// Current value of a capability, `None` if the device lacks it or hasn't reported it yet.
+    fn state(&self, capability: Capability) -> Option<CapabilityValue> {
+        match capability {
+            Capability::Occupancy => self.as_occupancy()?.occupied().map(CapabilityValue::Occupancy),
+            Capability::OnOff => self.as_on_off()?.is_on().map(CapabilityValue::OnOff),
+            Capability::Brightness => self.as_brightness()?.brightness().map(CapabilityValue::Brightness),
+            Capability::ColorTemperature => self.as_color_temperature()?.color_temperature().map(CapabilityValue::ColorTemperature),
+            Capability::Color => self.as_color()?.color().map(CapabilityValue::Color),
+            Capability::Contact => self.as_contact()?.contact().map(CapabilityValue::Contact),
+            Capability::Temperature => self.as_temperature()?.temperature().map(CapabilityValue::Temperature),
+            Capability::Humidity => self.as_humidity()?.humidity().map(CapabilityValue::Humidity),
+            Capability::Illuminance => self.as_illuminance()?.illuminance().map(CapabilityValue::Illuminance),
+            Capability::WindowCovering => self.as_window_covering()?.position().map(CapabilityValue::WindowCovering),
+            Capability::Lock => self.as_lock()?.lock_state().map(CapabilityValue::Lock),
+        }
+    }
+}
+
+/// Last reported value per capability, for drivers to keep their device state in.
+///
+/// Once bound to an event publisher, every change is announced as a `CapabilityChanged` event.
+#[derive(Debug, Default)]
+pub struct CapabilityState {
+    values: Mutex<HashMap<Capability, CapabilityValue>>,
+    publisher: Mutex<Option<EventPublisher>>,
+}
+
+impl CapabilityState {
+    pub fn bind(&self, publisher: EventPublisher) {
+        *self.publisher.lock().unwrap() = Some(publisher);
+    }
+
+    /// Publishes an event for the device, if the state is bound.
+    pub fn publish(&self, kind: EventKind) {
+        if let Some(publisher) = self.publisher.lock().unwrap().as_ref() {
+            publisher.publish(kind);
+        }
+    }
+
+    pub fn get(&self, capability: Capability) -> Option<CapabilityValue> {
+        self.values.lock().unwrap().get(&capability).copied()
+    }
+
+    /// Records a new value, returning whether it differs from the previous one.
+    pub fn set(&self, value: CapabilityValue) -> bool {
+        let previous = self.values.lock().unwrap().insert(value.capability(), value);
+        let changed = previous != Some(value);
+        if changed {
+            self.publish(EventKind::CapabilityChanged { value, previous });
+        }
+        changed
+    }
+
+    pub fn occupied(&self) -> Option<bool> {
+        match self.get(Capability::Occupancy)? {
+            CapabilityValue::Occupancy(occupied) => Some(occupied),
+            _ => None,
+        }
+    }
+
+    pub fn is_on(&self) -> Option<bool> {
+        match self.get(Capability::OnOff)? {
+            CapabilityValue::OnOff(on) => Some(on),
+            _ => None,
+        }
+    }
+
+    pub fn brightness(&self) -> Option<u8> {
+        match self.get(Capability::Brightness)? {
+            CapabilityValue::Brightness(brightness) => Some(brightness),
+            _ => None,
+        }
+    }
+
+    pub fn color_temperature(&self) -> Option<u16> {
+        match self.get(Capability::ColorTemperature)? {
+            CapabilityValue::ColorTemperature(kelvin) => Some(kelvin),
+            _ => None,
+        }
+    }
+
+    pub fn color(&self) -> Option<HueSaturation> {
+        match self.get(Capability::Color)? {
+            CapabilityValue::Color(color) => Some(color),
+            _ => None,
+        }
+    }
+
+    pub fn contact(&self) -> Option<ContactState> {
+        match self.get(Capability::Contact)? {
+            CapabilityValue::Contact(contact) => Some(contact),
+            _ => None,
+        }
+    }
+
+    pub fn temperature(&self) -> Option<f32> {
+        match self.get(Capability::Temperature)? {
+            CapabilityValue::Temperature(celsius) => Some(celsius),
+            _ => None,
+        }
+    }
+
+    pub fn humidity(&self) -> Option<f32> {
+        match self.get(Capability::Humidity)? {
+            CapabilityValue::Humidity(percent) => Some(percent),
+            _ => None,
+        }
+    }
+
+    pub fn illuminance(&self) -> Option<f32> {
+        match self.get(Capability::Illuminance)? {
+            CapabilityValue::Illuminance(lux) => Some(lux),
+            _ => None,
+        }
+    }
+
+    pub fn position(&self) -> Option<u8> {
+        match self.get(Capability::WindowCovering)? {
+            CapabilityValue::WindowCovering(position) => Some(position),
+            _ => None,
+        }
+    }
+
+    pub fn lock_state(&self) -> Option<LockState> {
+        match self.get(Capability::Lock)? {
+            CapabilityValue::Lock(state) => Some(state),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Light {
+        state: CapabilityState,
+    }
+
+    impl OnOff for Light {
+        fn is_on(&self) -> Option<bool> {
+            self.state.is_on()
+        }
+    }
+
+    impl Brightness for Light {
+        fn brightness(&self) -> Option<u8> {
+            self.state.brightness()
+        }
+    }
+
+    impl Capabilities for Light {
+        fn as_on_off(&self) -> Option<&dyn OnOff> { Some(self) }
+        fn as_brightness(&self) -> Option<&dyn Brightness> { Some(self) }
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let light = Light::default();
+        let device: &dyn Capabilities = &light;
+
+        assert_eq!(device.capabilities(), vec![Capability::OnOff, Capability::Brightness]);
+        assert!(!device.has_capability(Capability::Occupancy));
+        assert_eq!(device.state(Capability::OnOff), None);
+
+        assert!(light.state.set(CapabilityValue::OnOff(true)));
+        assert!(!light.state.set(CapabilityValue::OnOff(true)));
+        assert_eq!(device.state(Capability::OnOff), Some(CapabilityValue::OnOff(true)));
+        assert_eq!(device.as_on_off().unwrap().is_on(), Some(true));
+    }
+
+    #[test]
+    fn test_capability_names() {
+        for capability in Capability::ALL {
+            assert_eq!(capability.name().parse::<Capability>(), Ok(capability));
+        }
+    }
+}
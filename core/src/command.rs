@@ -0,0 +1,134 @@
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+use crate::{Capability, CapabilityValue, DynDevice, LocalBoxFuture};
+
+/// Devices that can be told to change state. The driver translates the target value into its protocol.
+pub trait Controllable {
+    /// Sends the new value to the device, resolving once the device accepted it.
+    fn execute(&self, target: CapabilityValue) -> LocalBoxFuture<'_, Result<(), Box<dyn Error>>>;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CommandOptions {
+    /// How long the whole command may take, confirmation included.
+    pub timeout: Duration,
+    /// Wait until the device reports the new value, instead of just until it accepted the command.
+    pub confirm: bool,
+    /// How often the device state is checked while waiting for confirmation.
+    pub poll_interval: Duration,
+}
+
+impl Default for CommandOptions {
+    fn default() -> Self {
+        CommandOptions {
+            timeout: Duration::from_secs(10),
+            confirm: false,
+            poll_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    /// The device doesn't have the capability, or can't be controlled through it.
+    Unsupported(Capability),
+    Failed(Box<dyn Error>),
+    Timeout,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Unsupported(capability) => write!(f, "Device can't be controlled through {}", capability),
+            CommandError::Failed(error) => write!(f, "Command failed: {}", error),
+            CommandError::Timeout => write!(f, "Command timed out"),
+        }
+    }
+}
+
+impl Error for CommandError {}
+
+impl<'a> dyn DynDevice + 'a {
+    /// Sets a capability of the device, e.g. `CapabilityValue::Brightness(40)`.
+    pub async fn command(&self, target: CapabilityValue, options: CommandOptions) -> Result<(), CommandError> {
+        let capability = target.capability();
+        let controllable = self.as_controllable()
+            .filter(|_| capability.is_controllable() && self.has_capability(capability))
+            .ok_or(CommandError::Unsupported(capability))?;
+
+        let run = async {
+            controllable.execute(target).await.map_err(CommandError::Failed)?;
+            if options.confirm {
+                while self.state(capability) != Some(target) {
+                    tokio::time::sleep(options.poll_interval).await;
+                }
+            }
+            Ok(())
+        };
+
+        tokio::time::timeout(options.timeout, run).await
+            .map_err(|_| CommandError::Timeout)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Capabilities, CapabilityState, Device, LifeCycle, OnOff, Brightness};
+
+    /// Accepts every command, but only applies brightness changes.
+    #[derive(Default)]
+    struct Dimmer {
+        state: CapabilityState,
+    }
+
+    impl LifeCycle for Dimmer {
+        async fn init(&self) -> Result<(), Box<dyn Error>> { Ok(()) }
+        async fn dispose(&self) -> Result<(), Box<dyn Error>> { Ok(()) }
+    }
+
+    impl OnOff for Dimmer {
+        fn is_on(&self) -> Option<bool> { self.state.is_on() }
+    }
+
+    impl Brightness for Dimmer {
+        fn brightness(&self) -> Option<u8> { self.state.brightness() }
+    }
+
+    impl Controllable for Dimmer {
+        fn execute(&self, target: CapabilityValue) -> LocalBoxFuture<'_, Result<(), Box<dyn Error>>> {
+            Box::pin(async move {
+                if let CapabilityValue::Brightness(_) = target {
+                    self.state.set(target);
+                }
+                Ok(())
+            })
+        }
+    }
+
+    impl Capabilities for Dimmer {
+        fn as_on_off(&self) -> Option<&dyn OnOff> { Some(self) }
+        fn as_brightness(&self) -> Option<&dyn Brightness> { Some(self) }
+        fn as_controllable(&self) -> Option<&dyn Controllable> { Some(self) }
+    }
+
+    impl Device for Dimmer {}
+
+    #[tokio::test(start_paused = true)]
+    async fn test_command() {
+        let dimmer = Dimmer::default();
+        let device: &dyn DynDevice = &dimmer;
+        let confirm = CommandOptions { confirm: true, ..Default::default() };
+
+        device.command(CapabilityValue::Brightness(40), confirm).await.unwrap();
+        assert_eq!(dimmer.brightness(), Some(40));
+
+        assert!(device.command(CapabilityValue::OnOff(true), CommandOptions::default()).await.is_ok());
+        assert!(matches!(device.command(CapabilityValue::OnOff(true), confirm).await, Err(CommandError::Timeout)));
+        assert!(matches!(
+            device.command(CapabilityValue::Lock(crate::LockState::Locked), confirm).await,
+            Err(CommandError::Unsupported(Capability::Lock))
+        ));
+    }
+}
@@ -1,23 +1,121 @@
-use std::fmt::Display;
-use crate::LifeCycle;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::any::Any;
+use std::time::SystemTime;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::{LifeCycle, DynLifeCycle, Capabilities, CapabilityValue, DiscoveryOptions, DiscoveryStream, EventPublisher, Health};
 
 pub trait DiscoveryInfo {
     fn name(&self) -> &str;
     fn id(&self) -> &str;
 }
 
-pub trait DeviceProperties : Display {
+/// The persisted record of a paired device, enough to `attach` it again after a restart.
+pub trait DeviceProperties : Serialize + DeserializeOwned {
+    fn id(&self) -> &str;
+    fn name(&self) -> &str;
+    fn driver(&self) -> &str;
+    fn address(&self) -> Option<SocketAddr>;
+    /// Reference to the pairing the device holds for us, if the protocol has one.
+    fn pairing(&self) -> Option<&str>;
+
+    fn to_toml(&self) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    fn from_toml(text: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(toml::from_str(text)?)
+    }
+
+    fn save(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(path, self.to_toml()?)?;
+        Ok(())
+    }
+
+    fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read device record {}: {}", path.display(), e))?;
+        Self::from_toml(&text)
+            .map_err(|e| format!("Invalid device record {}: {}", path.display(), e).into())
+    }
+}
+
+pub trait Device : LifeCycle + Capabilities {
+    /// Hands the device the publisher for its path, to announce its state changes with.
+    fn bind_events(&self, _publisher: EventPublisher) {}
+
+    /// Whether the device can currently be reached. Devices that don't track it are taken to be online.
+    fn health(&self) -> Health {
+        Health::Online
+    }
+
+    fn last_seen(&self) -> Option<SystemTime> {
+        None
+    }
+
+    /// Takes a value remembered from before a restart, to go by until the device reports a fresh one.
+    /// Devices that don't keep state ignore it.
+    fn restore(&self, _value: CapabilityValue) {}
 }
 
-pub trait Device : LifeCycle {
+/// Object-safe face of `Device`, implemented for every `Device`.
+pub trait DynDevice : DynLifeCycle + Capabilities {
+    fn bind_events(&self, publisher: EventPublisher);
+    fn health(&self) -> Health;
+    fn last_seen(&self) -> Option<SystemTime>;
+    fn restore(&self, value: CapabilityValue);
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<D: Device + 'static> DynDevice for D {
+    fn bind_events(&self, publisher: EventPublisher) {
+        Device::bind_events(self, publisher)
+    }
+
+    fn health(&self) -> Health {
+        Device::health(self)
+    }
+
+    fn last_seen(&self) -> Option<SystemTime> {
+        Device::last_seen(self)
+    }
+
+    fn restore(&self, value: CapabilityValue) {
+        Device::restore(self, value)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 
 
 #[allow(async_fn_in_trait)]
 pub trait Driver<I: DiscoveryInfo, P: DeviceProperties, D: Device> {
-    async fn discover(&self) -> Vec<I>;
+    /// Starts looking for devices, yielding them as they are found until the timeout passes or the options are cancelled.
+    async fn discover(&self, options: DiscoveryOptions) -> Result<DiscoveryStream<I>, Box<dyn std::error::Error>>;
     async fn pair(&self, discovery: &I) -> Result<P, Box<dyn std::error::Error>>;
 
-    //async fn create(info: I) -> D;
-}
\ No newline at end of file
+    /// Brings a previously paired device back to life from its persisted properties, without re-pairing.
+    async fn attach(&self, properties: &P) -> Result<D, Box<dyn std::error::Error>>;
+
+    /// Removes the pairing from the device, after which its properties can be forgotten.
+    async fn unpair(&self, properties: &P) -> Result<(), Box<dyn std::error::Error>>;
+}
+/// Devices `domus!` can build: it sets the properties it is given and takes the rest from `Default`.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` needs to implement `Default` to be declared in `domus!`",
+    label = "the properties that aren't given are taken from `Default`",
+    note = "derive or implement `Default` for `{Self}`",
+)]
+pub trait DeclaredDevice: Default {}
+
+impl<T: Default> DeclaredDevice for T {}
+
+/// The properties of a device that `domus!` wasn't given.
+#[doc(hidden)]
+pub fn declared_defaults<T: DeclaredDevice>() -> T {
+    T::default()
+}
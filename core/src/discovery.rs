@@ -0,0 +1,33 @@
+use std::time::Duration;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// Devices found by a running discovery, yielded as they arrive.
+pub type DiscoveryStream<I> = BoxStream<'static, I>;
+
+#[derive(Debug, Clone)]
+pub struct DiscoveryOptions {
+    /// How long to keep looking, `None` to run until cancelled.
+    pub timeout: Option<Duration>,
+    /// Network interface to discover on, all interfaces if `None`.
+    pub interface: Option<String>,
+    pub cancel: CancellationToken,
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        DiscoveryOptions {
+            timeout: Some(Duration::from_secs(5)),
+            interface: None,
+            cancel: CancellationToken::new(),
+        }
+    }
+}
+
+/// Turns the receiving end of a discovery task into a `DiscoveryStream`.
+pub fn discovery_stream<I: Send + 'static>(receiver: mpsc::UnboundedReceiver<I>) -> DiscoveryStream<I> {
+    stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|item| (item, receiver))
+    }).boxed()
+}
@@ -0,0 +1,157 @@
+use std::any::Any;
+use std::error::Error;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use futures_util::StreamExt;
+use crate::{Capability, DiscoveryInfo, DeviceProperties, Device, DynDevice, Driver, DiscoveryOptions, DiscoveryStream, LocalBoxFuture};
+
+/// What a driver registers itself with, so it can be listed and selected by name.
+#[derive(Debug, Clone)]
+pub struct DriverInfo {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub capabilities: &'static [Capability],
+}
+
+/// Object-safe face of `DiscoveryInfo`.
+pub trait DynDiscovery : DiscoveryInfo + Send {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<I: DiscoveryInfo + Send + 'static> DynDiscovery for I {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub type DynDiscoveryStream = DiscoveryStream<Box<dyn DynDiscovery>>;
+
+/// Object-safe face of `DeviceProperties`.
+pub trait DynProperties {
+    fn id(&self) -> &str;
+    fn name(&self) -> &str;
+    fn driver(&self) -> &str;
+    fn address(&self) -> Option<SocketAddr>;
+    fn pairing(&self) -> Option<&str>;
+    fn to_toml(&self) -> Result<String, Box<dyn Error>>;
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<P: DeviceProperties + 'static> DynProperties for P {
+    fn id(&self) -> &str {
+        DeviceProperties::id(self)
+    }
+
+    fn name(&self) -> &str {
+        DeviceProperties::name(self)
+    }
+
+    fn driver(&self) -> &str {
+        DeviceProperties::driver(self)
+    }
+
+    fn address(&self) -> Option<SocketAddr> {
+        DeviceProperties::address(self)
+    }
+
+    fn pairing(&self) -> Option<&str> {
+        DeviceProperties::pairing(self)
+    }
+
+    fn to_toml(&self) -> Result<String, Box<dyn Error>> {
+        DeviceProperties::to_toml(self)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Type-erased `Driver`, so drivers with different discovery, properties and device types can sit side by side.
+pub trait DynDriver {
+    fn info(&self) -> &DriverInfo;
+
+    fn name(&self) -> &str {
+        self.info().name
+    }
+
+    fn discover(&self, options: DiscoveryOptions) -> LocalBoxFuture<'_, Result<DynDiscoveryStream, Box<dyn Error>>>;
+    fn pair<'a>(&'a self, discovery: &'a dyn DynDiscovery) -> LocalBoxFuture<'a, Result<Box<dyn DynProperties>, Box<dyn Error>>>;
+    fn attach<'a>(&'a self, properties: &'a dyn DynProperties) -> LocalBoxFuture<'a, Result<Box<dyn DynDevice>, Box<dyn Error>>>;
+    fn unpair<'a>(&'a self, properties: &'a dyn DynProperties) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>>;
+
+    /// Reads a device record written by this driver.
+    fn load(&self, text: &str) -> Result<Box<dyn DynProperties>, Box<dyn Error>>;
+}
+
+/// Wraps a `Driver` into a `DynDriver`.
+pub struct DriverAdapter<T, I, P, D> {
+    info: DriverInfo,
+    driver: T,
+    _types: PhantomData<(I, P, D)>,
+}
+
+impl<T, I, P, D> DriverAdapter<T, I, P, D>
+where
+    T: Driver<I, P, D>,
+    I: DiscoveryInfo + Send + 'static,
+    P: DeviceProperties + 'static,
+    D: Device + 'static,
+{
+    pub fn new(info: DriverInfo, driver: T) -> Self {
+        DriverAdapter { info, driver, _types: PhantomData }
+    }
+
+    fn downcast<'a, X: 'static>(&self, value: &'a dyn Any, what: &str) -> Result<&'a X, Box<dyn Error>> {
+        value.downcast_ref::<X>()
+            .ok_or_else(|| format!("{} does not belong to driver {}", what, self.info.name).into())
+    }
+}
+
+impl<T, I, P, D> DynDriver for DriverAdapter<T, I, P, D>
+where
+    T: Driver<I, P, D>,
+    I: DiscoveryInfo + Send + 'static,
+    P: DeviceProperties + 'static,
+    D: Device + 'static,
+{
+    fn info(&self) -> &DriverInfo {
+        &self.info
+    }
+
+    fn discover(&self, options: DiscoveryOptions) -> LocalBoxFuture<'_, Result<DynDiscoveryStream, Box<dyn Error>>> {
+        Box::pin(async move {
+            let discoveries = self.driver.discover(options).await?;
+            Ok(discoveries
+                .map(|discovery| Box::new(discovery) as Box<dyn DynDiscovery>)
+                .boxed())
+        })
+    }
+
+    fn pair<'a>(&'a self, discovery: &'a dyn DynDiscovery) -> LocalBoxFuture<'a, Result<Box<dyn DynProperties>, Box<dyn Error>>> {
+        Box::pin(async move {
+            let discovery = self.downcast::<I>(discovery.as_any(), "Discovery")?;
+            let properties = self.driver.pair(discovery).await?;
+            Ok(Box::new(properties) as Box<dyn DynProperties>)
+        })
+    }
+
+    fn attach<'a>(&'a self, properties: &'a dyn DynProperties) -> LocalBoxFuture<'a, Result<Box<dyn DynDevice>, Box<dyn Error>>> {
+        Box::pin(async move {
+            let properties = self.downcast::<P>(properties.as_any(), "Device record")?;
+            let device = self.driver.attach(properties).await?;
+            Ok(Box::new(device) as Box<dyn DynDevice>)
+        })
+    }
+
+    fn unpair<'a>(&'a self, properties: &'a dyn DynProperties) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>> {
+        Box::pin(async move {
+            let properties = self.downcast::<P>(properties.as_any(), "Device record")?;
+            self.driver.unpair(properties).await
+        })
+    }
+
+    fn load(&self, text: &str) -> Result<Box<dyn DynProperties>, Box<dyn Error>> {
+        Ok(Box::new(P::from_toml(text)?))
+    }
+}
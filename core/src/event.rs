@@ -0,0 +1,283 @@
+use std::sync::OnceLock;
+use std::time::SystemTime;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast::{self, error::{RecvError, TryRecvError}};
+use crate::{Capability, CapabilityValue, PATH_SEPARATOR, join_path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LifeCycleStage {
+    Initializing,
+    Initialized,
+    Disposing,
+    Disposed,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EventKind {
+    CapabilityChanged {
+        value: CapabilityValue,
+        previous: Option<CapabilityValue>,
+    },
+    DeviceOnline,
+    /// Still reachable, but late or misbehaving.
+    DeviceDegraded {
+        reason: String,
+    },
+    DeviceOffline {
+        reason: String,
+    },
+    PresenceChanged {
+        previous: f32,
+        probability: f32,
+    },
+    LifeCycle {
+        stage: LifeCycleStage,
+    },
+    /// A schedule came due, `due` being when it was meant to run, earlier than now when catching up.
+    Scheduled {
+        schedule: String,
+        due: SystemTime,
+    },
+    /// A state machine attached to the space moved to another state.
+    StateChanged {
+        machine: String,
+        from: String,
+        to: String,
+    },
+    /// The rule's triggers fired and its conditions held.
+    RuleFired {
+        rule: String,
+    },
+    /// An automation told the device to take the value.
+    CommandSent {
+        value: CapabilityValue,
+    },
+}
+
+/// The time events are stamped with: the wall clock when first asked, moved along by tokio's clock since.
+///
+/// On the paused clock of a simulation it runs as fast as the simulation, so recordings and history keep
+/// the gaps the automations saw rather than the real ones.
+pub fn now() -> SystemTime {
+    static START: OnceLock<(SystemTime, tokio::time::Instant)> = OnceLock::new();
+    let (wall, clock) = START.get_or_init(|| (SystemTime::now(), tokio::time::Instant::now()));
+    *wall + tokio::time::Instant::now().saturating_duration_since(*clock)
+}
+
+/// Something that happened to the space or device at `path`, the root space having the empty path.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    pub path: String,
+    pub time: SystemTime,
+    pub kind: EventKind,
+}
+
+impl Event {
+    pub fn new(path: &str, kind: EventKind) -> Self {
+        Event {
+            path: path.to_string(),
+            time: now(),
+            kind,
+        }
+    }
+
+    pub fn capability(&self) -> Option<Capability> {
+        match &self.kind {
+            EventKind::CapabilityChanged { value, .. } => Some(value.capability()),
+            _ => None,
+        }
+    }
+}
+
+/// Which events a subscription receives. Every condition that is set has to match.
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    within: Option<String>,
+    device: Option<String>,
+    capability: Option<Capability>,
+}
+
+impl EventFilter {
+    pub fn all() -> Self {
+        EventFilter::default()
+    }
+
+    /// Events of the space at `path` and of everything below it.
+    pub fn within(mut self, path: &str) -> Self {
+        self.within = Some(path.trim_matches(PATH_SEPARATOR).to_string());
+        self
+    }
+
+    /// Events of the device at `path` only.
+    pub fn device(mut self, path: &str) -> Self {
+        self.device = Some(path.trim_matches(PATH_SEPARATOR).to_string());
+        self
+    }
+
+    /// Only changes of this capability.
+    pub fn capability(mut self, capability: Capability) -> Self {
+        self.capability = Some(capability);
+        self
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        let within = self.within.as_deref().is_none_or(|within| {
+            within.is_empty()
+                || event.path == within
+                || event.path.strip_prefix(within).is_some_and(|rest| rest.starts_with(PATH_SEPARATOR))
+        });
+        let device = self.device.as_deref().is_none_or(|device| event.path == device);
+        let capability = self.capability.is_none_or(|capability| event.capability() == Some(capability));
+        within && device && capability
+    }
+}
+
+/// In-process bus the devices and spaces publish their changes on. Cloning gives another handle to the same bus.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new(256)
+    }
+}
+
+impl EventBus {
+    /// `capacity` is how many events a slow subscriber may fall behind before it starts missing them.
+    pub fn new(capacity: usize) -> Self {
+        EventBus { sender: broadcast::channel(capacity).0 }
+    }
+
+    pub fn publish(&self, event: Event) {
+        log::trace!("Event {:?}", event);
+        // no subscribers is fine
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self, filter: EventFilter) -> EventSubscription {
+        EventSubscription {
+            receiver: self.sender.subscribe(),
+            filter,
+        }
+    }
+
+    /// A handle publishing on behalf of the space or device at `path`.
+    pub fn publisher(&self, path: &str) -> EventPublisher {
+        EventPublisher {
+            bus: self.clone(),
+            path: path.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EventPublisher {
+    bus: EventBus,
+    path: String,
+}
+
+impl EventPublisher {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn publish(&self, kind: EventKind) {
+        self.bus.publish(Event::new(&self.path, kind));
+    }
+
+    /// A publisher for `key` below this path, such as a member of a group.
+    pub fn child(&self, key: &str) -> EventPublisher {
+        self.bus.publisher(&join_path(&self.path, key))
+    }
+}
+
+pub struct EventSubscription {
+    receiver: broadcast::Receiver<Event>,
+    filter: EventFilter,
+}
+
+impl EventSubscription {
+    /// Waits for the next matching event, `None` once the bus is gone.
+    pub async fn recv(&mut self) -> Option<Event> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) if self.filter.matches(&event) => return Some(event),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => log::warn!("Event subscriber fell behind, missed {} events", missed),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// The next matching event if one is waiting.
+    pub fn try_recv(&mut self) -> Option<Event> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(event) if self.filter.matches(&event) => return Some(event),
+                Ok(_) => continue,
+                Err(TryRecvError::Lagged(missed)) => log::warn!("Event subscriber fell behind, missed {} events", missed),
+                Err(TryRecvError::Empty | TryRecvError::Closed) => return None,
+            }
+        }
+    }
+
+    pub fn into_stream(self) -> BoxStream<'static, Event> {
+        stream::unfold(self, |mut subscription| async move {
+            subscription.recv().await.map(|event| (event, subscription))
+        }).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CapabilityState;
+
+    #[test]
+    fn test_filters() {
+        let bus = EventBus::default();
+        let mut kitchen = bus.subscribe(EventFilter::all().within("main_living_area/kitchen"));
+        let mut occupancy = bus.subscribe(EventFilter::all().capability(Capability::Occupancy));
+        let mut curtains = bus.subscribe(EventFilter::all().device("main_living_area/kitchen/curtains"));
+
+        bus.publisher("main_living_area/kitchen_island").publish(EventKind::DeviceOnline);
+        bus.publisher("main_living_area/kitchen/motion_sensor").publish(EventKind::CapabilityChanged {
+            value: CapabilityValue::Occupancy(true),
+            previous: None,
+        });
+        bus.publisher("main_living_area/kitchen").publish(EventKind::PresenceChanged { previous: 0.0, probability: 1.0 });
+
+        assert_eq!(kitchen.try_recv().unwrap().path, "main_living_area/kitchen/motion_sensor");
+        assert_eq!(kitchen.try_recv().unwrap().path, "main_living_area/kitchen");
+        assert!(kitchen.try_recv().is_none());
+        assert_eq!(occupancy.try_recv().unwrap().capability(), Some(Capability::Occupancy));
+        assert!(occupancy.try_recv().is_none());
+        assert!(curtains.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_capability_state_publishes() {
+        let bus = EventBus::default();
+        let mut events = bus.subscribe(EventFilter::all());
+        let state = CapabilityState::default();
+
+        state.set(CapabilityValue::OnOff(true));
+        state.bind(bus.publisher("office/ceiling_light"));
+        state.set(CapabilityValue::OnOff(true));
+        state.set(CapabilityValue::OnOff(false));
+
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.path, "office/ceiling_light");
+        assert_eq!(event.kind, EventKind::CapabilityChanged {
+            value: CapabilityValue::OnOff(false),
+            previous: Some(CapabilityValue::OnOff(true)),
+        });
+        assert!(events.try_recv().is_none());
+    }
+}
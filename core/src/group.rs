@@ -0,0 +1,329 @@
+use std::error::Error;
+use std::fmt;
+use std::time::SystemTime;
+use futures_util::future::join_all;
+use tokio_util::sync::CancellationToken;
+use crate::{
+    Brightness, Capabilities, Capability, CapabilityState, CapabilityValue, Color, ColorTemperature, Contact, ContactState,
+    Controllable, Device, DynDevice, EventBus, EventFilter, EventKind, EventPublisher, Health, Humidity, Illuminance,
+    LifeCycle, LocalBoxFuture, Lock, LockState, Occupancy, OnOff, PATH_SEPARATOR, Space, Temperature, WindowCovering,
+};
+
+/// Several devices acting as one, such as the curtains of a room.
+///
+/// The group has the capabilities all its members share. Commands go out to every member, and
+/// state is aggregated: on if any member is on, the average brightness, the lowest position.
+/// Members are reached below the group's path, e.g. `living_room/curtains/balcony`.
+#[derive(Default)]
+pub struct DeviceGroup {
+    pub name: String,
+    members: Vec<(String, Box<dyn DynDevice>)>,
+    /// The aggregate as last published, see `refresh`.
+    state: CapabilityState,
+}
+
+impl DeviceGroup {
+    pub fn new(name: &str) -> Self {
+        DeviceGroup { name: name.to_string(), ..Default::default() }
+    }
+
+    pub fn member(mut self, key: &str, device: impl DynDevice + 'static) -> Self {
+        self.members.push((key.to_string(), Box::new(device)));
+        self
+    }
+
+    pub fn members(&self) -> Vec<(&str, &dyn DynDevice)> {
+        self.members.iter().map(|(key, device)| (key.as_str(), device.as_ref())).collect()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&dyn DynDevice> {
+        self.members.iter().find(|(k, _)| k == key).map(|(_, device)| device.as_ref())
+    }
+
+    fn shares(&self, capability: Capability) -> bool {
+        !self.members.is_empty() && self.members.iter().all(|(_, device)| device.has_capability(capability))
+    }
+
+    fn readings<T>(&self, read: impl Fn(&dyn DynDevice) -> Option<T>) -> Vec<T> {
+        self.members.iter().filter_map(|(_, device)| read(device.as_ref())).collect()
+    }
+
+    /// Combined value of the members that reported one, `None` if none did or not every member has the capability.
+    pub fn aggregate(&self, capability: Capability) -> Option<CapabilityValue> {
+        if !self.shares(capability) {
+            return None;
+        }
+        match capability {
+            Capability::Occupancy => any(self.readings(|d| d.as_occupancy()?.occupied())).map(CapabilityValue::Occupancy),
+            Capability::OnOff => any(self.readings(|d| d.as_on_off()?.is_on())).map(CapabilityValue::OnOff),
+            Capability::Brightness => average(self.readings(|d| d.as_brightness()?.brightness().map(f32::from)))
+                .map(|brightness| CapabilityValue::Brightness(brightness.round() as u8)),
+            Capability::ColorTemperature => average(self.readings(|d| d.as_color_temperature()?.color_temperature().map(f32::from)))
+                .map(|kelvin| CapabilityValue::ColorTemperature(kelvin.round() as u16)),
+            // hues don't average, the first member sets the color
+            Capability::Color => self.readings(|d| d.as_color()?.color()).first().copied().map(CapabilityValue::Color),
+            Capability::Contact => self.readings(|d| d.as_contact()?.contact()).into_iter()
+                .reduce(|a, b| if a == ContactState::Open { a } else { b })
+                .map(CapabilityValue::Contact),
+            Capability::Temperature => average(self.readings(|d| d.as_temperature()?.temperature())).map(CapabilityValue::Temperature),
+            Capability::Humidity => average(self.readings(|d| d.as_humidity()?.humidity())).map(CapabilityValue::Humidity),
+            Capability::Illuminance => average(self.readings(|d| d.as_illuminance()?.illuminance())).map(CapabilityValue::Illuminance),
+            Capability::WindowCovering => self.readings(|d| d.as_window_covering()?.position()).into_iter().min()
+                .map(CapabilityValue::WindowCovering),
+            Capability::Lock => self.readings(|d| d.as_lock()?.lock_state()).into_iter()
+                .max_by_key(|state| match state {
+                    LockState::Locked => 0,
+                    LockState::Unlocked => 1,
+                    LockState::Jammed => 2,
+                })
+                .map(CapabilityValue::Lock),
+        }
+    }
+
+    /// Publishes the aggregate values that changed since the last refresh, under the group's own path.
+    /// Returns whether any did.
+    pub fn refresh(&self) -> bool {
+        let mut changed = false;
+        for value in Capability::ALL.into_iter().filter_map(|capability| self.aggregate(capability)) {
+            changed |= self.state.set(value);
+        }
+        changed
+    }
+}
+
+/// `Some(true)` if any reading is, `None` without readings.
+fn any(readings: Vec<bool>) -> Option<bool> {
+    (!readings.is_empty()).then(|| readings.contains(&true))
+}
+
+fn average(readings: Vec<f32>) -> Option<f32> {
+    (!readings.is_empty()).then(|| readings.iter().sum::<f32>() / readings.len() as f32)
+}
+
+/// Joins the errors of the members that failed, `Ok` if none did.
+fn combine(results: Vec<Result<(), String>>) -> Result<(), Box<dyn Error>> {
+    let errors: Vec<_> = results.into_iter().filter_map(Result::err).collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("; ").into())
+    }
+}
+
+/// Labels a member's error with its key.
+fn labelled(key: &str, result: Result<(), Box<dyn Error>>) -> Result<(), String> {
+    result.map_err(|error| format!("{}: {}", key, error))
+}
+
+impl fmt::Debug for DeviceGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceGroup")
+            .field("name", &self.name)
+            .field("members", &self.members.iter().map(|(key, _)| key).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl LifeCycle for DeviceGroup {
+    async fn init(&self) -> Result<(), Box<dyn Error>> {
+        let results = join_all(self.members.iter().map(|(key, device)| async move { labelled(key, device.init().await) })).await;
+        combine(results)
+    }
+
+    async fn dispose(&self) -> Result<(), Box<dyn Error>> {
+        let results = join_all(self.members.iter().map(|(key, device)| async move { labelled(key, device.dispose().await) })).await;
+        combine(results)
+    }
+}
+
+impl Device for DeviceGroup {
+    fn bind_events(&self, publisher: EventPublisher) {
+        for (key, device) in &self.members {
+            device.bind_events(publisher.child(key));
+        }
+        self.state.bind(publisher);
+    }
+
+    /// The worst health of any member.
+    fn health(&self) -> Health {
+        self.members.iter()
+            .map(|(key, device)| match device.health() {
+                Health::Online => Health::Online,
+                Health::Degraded { reason } => Health::Degraded { reason: format!("{}: {}", key, reason) },
+                Health::Offline { reason } => Health::Offline { reason: format!("{}: {}", key, reason) },
+            })
+            .max_by_key(Health::severity)
+            .unwrap_or(Health::Online)
+    }
+
+    fn last_seen(&self) -> Option<SystemTime> {
+        self.members.iter().filter_map(|(_, device)| device.last_seen()).max()
+    }
+}
+
+impl Controllable for DeviceGroup {
+    fn execute(&self, target: CapabilityValue) -> LocalBoxFuture<'_, Result<(), Box<dyn Error>>> {
+        Box::pin(async move {
+            let results = join_all(self.members.iter().map(|(key, device)| async move {
+                let result = match device.as_controllable() {
+                    Some(controllable) => controllable.execute(target).await,
+                    None => Err(format!("Can't be controlled through {}", target.capability()).into()),
+                };
+                labelled(key, result)
+            })).await;
+            combine(results)
+        })
+    }
+}
+
+macro_rules! aggregated {
+    ($($trait:ident :: $method:ident -> $type:ty = $capability:ident;)*) => {
+        $(
+            impl $trait for DeviceGroup {
+                fn $method(&self) -> Option<$type> {
+                    match self.aggregate(Capability::$capability)? {
+                        CapabilityValue::$capability(value) => Some(value),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+aggregated! {
+    Occupancy::occupied -> bool = Occupancy;
+    OnOff::is_on -> bool = OnOff;
+    Brightness::brightness -> u8 = Brightness;
+    ColorTemperature::color_temperature -> u16 = ColorTemperature;
+    Color::color -> crate::HueSaturation = Color;
+    Contact::contact -> ContactState = Contact;
+    Temperature::temperature -> f32 = Temperature;
+    Humidity::humidity -> f32 = Humidity;
+    Illuminance::illuminance -> f32 = Illuminance;
+    WindowCovering::position -> u8 = WindowCovering;
+    Lock::lock_state -> LockState = Lock;
+}
+
+impl Capabilities for DeviceGroup {
+    fn as_occupancy(&self) -> Option<&dyn Occupancy> { self.shares(Capability::Occupancy).then_some(self) }
+    fn as_on_off(&self) -> Option<&dyn OnOff> { self.shares(Capability::OnOff).then_some(self) }
+    fn as_brightness(&self) -> Option<&dyn Brightness> { self.shares(Capability::Brightness).then_some(self) }
+    fn as_color_temperature(&self) -> Option<&dyn ColorTemperature> { self.shares(Capability::ColorTemperature).then_some(self) }
+    fn as_color(&self) -> Option<&dyn Color> { self.shares(Capability::Color).then_some(self) }
+    fn as_contact(&self) -> Option<&dyn Contact> { self.shares(Capability::Contact).then_some(self) }
+    fn as_temperature(&self) -> Option<&dyn Temperature> { self.shares(Capability::Temperature).then_some(self) }
+    fn as_humidity(&self) -> Option<&dyn Humidity> { self.shares(Capability::Humidity).then_some(self) }
+    fn as_illuminance(&self) -> Option<&dyn Illuminance> { self.shares(Capability::Illuminance).then_some(self) }
+    fn as_window_covering(&self) -> Option<&dyn WindowCovering> { self.shares(Capability::WindowCovering).then_some(self) }
+    fn as_lock(&self) -> Option<&dyn Lock> { self.shares(Capability::Lock).then_some(self) }
+
+    fn as_controllable(&self) -> Option<&dyn Controllable> {
+        let controllable = !self.members.is_empty() && self.members.iter().all(|(_, device)| device.as_controllable().is_some());
+        controllable.then_some(self)
+    }
+}
+
+impl<'a> dyn Space + 'a {
+    /// The group a device at `path` is a member of, if it is one.
+    pub fn group_of(&self, path: &str) -> Option<&DeviceGroup> {
+        let (group, _) = path.rsplit_once(PATH_SEPARATOR)?;
+        self.find_device(group)?.as_any().downcast_ref()
+    }
+
+    /// Keeps the groups from this space down publishing their aggregate state until cancelled,
+    /// refreshing a group whenever one of its members changes.
+    pub async fn track_groups(&self, events: &EventBus, cancel: CancellationToken) {
+        let mut changes = events.subscribe(EventFilter::all());
+        loop {
+            let event = tokio::select! {
+                _ = cancel.cancelled() => break,
+                event = changes.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+            };
+            if !matches!(event.kind, EventKind::CapabilityChanged { .. }) {
+                continue;
+            }
+            if let Some(group) = self.group_of(&event.path) {
+                group.refresh();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CommandOptions;
+
+    /// Applies every command straight away.
+    #[derive(Default)]
+    struct Curtain {
+        state: CapabilityState,
+        dimmable: bool,
+    }
+
+    impl LifeCycle for Curtain {
+        async fn init(&self) -> Result<(), Box<dyn Error>> { Ok(()) }
+        async fn dispose(&self) -> Result<(), Box<dyn Error>> { Ok(()) }
+    }
+
+    impl WindowCovering for Curtain {
+        fn position(&self) -> Option<u8> { self.state.position() }
+    }
+
+    impl Brightness for Curtain {
+        fn brightness(&self) -> Option<u8> { self.state.brightness() }
+    }
+
+    impl Controllable for Curtain {
+        fn execute(&self, target: CapabilityValue) -> LocalBoxFuture<'_, Result<(), Box<dyn Error>>> {
+            Box::pin(async move {
+                self.state.set(target);
+                Ok(())
+            })
+        }
+    }
+
+    impl Capabilities for Curtain {
+        fn as_window_covering(&self) -> Option<&dyn WindowCovering> { Some(self) }
+        fn as_brightness(&self) -> Option<&dyn Brightness> { self.dimmable.then_some(self) }
+        fn as_controllable(&self) -> Option<&dyn Controllable> { Some(self) }
+    }
+
+    impl Device for Curtain {}
+
+    #[tokio::test]
+    async fn test_group() {
+        let group = DeviceGroup::new("Curtains")
+            .member("window", Curtain { dimmable: true, ..Default::default() })
+            .member("balcony", Curtain::default());
+        let events = EventBus::default();
+        let mut changes = events.subscribe(EventFilter::all().device("curtains"));
+        DynDevice::bind_events(&group, events.publisher("curtains"));
+
+        // only what every member has
+        assert_eq!(group.capabilities(), vec![Capability::WindowCovering]);
+        assert_eq!(group.position(), None);
+
+        let device: &dyn DynDevice = &group;
+        device.command(CapabilityValue::WindowCovering(80), CommandOptions::default()).await.unwrap();
+        assert_eq!(group.get("balcony").unwrap().state(Capability::WindowCovering), Some(CapabilityValue::WindowCovering(80)));
+
+        group.get("window").unwrap().as_controllable().unwrap().execute(CapabilityValue::WindowCovering(30)).await.unwrap();
+        assert_eq!(group.position(), Some(30));
+        assert!(group.refresh());
+        assert!(!group.refresh());
+        assert_eq!(changes.try_recv().map(|event| event.kind), Some(EventKind::CapabilityChanged {
+            value: CapabilityValue::WindowCovering(30),
+            previous: None,
+        }));
+
+        assert!(matches!(
+            device.command(CapabilityValue::Brightness(50), CommandOptions::default()).await,
+            Err(crate::CommandError::Unsupported(Capability::Brightness))
+        ));
+    }
+}
@@ -0,0 +1,54 @@
+use std::fmt;
+use std::marker::PhantomData;
+use crate::Space;
+
+/// Typed reference to a device by its path, resolved against a tree when needed.
+/// `domus!` generates one for every device, reached through `handle()` on the spaces it generates.
+pub struct DeviceHandle<T> {
+    path: &'static str,
+    device: PhantomData<fn() -> T>,
+}
+
+impl<T> DeviceHandle<T> {
+    pub const fn new(path: &'static str) -> Self {
+        DeviceHandle { path, device: PhantomData }
+    }
+
+    /// Path of the device relative to the root, stable as long as the fields on the way keep their names.
+    pub const fn path(&self) -> &'static str {
+        self.path
+    }
+}
+
+impl<T: 'static> DeviceHandle<T> {
+    /// The device in `root`, `None` if there's no device at the path or it isn't a `T`.
+    pub fn resolve<'a>(&self, root: &'a dyn Space) -> Option<&'a T> {
+        root.find_device(self.path)?.as_any().downcast_ref()
+    }
+}
+
+impl<T> Clone for DeviceHandle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for DeviceHandle<T> {}
+
+impl<T> fmt::Debug for DeviceHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("DeviceHandle").field(&self.path).finish()
+    }
+}
+
+impl<T> fmt::Display for DeviceHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.path)
+    }
+}
+
+/// Typed reference to a space, implemented by the handle structs `domus!` generates for every space.
+pub trait SpaceHandle {
+    /// Path of the space relative to the root, empty for the root itself.
+    fn path(&self) -> &'static str;
+}
@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use serde::{Serialize, Deserialize};
+use tokio_util::sync::CancellationToken;
+use crate::{EventBus, EventKind, Space};
+
+/// Whether a device can currently be reached.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Health {
+    Online,
+    /// Reachable but late or misbehaving, such as a sensor that missed a report.
+    Degraded { reason: String },
+    Offline { reason: String },
+}
+
+impl Health {
+    pub fn is_online(&self) -> bool {
+        matches!(self, Health::Online)
+    }
+
+    pub(crate) fn severity(&self) -> u8 {
+        match self {
+            Health::Online => 0,
+            Health::Degraded { .. } => 1,
+            Health::Offline { .. } => 2,
+        }
+    }
+
+    fn event(&self) -> EventKind {
+        match self {
+            Health::Online => EventKind::DeviceOnline,
+            Health::Degraded { reason } => EventKind::DeviceDegraded { reason: reason.clone() },
+            Health::Offline { reason } => EventKind::DeviceOffline { reason: reason.clone() },
+        }
+    }
+}
+
+/// Reports a device may miss before it counts as degraded, and as offline.
+const MISSED_DEGRADED: u32 = 2;
+const MISSED_OFFLINE: u32 = 5;
+
+/// Liveness of a device that is expected to report every `interval`, for drivers to keep next to their state.
+///
+/// Drivers call `seen` whenever they hear from the device and `lost` when their connection to it drops.
+#[derive(Debug)]
+pub struct Heartbeat {
+    interval: Duration,
+    last_seen: Mutex<Option<SystemTime>>,
+    lost: Mutex<Option<String>>,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat::new(Duration::from_secs(300))
+    }
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration) -> Self {
+        Heartbeat {
+            interval,
+            last_seen: Mutex::new(None),
+            lost: Mutex::new(None),
+        }
+    }
+
+    pub fn seen(&self) {
+        self.seen_at(SystemTime::now());
+    }
+
+    pub fn seen_at(&self, time: SystemTime) {
+        *self.last_seen.lock().unwrap() = Some(time);
+        *self.lost.lock().unwrap() = None;
+    }
+
+    /// The connection to the device dropped, it is offline until seen again.
+    pub fn lost(&self, reason: &str) {
+        *self.lost.lock().unwrap() = Some(reason.to_string());
+    }
+
+    pub fn last_seen(&self) -> Option<SystemTime> {
+        *self.last_seen.lock().unwrap()
+    }
+
+    pub fn health(&self, now: SystemTime) -> Health {
+        if let Some(reason) = self.lost.lock().unwrap().clone() {
+            return Health::Offline { reason };
+        }
+        let Some(last_seen) = self.last_seen() else {
+            return Health::Offline { reason: "Not heard from yet".to_string() };
+        };
+        let silent = now.duration_since(last_seen).unwrap_or_default();
+        if silent <= self.interval * MISSED_DEGRADED {
+            Health::Online
+        } else if silent <= self.interval * MISSED_OFFLINE {
+            Health::Degraded { reason: format!("Last seen {}s ago", silent.as_secs()) }
+        } else {
+            Health::Offline { reason: format!("Last seen {}s ago", silent.as_secs()) }
+        }
+    }
+}
+
+/// Health of every device under a space, by path relative to it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SpaceHealth {
+    pub devices: BTreeMap<String, Health>,
+}
+
+impl SpaceHealth {
+    pub fn online(&self) -> usize {
+        self.devices.values().filter(|health| health.is_online()).count()
+    }
+
+    /// Paths of the devices that aren't online.
+    pub fn unavailable(&self) -> Vec<&str> {
+        self.devices.iter()
+            .filter(|(_, health)| !health.is_online())
+            .map(|(path, _)| path.as_str())
+            .collect()
+    }
+
+    /// The health of the worst device, online for a space without devices.
+    pub fn worst(&self) -> Health {
+        self.devices.values().max_by_key(|health| health.severity()).cloned().unwrap_or(Health::Online)
+    }
+}
+
+impl<'a> dyn Space + 'a {
+    pub fn health(&self) -> SpaceHealth {
+        SpaceHealth {
+            devices: self.walk_devices().into_iter()
+                .map(|visit| (visit.path, visit.device.health()))
+                .collect(),
+        }
+    }
+
+    /// Checks every device every `interval` until cancelled, publishing `DeviceOnline`, `DeviceDegraded`
+    /// and `DeviceOffline` whenever one changes.
+    pub async fn monitor_health(&self, events: &EventBus, interval: Duration, cancel: CancellationToken) {
+        let mut known: BTreeMap<String, Health> = BTreeMap::new();
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticks.tick() => {},
+            }
+            for (path, health) in self.health().devices {
+                // devices start out assumed online, only news is published
+                let previous = known.get(&path).unwrap_or(&Health::Online);
+                if *previous != health {
+                    log::info!("{} is now {:?}", path, health);
+                    events.publisher(&path).publish(health.event());
+                    known.insert(path, health);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heartbeat() {
+        let heartbeat = Heartbeat::new(Duration::from_secs(60));
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(matches!(heartbeat.health(start), Health::Offline { .. }));
+        heartbeat.seen_at(start);
+        assert_eq!(heartbeat.health(at(90)), Health::Online);
+        assert_eq!(heartbeat.health(at(200)), Health::Degraded { reason: "Last seen 200s ago".to_string() });
+        assert!(matches!(heartbeat.health(at(400)), Health::Offline { .. }));
+
+        heartbeat.seen_at(at(400));
+        heartbeat.lost("Session closed");
+        assert_eq!(heartbeat.health(at(400)), Health::Offline { reason: "Session closed".to_string() });
+
+        let space = SpaceHealth {
+            devices: BTreeMap::from([
+                ("office/light".to_string(), Health::Online),
+                ("office/motion_sensor".to_string(), heartbeat.health(at(400))),
+            ]),
+        };
+        assert_eq!(space.online(), 1);
+        assert_eq!(space.unavailable(), vec!["office/motion_sensor"]);
+        assert!(matches!(space.worst(), Health::Offline { .. }));
+    }
+}
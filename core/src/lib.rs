@@ -1,7 +1,33 @@
 mod life_cycle;
 mod space;
 mod device;
+mod discovery;
+mod dyn_driver;
+mod capability;
+mod presence;
+mod metadata;
+mod event;
+mod command;
+mod state_machine;
+mod scene;
+mod supervisor;
+mod health;
+mod handle;
+mod group;
 
 pub use life_cycle::*;
 pub use space::*;
-pub use device::*;
\ No newline at end of file
+pub use device::*;
+pub use discovery::*;
+pub use dyn_driver::*;
+pub use capability::*;
+pub use presence::*;
+pub use metadata::*;
+pub use event::*;
+pub use command::*;
+pub use state_machine::*;
+pub use scene::*;
+pub use supervisor::*;
+pub use health::*;
+pub use handle::*;
+pub use group::*;
\ No newline at end of file
@@ -1,5 +1,26 @@
+use std::future::Future;
+use std::pin::Pin;
+
+pub type LocalBoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
 #[allow(async_fn_in_trait)]
 pub trait LifeCycle {
     async fn init(&self) -> Result<(), Box<dyn std::error::Error>>;
     async fn dispose(&self) -> Result<(), Box<dyn std::error::Error>>;
-}
\ No newline at end of file
+}
+
+/// Object-safe face of `LifeCycle`, implemented for every `LifeCycle`.
+pub trait DynLifeCycle {
+    fn init(&self) -> LocalBoxFuture<'_, Result<(), Box<dyn std::error::Error>>>;
+    fn dispose(&self) -> LocalBoxFuture<'_, Result<(), Box<dyn std::error::Error>>>;
+}
+
+impl<T: LifeCycle> DynLifeCycle for T {
+    fn init(&self) -> LocalBoxFuture<'_, Result<(), Box<dyn std::error::Error>>> {
+        Box::pin(LifeCycle::init(self))
+    }
+
+    fn dispose(&self) -> LocalBoxFuture<'_, Result<(), Box<dyn std::error::Error>>> {
+        Box::pin(LifeCycle::dispose(self))
+    }
+}
@@ -0,0 +1,24 @@
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+
+/// Free-form tags and key/value pairs attached to a space or a device.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    pub tags: Vec<String>,
+    pub values: BTreeMap<String, String>,
+}
+
+impl Metadata {
+    pub const EMPTY: Metadata = Metadata {
+        tags: Vec::new(),
+        values: BTreeMap::new(),
+    };
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+}
@@ -0,0 +1,229 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use crate::{Capability, EventBus, EventFilter, EventKind, EventPublisher, Space};
+
+/// How a space folds its own presence together with that of its sub-spaces.
+#[derive(Clone, Copy)]
+pub enum PresenceCombiner {
+    /// The space is as occupied as its most occupied part.
+    Max,
+    /// Treats every part as independent evidence, `1 - Π(1 - p)`.
+    NoisyOr,
+    Custom(fn(&[f32]) -> f32),
+}
+
+impl PresenceCombiner {
+    pub fn combine(&self, probabilities: &[f32]) -> f32 {
+        let combined = match self {
+            PresenceCombiner::Max => probabilities.iter().copied().fold(0.0, f32::max),
+            PresenceCombiner::NoisyOr => 1.0 - probabilities.iter().map(|p| 1.0 - p).product::<f32>(),
+            PresenceCombiner::Custom(combine) => combine(probabilities),
+        };
+        combined.clamp(0.0, 1.0)
+    }
+}
+
+impl fmt::Debug for PresenceCombiner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresenceCombiner::Max => write!(f, "Max"),
+            PresenceCombiner::NoisyOr => write!(f, "NoisyOr"),
+            PresenceCombiner::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PresenceConfig {
+    /// Time for the presence probability to halve once the sensors stop reporting occupancy.
+    pub half_life: Duration,
+    pub combiner: PresenceCombiner,
+    /// Smallest change in probability that is published as a `PresenceChanged` event.
+    pub min_change: f32,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        PresenceConfig {
+            half_life: Duration::from_secs(120),
+            combiner: PresenceCombiner::Max,
+            min_change: 0.05,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PresenceChanged {
+    pub space: String,
+    pub previous: f32,
+    pub probability: f32,
+}
+
+#[derive(Debug)]
+struct PresenceState {
+    config: PresenceConfig,
+    /// Probability from the space's own sensors at the time of `last_evidence`.
+    sensed: f32,
+    last_evidence: Option<Instant>,
+    probability: f32,
+    /// Last probability that was published.
+    published: f32,
+    publisher: Option<EventPublisher>,
+}
+
+/// Presence of a single space, as a probability that someone is in it.
+#[derive(Debug)]
+pub struct SpacePresence {
+    state: Mutex<PresenceState>,
+    events: broadcast::Sender<PresenceChanged>,
+}
+
+impl Default for SpacePresence {
+    fn default() -> Self {
+        SpacePresence::new(PresenceConfig::default())
+    }
+}
+
+impl SpacePresence {
+    pub fn new(config: PresenceConfig) -> Self {
+        SpacePresence {
+            state: Mutex::new(PresenceState {
+                config,
+                sensed: 0.0,
+                last_evidence: None,
+                probability: 0.0,
+                published: 0.0,
+                publisher: None,
+            }),
+            events: broadcast::channel(16).0,
+        }
+    }
+
+    pub fn configure(&self, config: PresenceConfig) {
+        self.state.lock().unwrap().config = config;
+    }
+
+    pub fn config(&self) -> PresenceConfig {
+        self.state.lock().unwrap().config
+    }
+
+    pub fn probability(&self) -> f32 {
+        self.state.lock().unwrap().probability
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PresenceChanged> {
+        self.events.subscribe()
+    }
+
+    /// Also publishes the changes on the event bus, under the space's path.
+    pub fn bind(&self, publisher: EventPublisher) {
+        self.state.lock().unwrap().publisher = Some(publisher);
+    }
+
+    /// Folds in the latest sensor readings and sub-space probabilities, returning the new probability of the space.
+    ///
+    /// `occupied` holds the occupancy reported by each of the space's own sensors, `None` for sensors that haven't reported.
+    pub fn update(&self, space: &str, now: Instant, occupied: &[Option<bool>], sub_spaces: &[f32]) -> f32 {
+        let mut state = self.state.lock().unwrap();
+
+        if occupied.contains(&Some(true)) {
+            state.sensed = 1.0;
+            state.last_evidence = Some(now);
+        }
+
+        let own = match state.last_evidence {
+            Some(last_evidence) => {
+                let elapsed = now.saturating_duration_since(last_evidence).as_secs_f32();
+                state.sensed * 0.5f32.powf(elapsed / state.config.half_life.as_secs_f32().max(f32::EPSILON))
+            },
+            None => 0.0,
+        };
+
+        let mut probabilities = Vec::with_capacity(sub_spaces.len() + 1);
+        probabilities.push(own);
+        probabilities.extend_from_slice(sub_spaces);
+        state.probability = state.config.combiner.combine(&probabilities);
+
+        let previous = state.published;
+        let settled = state.probability == 0.0 || state.probability == 1.0;
+        if (state.probability - previous).abs() >= state.config.min_change || (settled && state.probability != previous) {
+            state.published = state.probability;
+            log::debug!("Presence in {} changed from {:.2} to {:.2}", space, previous, state.probability);
+            // no subscribers is fine
+            let _ = self.events.send(PresenceChanged {
+                space: space.to_string(),
+                previous,
+                probability: state.probability,
+            });
+            if let Some(publisher) = &state.publisher {
+                publisher.publish(EventKind::PresenceChanged { previous, probability: state.probability });
+            }
+        }
+
+        state.probability
+    }
+}
+
+impl<'a> dyn Space + 'a {
+    /// Keeps presence from this space down up to date until cancelled, refreshing on every occupancy report and every `interval` so it decays.
+    pub async fn track_presence(&self, events: &EventBus, interval: Duration, cancel: CancellationToken) {
+        let mut occupancy = events.subscribe(EventFilter::all().capability(Capability::Occupancy));
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticks.tick() => {},
+                event = occupancy.recv() => if event.is_none() {
+                    break;
+                },
+            }
+            self.refresh_presence(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presence_decays() {
+        let presence = SpacePresence::default();
+        let start = Instant::now();
+
+        assert_eq!(presence.update("office", start, &[Some(true)], &[]), 1.0);
+        assert_eq!(presence.update("office", start + Duration::from_secs(120), &[Some(false)], &[]), 0.5);
+        assert_eq!(presence.update("office", start + Duration::from_secs(240), &[None], &[]), 0.25);
+        assert_eq!(presence.update("office", start + Duration::from_secs(250), &[Some(true)], &[]), 1.0);
+    }
+
+    #[test]
+    fn test_presence_propagates() {
+        let presence = SpacePresence::default();
+        let now = Instant::now();
+
+        assert_eq!(presence.update("apartment", now, &[], &[0.2, 0.7]), 0.7);
+
+        presence.configure(PresenceConfig { combiner: PresenceCombiner::NoisyOr, ..Default::default() });
+        assert!((presence.update("apartment", now, &[], &[0.5, 0.5]) - 0.75).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn test_presence_events() {
+        let presence = SpacePresence::default();
+        let mut events = presence.subscribe();
+        let start = Instant::now();
+
+        presence.update("office", start, &[Some(true)], &[]);
+        presence.update("office", start + Duration::from_secs(1), &[None], &[]);
+
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.space, "office");
+        assert_eq!(event.probability, 1.0);
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,262 @@
+use std::path::Path;
+use std::time::Duration;
+use futures_util::future::join_all;
+use serde::{Serialize, Deserialize};
+use crate::{Capability, CapabilityValue, CommandError, CommandOptions, DeviceQuery, DynDevice, Space, join_path};
+
+/// Target values for one device under the scene's space.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SceneTarget {
+    /// Path of the device, relative to the scene's space.
+    pub device: String,
+    pub values: Vec<CapabilityValue>,
+}
+
+/// Named target state for the devices under a space, such as "Movie" for the living room.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scene {
+    pub name: String,
+    /// Path of the space the scene applies to, the empty path for the whole tree.
+    pub space: String,
+    pub targets: Vec<SceneTarget>,
+}
+
+impl Scene {
+    pub fn to_toml(&self) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(toml::from_str(text)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(path, self.to_toml()?)?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read scene {}: {}", path.display(), e))?;
+        Self::from_toml(&text)
+            .map_err(|e| format!("Invalid scene {}: {}", path.display(), e).into())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SceneOptions {
+    /// Fades brightness and colour temperature over this time instead of jumping to them.
+    pub transition: Option<Duration>,
+    pub command: CommandOptions,
+}
+
+/// What applying a scene did, device by device.
+#[derive(Debug, Default)]
+pub struct SceneReport {
+    pub applied: Vec<String>,
+    pub failed: Vec<(String, CommandError)>,
+}
+
+impl SceneReport {
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Steps a fade is split into, at most.
+const MAX_TRANSITION_STEPS: u32 = 20;
+const TRANSITION_STEP: Duration = Duration::from_millis(250);
+
+/// The value `step` out of `steps` on the way from `from` to `to`, for values that can fade.
+fn fade_step(from: Option<CapabilityValue>, to: CapabilityValue, step: u32, steps: u32) -> Option<CapabilityValue> {
+    let between = |from: f32, to: f32| from + (to - from) * step as f32 / steps as f32;
+    match (from?, to) {
+        (CapabilityValue::Brightness(from), CapabilityValue::Brightness(to)) =>
+            Some(CapabilityValue::Brightness(between(from as f32, to as f32).round() as u8)),
+        (CapabilityValue::ColorTemperature(from), CapabilityValue::ColorTemperature(to)) =>
+            Some(CapabilityValue::ColorTemperature(between(from as f32, to as f32).round() as u16)),
+        _ => None,
+    }
+}
+
+async fn apply_to_device(device: &dyn DynDevice, values: &[CapabilityValue], options: SceneOptions) -> Result<(), CommandError> {
+    let steps = options.transition
+        .map(|transition| (transition.as_millis() / TRANSITION_STEP.as_millis()).clamp(1, MAX_TRANSITION_STEPS as u128) as u32)
+        .unwrap_or(1);
+    let fading: Vec<_> = values.iter()
+        .map(|to| (device.state(to.capability()), *to))
+        .filter(|(from, to)| steps > 1 && fade_step(*from, *to, 0, steps).is_some())
+        .collect();
+    let switching_off = values.contains(&CapabilityValue::OnOff(false));
+
+    // switch on and set everything that doesn't fade first, switch off last so the fade is visible
+    for value in values.iter().filter(|value| **value != CapabilityValue::OnOff(false) && !fading.iter().any(|(_, to)| to == *value)) {
+        device.command(*value, options.command).await?;
+    }
+    for step in 1..=steps {
+        if fading.is_empty() {
+            break;
+        }
+        for (from, to) in &fading {
+            if let Some(value) = fade_step(*from, *to, step, steps) {
+                device.command(value, options.command).await?;
+            }
+        }
+        if step < steps {
+            tokio::time::sleep(options.transition.unwrap_or_default() / steps).await;
+        }
+    }
+    if switching_off {
+        device.command(CapabilityValue::OnOff(false), options.command).await?;
+    }
+    Ok(())
+}
+
+impl<'a> dyn Space + 'a {
+    /// Captures the current state of every controllable device under the space at `space` matching the query.
+    pub fn capture_scene(&self, name: &str, space: &str, query: &DeviceQuery) -> Option<Scene> {
+        let targets = self.find_space(space)?.query_devices(query).into_iter()
+            .filter(|visit| visit.device.as_controllable().is_some())
+            .map(|visit| SceneTarget {
+                values: Capability::ALL.into_iter()
+                    .filter(|capability| capability.is_controllable())
+                    .filter_map(|capability| visit.device.state(capability))
+                    .collect(),
+                device: visit.path,
+            })
+            .filter(|target| !target.values.is_empty())
+            .collect();
+        Some(Scene {
+            name: name.to_string(),
+            space: space.to_string(),
+            targets,
+        })
+    }
+
+    /// Sets every device of the scene to its target state, all devices at once, reporting which ones failed.
+    pub async fn apply_scene(&self, scene: &Scene, options: SceneOptions) -> SceneReport {
+        log::info!("Applying scene {} to {:?}", scene.name, scene.space);
+        let results = join_all(scene.targets.iter().map(|target| async move {
+            let path = join_path(&scene.space, &target.device);
+            let result = match self.find_device(&path) {
+                Some(device) => apply_to_device(device, &target.values, options).await,
+                None => Err(CommandError::Failed(format!("No device at {}", path).into())),
+            };
+            (path, result)
+        })).await;
+
+        let mut report = SceneReport::default();
+        for (path, result) in results {
+            match result {
+                Ok(()) => report.applied.push(path),
+                Err(error) => {
+                    log::error!("Scene {}: {} failed: {}", scene.name, path, error);
+                    report.failed.push((path, error));
+                },
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    use crate::{Brightness, Capabilities, CapabilityState, Controllable, Device, LifeCycle, LocalBoxFuture, Metadata, OnOff, SpacePresence};
+
+    #[derive(Default)]
+    struct Lamp {
+        state: CapabilityState,
+        broken: bool,
+    }
+
+    impl LifeCycle for Lamp {
+        async fn init(&self) -> Result<(), Box<dyn Error>> { Ok(()) }
+        async fn dispose(&self) -> Result<(), Box<dyn Error>> { Ok(()) }
+    }
+
+    impl OnOff for Lamp {
+        fn is_on(&self) -> Option<bool> { self.state.is_on() }
+    }
+
+    impl Brightness for Lamp {
+        fn brightness(&self) -> Option<u8> { self.state.brightness() }
+    }
+
+    impl Controllable for Lamp {
+        fn execute(&self, target: CapabilityValue) -> LocalBoxFuture<'_, Result<(), Box<dyn Error>>> {
+            Box::pin(async move {
+                if self.broken {
+                    return Err("Lamp is unreachable".into());
+                }
+                self.state.set(target);
+                Ok(())
+            })
+        }
+    }
+
+    impl Capabilities for Lamp {
+        fn as_on_off(&self) -> Option<&dyn OnOff> { Some(self) }
+        fn as_brightness(&self) -> Option<&dyn Brightness> { Some(self) }
+        fn as_controllable(&self) -> Option<&dyn Controllable> { Some(self) }
+    }
+
+    impl Device for Lamp {}
+
+    #[derive(Default)]
+    struct LivingRoom {
+        presence: SpacePresence,
+        metadata: Metadata,
+        floor_lamp: Lamp,
+        reading_lamp: Lamp,
+    }
+
+    impl LifeCycle for LivingRoom {
+        async fn init(&self) -> Result<(), Box<dyn Error>> { Ok(()) }
+        async fn dispose(&self) -> Result<(), Box<dyn Error>> { Ok(()) }
+    }
+
+    impl Space for LivingRoom {
+        fn name(&self) -> &str { "Living Room" }
+        fn presence(&self) -> &SpacePresence { &self.presence }
+        fn metadata(&self) -> &Metadata { &self.metadata }
+        fn device_metadata(&self, _key: &str) -> Option<&Metadata> { None }
+        fn sub_spaces(&self) -> Vec<(&str, &dyn Space)> { Vec::new() }
+
+        fn devices(&self) -> Vec<(&str, &dyn DynDevice)> {
+            vec![("floor_lamp", &self.floor_lamp), ("reading_lamp", &self.reading_lamp)]
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scene() {
+        let living_room = LivingRoom::default();
+        let root: &dyn Space = &living_room;
+        living_room.floor_lamp.state.set(CapabilityValue::OnOff(true));
+        living_room.floor_lamp.state.set(CapabilityValue::Brightness(20));
+
+        let movie = root.capture_scene("Movie", "", &DeviceQuery::new()).unwrap();
+        assert_eq!(movie.targets.len(), 1);
+        assert_eq!(Scene::from_toml(&movie.to_toml().unwrap()).unwrap(), movie);
+
+        living_room.floor_lamp.state.set(CapabilityValue::Brightness(100));
+        let options = SceneOptions { transition: Some(Duration::from_secs(2)), ..Default::default() };
+        let report = root.apply_scene(&movie, options).await;
+        assert!(report.is_complete());
+        assert_eq!(living_room.floor_lamp.brightness(), Some(20));
+
+        let broken = LivingRoom { reading_lamp: Lamp { broken: true, ..Default::default() }, ..Default::default() };
+        let everything_on = Scene {
+            name: "Bright".to_string(),
+            space: String::new(),
+            targets: ["floor_lamp", "reading_lamp"].into_iter()
+                .map(|device| SceneTarget { device: device.to_string(), values: vec![CapabilityValue::OnOff(true)] })
+                .collect(),
+        };
+        let report = (&broken as &dyn Space).apply_scene(&everything_on, SceneOptions::default()).await;
+        assert_eq!(report.applied, vec!["floor_lamp"]);
+        assert_eq!(report.failed[0].0, "reading_lamp");
+    }
+}
@@ -1,9 +1,319 @@
-use crate::LifeCycle;
+use tokio::time::Instant;
+use crate::{Capability, DeviceGroup, DynLifeCycle, DynDevice, EventBus, Metadata, SpacePresence};
 
-pub trait Space : LifeCycle {
+/// Separates the field names in a path like `main_living_area/kitchen/curtains`.
+pub const PATH_SEPARATOR: char = '/';
+
+pub trait Space : DynLifeCycle {
     fn name(&self) -> &str;
-    
-    // fn tags(&self) -> &[String];
-    //fn sub_spaces(&self) -> SubSpaceIterator<'_, &Self>;
-//    fn devices(&self) -> &[Box<dyn Device>];   // Assuming a Device trait for all devices
-}
\ No newline at end of file
+
+    fn presence(&self) -> &SpacePresence;
+
+    fn metadata(&self) -> &Metadata;
+
+    /// Metadata of a device directly in this space, by its field name.
+    fn device_metadata(&self, key: &str) -> Option<&Metadata>;
+
+    /// Spaces directly below this one, keyed by their field name.
+    fn sub_spaces(&self) -> Vec<(&str, &dyn Space)>;
+
+    /// Devices directly in this space, keyed by their field name.
+    fn devices(&self) -> Vec<(&str, &dyn DynDevice)>;
+
+    /// Re-evaluates presence in this space and every space below it, returning the probability for this space.
+    fn refresh_presence(&self, now: Instant) -> f32 {
+        let occupied: Vec<_> = self.devices().into_iter()
+            .filter_map(|(_, device)| device.as_occupancy())
+            .map(|occupancy| occupancy.occupied())
+            .collect();
+        let sub_spaces: Vec<_> = self.sub_spaces().into_iter()
+            .map(|(_, space)| space.refresh_presence(now))
+            .collect();
+        self.presence().update(self.name(), now, &occupied, &sub_spaces)
+    }
+}
+
+/// A space reached while walking the tree.
+pub struct SpaceVisit<'a> {
+    pub path: String,
+    pub space: &'a dyn Space,
+    pub parent: Option<&'a dyn Space>,
+}
+
+/// A device reached while walking the tree.
+pub struct DeviceVisit<'a> {
+    pub path: String,
+    pub device: &'a dyn DynDevice,
+    pub metadata: &'a Metadata,
+    pub space: &'a dyn Space,
+}
+
+static NO_METADATA: Metadata = Metadata::EMPTY;
+
+/// Picks devices by tag, metadata and capability, e.g. every light tagged `ambient`.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceQuery {
+    tags: Vec<String>,
+    values: Vec<(String, String)>,
+    capabilities: Vec<Capability>,
+}
+
+impl DeviceQuery {
+    pub fn new() -> Self {
+        DeviceQuery::default()
+    }
+
+    pub fn tagged(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    pub fn with_metadata(mut self, key: &str, value: &str) -> Self {
+        self.values.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_capability(mut self, capability: Capability) -> Self {
+        self.capabilities.push(capability);
+        self
+    }
+
+    pub fn matches(&self, device: &dyn DynDevice, metadata: &Metadata) -> bool {
+        self.tags.iter().all(|tag| metadata.has_tag(tag))
+            && self.values.iter().all(|(key, value)| metadata.get(key) == Some(value.as_str()))
+            && self.capabilities.iter().all(|capability| device.has_capability(*capability))
+    }
+}
+
+pub fn join_path(parent: &str, key: &str) -> String {
+    if parent.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}{}{}", parent, PATH_SEPARATOR, key)
+    }
+}
+
+impl<'a> dyn Space + 'a {
+    pub fn sub_space(&self, key: &str) -> Option<&dyn Space> {
+        self.sub_spaces().into_iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, space)| space)
+    }
+
+    pub fn device(&self, key: &str) -> Option<&dyn DynDevice> {
+        self.devices().into_iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, device)| device)
+    }
+
+    /// Resolves a path like `main_living_area/kitchen` relative to this space, the empty path being the space itself.
+    pub fn find_space(&self, path: &str) -> Option<&dyn Space> {
+        path.split(PATH_SEPARATOR)
+            .filter(|key| !key.is_empty())
+            .try_fold(self, |space, key| space.sub_space(key))
+    }
+
+    /// Resolves a path like `main_living_area/kitchen/curtains` relative to this space, or one going on to a group member.
+    pub fn find_device(&self, path: &str) -> Option<&dyn DynDevice> {
+        let Some((parent, key)) = path.rsplit_once(PATH_SEPARATOR) else {
+            return self.device(path);
+        };
+        match self.find_space(parent) {
+            Some(space) => space.device(key),
+            None => self.find_device(parent)?.as_any().downcast_ref::<DeviceGroup>()?.get(key),
+        }
+    }
+
+    /// The space holding whatever `path` points at, be it a space or a device.
+    pub fn parent_of(&self, path: &str) -> Option<&dyn Space> {
+        let path = path.trim_end_matches(PATH_SEPARATOR);
+        if path.is_empty() {
+            return None;
+        }
+        match path.rsplit_once(PATH_SEPARATOR) {
+            Some((parent, _)) => self.find_space(parent),
+            None => Some(self),
+        }
+    }
+
+    /// Every space from this one down, depth first, with paths relative to this space.
+    pub fn walk(&self) -> Vec<SpaceVisit<'_>> {
+        let mut visits = vec![SpaceVisit { path: String::new(), space: self, parent: None }];
+        let mut index = 0;
+        while index < visits.len() {
+            let (path, space) = (visits[index].path.clone(), visits[index].space);
+            let children = space.sub_spaces().into_iter().map(|(key, sub_space)| SpaceVisit {
+                path: join_path(&path, key),
+                space: sub_space,
+                parent: Some(space),
+            });
+            visits.splice(index + 1..index + 1, children);
+            index += 1;
+        }
+        visits
+    }
+
+    /// Every device from this space down, with paths relative to this space.
+    pub fn walk_devices(&self) -> Vec<DeviceVisit<'_>> {
+        self.walk().into_iter()
+            .flat_map(|visit| visit.space.devices().into_iter().map(move |(key, device)| DeviceVisit {
+                path: join_path(&visit.path, key),
+                device,
+                metadata: visit.space.device_metadata(key).unwrap_or(&NO_METADATA),
+                space: visit.space,
+            }))
+            .collect()
+    }
+
+    /// Every device from this space down that matches the query.
+    pub fn query_devices(&self, query: &DeviceQuery) -> Vec<DeviceVisit<'_>> {
+        self.walk_devices().into_iter()
+            .filter(|visit| query.matches(visit.device, visit.metadata))
+            .collect()
+    }
+
+    /// Connects the presence of every space and every device from this space down to the bus, under their paths.
+    pub fn bind_events(&self, bus: &EventBus) {
+        self.bind_events_where(bus, |_| true);
+    }
+
+    /// Like `bind_events`, but only binds the devices whose path passes `filter`, e.g. the ones a reload attached anew.
+    pub fn bind_events_where(&self, bus: &EventBus, filter: impl Fn(&str) -> bool) {
+        for visit in self.walk() {
+            visit.space.presence().bind(bus.publisher(&visit.path));
+        }
+        for visit in self.walk_devices().into_iter().filter(|visit| filter(&visit.path)) {
+            visit.device.bind_events(bus.publisher(&visit.path));
+        }
+    }
+
+    /// Every space from this one down carrying the tag.
+    pub fn spaces_tagged(&self, tag: &str) -> Vec<SpaceVisit<'_>> {
+        self.walk().into_iter()
+            .filter(|visit| visit.space.metadata().has_tag(tag))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Capabilities, Device, LifeCycle};
+
+    struct Light;
+
+    impl LifeCycle for Light {
+        async fn init(&self) -> Result<(), Box<dyn std::error::Error>> { Ok(()) }
+        async fn dispose(&self) -> Result<(), Box<dyn std::error::Error>> { Ok(()) }
+    }
+
+    impl Capabilities for Light {}
+    impl Device for Light {}
+
+    struct Room {
+        name: &'static str,
+        presence: SpacePresence,
+        metadata: Metadata,
+        rooms: Vec<(&'static str, Room)>,
+        lights: Vec<(&'static str, Light, Metadata)>,
+    }
+
+    fn tags(tags: &[&str]) -> Metadata {
+        Metadata { tags: tags.iter().map(|tag| tag.to_string()).collect(), ..Default::default() }
+    }
+
+    impl Room {
+        fn new(name: &'static str, rooms: Vec<(&'static str, Room)>, lights: Vec<&'static str>) -> Self {
+            Room {
+                name,
+                presence: SpacePresence::default(),
+                metadata: Metadata::default(),
+                rooms,
+                lights: lights.into_iter().map(|key| (key, Light, tags(&[key]))).collect(),
+            }
+        }
+    }
+
+    impl LifeCycle for Room {
+        async fn init(&self) -> Result<(), Box<dyn std::error::Error>> { Ok(()) }
+        async fn dispose(&self) -> Result<(), Box<dyn std::error::Error>> { Ok(()) }
+    }
+
+    impl Space for Room {
+        fn name(&self) -> &str { self.name }
+        fn presence(&self) -> &SpacePresence { &self.presence }
+        fn metadata(&self) -> &Metadata { &self.metadata }
+
+        fn device_metadata(&self, key: &str) -> Option<&Metadata> {
+            self.lights.iter().find(|(k, _, _)| *k == key).map(|(_, _, metadata)| metadata)
+        }
+
+        fn sub_spaces(&self) -> Vec<(&str, &dyn Space)> {
+            self.rooms.iter().map(|(key, room)| (*key, room as &dyn Space)).collect()
+        }
+
+        fn devices(&self) -> Vec<(&str, &dyn DynDevice)> {
+            self.lights.iter().map(|(key, light, _)| (*key, light as &dyn DynDevice)).collect()
+        }
+    }
+
+    fn apartment() -> Room {
+        Room::new("Apartment", vec![
+            ("main_living_area", Room::new("Main Living Area", vec![
+                ("kitchen", Room::new("Kitchen", vec![], vec!["ceiling_light"])),
+                ("living_room", Room::new("Living Room", vec![], vec!["ceiling_light"])),
+            ], vec![])),
+            ("office", Room::new("Office", vec![], vec!["ceiling_light", "desk_light"])),
+        ], vec![])
+    }
+
+    #[test]
+    fn test_find() {
+        let apartment = apartment();
+        let root: &dyn Space = &apartment;
+
+        assert_eq!(root.find_space("").unwrap().name(), "Apartment");
+        assert_eq!(root.find_space("main_living_area/kitchen").unwrap().name(), "Kitchen");
+        assert!(root.find_space("main_living_area/bathroom").is_none());
+        assert!(root.find_device("office/desk_light").is_some());
+        assert!(root.find_device("office/floor_lamp").is_none());
+        assert_eq!(root.parent_of("main_living_area/kitchen/ceiling_light").unwrap().name(), "Kitchen");
+        assert_eq!(root.parent_of("office").unwrap().name(), "Apartment");
+        assert!(root.parent_of("").is_none());
+    }
+
+    #[test]
+    fn test_walk() {
+        let apartment = apartment();
+        let root: &dyn Space = &apartment;
+
+        let paths: Vec<_> = root.walk().into_iter().map(|visit| visit.path).collect();
+        assert_eq!(paths, vec!["", "main_living_area", "main_living_area/kitchen", "main_living_area/living_room", "office"]);
+
+        let devices: Vec<_> = root.walk_devices().into_iter().map(|visit| visit.path).collect();
+        assert_eq!(devices, vec![
+            "main_living_area/kitchen/ceiling_light",
+            "main_living_area/living_room/ceiling_light",
+            "office/ceiling_light",
+            "office/desk_light",
+        ]);
+    }
+
+    #[test]
+    fn test_query() {
+        let mut apartment = apartment();
+        apartment.rooms[1].1.metadata = tags(&["work"]);
+        let root: &dyn Space = &apartment;
+
+        let desk_lights = root.query_devices(&DeviceQuery::new().tagged("desk_light"));
+        assert_eq!(desk_lights.len(), 1);
+        assert_eq!(desk_lights[0].path, "office/desk_light");
+
+        let ceiling_lights = root.find_space("main_living_area").unwrap().query_devices(&DeviceQuery::new().tagged("ceiling_light"));
+        assert_eq!(ceiling_lights.len(), 2);
+        assert!(root.query_devices(&DeviceQuery::new().with_capability(Capability::OnOff)).is_empty());
+
+        let spaces: Vec<_> = root.spaces_tagged("work").into_iter().map(|visit| visit.path).collect();
+        assert_eq!(spaces, vec!["office"]);
+    }
+}
@@ -0,0 +1,306 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use crate::{Capability, CapabilityValue, CommandOptions, DeviceQuery, Event, EventBus, EventFilter, EventKind, Space};
+
+/// What a state machine reacts to.
+#[derive(Debug)]
+pub enum Input<'a> {
+    /// New presence probability of the machine's space.
+    Presence(f32),
+    /// Any other event from the space or below it.
+    Event(&'a Event),
+    /// The timer set by an earlier transition ran out.
+    Timeout,
+}
+
+/// What a transition does to the machine's timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timer {
+    /// Leaves a running timer alone.
+    Keep,
+    /// Starts the timer unless it is already running, so repeated inputs don't push the deadline out.
+    Start(Duration),
+    /// Starts the timer over.
+    Restart(Duration),
+    Cancel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition<S> {
+    pub to: S,
+    pub timer: Timer,
+}
+
+impl<S> Transition<S> {
+    pub fn to(to: S, timer: Timer) -> Self {
+        Transition { to, timer }
+    }
+}
+
+/// Behaviour attached to a space, as states and the inputs that move between them.
+pub trait StateMachine {
+    type State: Copy + Eq + fmt::Debug;
+
+    fn name(&self) -> &str;
+    fn initial(&self) -> Self::State;
+
+    /// Where to go from `state` on `input`, `None` to ignore the input.
+    fn next(&self, state: Self::State, input: &Input) -> Option<Transition<Self::State>>;
+
+    /// Commands for the devices of the space when the machine enters `state`.
+    fn on_enter(&self, _state: Self::State) -> Vec<(DeviceQuery, CapabilityValue)> {
+        Vec::new()
+    }
+}
+
+/// A state machine running against the space at `path`.
+pub struct SpaceStateMachine<M: StateMachine> {
+    path: String,
+    machine: M,
+    state: watch::Sender<M::State>,
+    deadline: Mutex<Option<Instant>>,
+}
+
+impl<M: StateMachine> SpaceStateMachine<M> {
+    pub fn new(path: &str, machine: M) -> Self {
+        let initial = machine.initial();
+        SpaceStateMachine {
+            path: path.to_string(),
+            machine,
+            state: watch::channel(initial).0,
+            deadline: Mutex::new(None),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn state(&self) -> M::State {
+        *self.state.borrow()
+    }
+
+    pub fn watch(&self) -> watch::Receiver<M::State> {
+        self.state.subscribe()
+    }
+
+    /// When the running timer runs out, if there is one.
+    pub fn deadline(&self) -> Option<Instant> {
+        *self.deadline.lock().unwrap()
+    }
+
+    /// Feeds an input to the machine, returning the new state if it changed.
+    pub fn handle(&self, input: &Input, now: Instant) -> Option<M::State> {
+        let from = self.state();
+        let mut deadline = self.deadline.lock().unwrap();
+        // a timeout uses the timer up, even when the machine ignores it
+        if matches!(input, Input::Timeout) {
+            *deadline = None;
+        }
+        let transition = self.machine.next(from, input)?;
+
+        match transition.timer {
+            Timer::Keep => {},
+            Timer::Start(duration) => {
+                deadline.get_or_insert(now + duration);
+            },
+            Timer::Restart(duration) => *deadline = Some(now + duration),
+            Timer::Cancel => *deadline = None,
+        }
+
+        if transition.to == from {
+            return None;
+        }
+        log::info!("{} in {} went from {:?} to {:?}", self.machine.name(), self.path, from, transition.to);
+        self.state.send_replace(transition.to);
+        Some(transition.to)
+    }
+
+    /// Drives the machine from the events of its space until cancelled, sending the commands of every state it enters.
+    pub async fn run(&self, root: &dyn Space, events: &EventBus, cancel: CancellationToken) {
+        let Some(space) = root.find_space(&self.path) else {
+            log::error!("{}: no space at {}", self.machine.name(), self.path);
+            return;
+        };
+        let mut subscription = events.subscribe(EventFilter::all().within(&self.path));
+        let publisher = events.publisher(&self.path);
+        let mut commands = FuturesUnordered::new();
+
+        let mut current = self.state();
+        let mut entered = self.handle(&Input::Presence(space.presence().probability()), Instant::now());
+        loop {
+            if let Some(to) = entered.take() {
+                publisher.publish(EventKind::StateChanged {
+                    machine: self.machine.name().to_string(),
+                    from: format!("{:?}", current),
+                    to: format!("{:?}", to),
+                });
+                current = to;
+                for (query, value) in self.machine.on_enter(to) {
+                    for visit in space.query_devices(&query) {
+                        commands.push(async move {
+                            if let Err(error) = visit.device.command(value, CommandOptions::default()).await {
+                                log::error!("Setting {:?} on {} failed: {}", value, visit.path, error);
+                            }
+                        });
+                    }
+                }
+            }
+
+            let deadline = self.deadline();
+            entered = tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.handle(&Input::Timeout, Instant::now())
+                },
+                Some(()) = commands.next(), if !commands.is_empty() => None,
+                event = subscription.recv() => {
+                    let Some(event) = event else { break };
+                    let input = match event.kind {
+                        EventKind::PresenceChanged { probability, .. } if event.path == self.path => Input::Presence(probability),
+                        _ => Input::Event(&event),
+                    };
+                    self.handle(&input, Instant::now())
+                },
+            };
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OccupancyState {
+    Vacant,
+    Occupied,
+    Dimming,
+}
+
+/// Lights follow presence: on while the space is occupied, dimmed for a while once it empties, then off.
+#[derive(Debug, Clone)]
+pub struct OccupancyLighting {
+    /// Presence probability from which the space counts as occupied.
+    pub threshold: f32,
+    /// How long the space has to stay below the threshold before dimming, riding out sensor dropouts.
+    pub vacancy_delay: Duration,
+    /// How long the lights stay dimmed before turning off.
+    pub dimming_time: Duration,
+    pub dimmed_brightness: u8,
+    pub lights: DeviceQuery,
+}
+
+impl Default for OccupancyLighting {
+    fn default() -> Self {
+        OccupancyLighting {
+            threshold: 0.5,
+            vacancy_delay: Duration::from_secs(60),
+            dimming_time: Duration::from_secs(30),
+            dimmed_brightness: 30,
+            lights: DeviceQuery::new().with_capability(Capability::OnOff),
+        }
+    }
+}
+
+impl StateMachine for OccupancyLighting {
+    type State = OccupancyState;
+
+    fn name(&self) -> &str {
+        "Occupancy lighting"
+    }
+
+    fn initial(&self) -> OccupancyState {
+        OccupancyState::Vacant
+    }
+
+    fn next(&self, state: OccupancyState, input: &Input) -> Option<Transition<OccupancyState>> {
+        use OccupancyState::*;
+        let occupied = match input {
+            Input::Presence(probability) => Some(*probability >= self.threshold),
+            _ => None,
+        };
+        match (state, occupied, input) {
+            (Vacant | Occupied | Dimming, Some(true), _) => Some(Transition::to(Occupied, Timer::Cancel)),
+            (Occupied, Some(false), _) => Some(Transition::to(Occupied, Timer::Start(self.vacancy_delay))),
+            (Occupied, _, Input::Timeout) => Some(Transition::to(Dimming, Timer::Restart(self.dimming_time))),
+            (Dimming, _, Input::Timeout) => Some(Transition::to(Vacant, Timer::Cancel)),
+            _ => None,
+        }
+    }
+
+    fn on_enter(&self, state: OccupancyState) -> Vec<(DeviceQuery, CapabilityValue)> {
+        let dimmable = self.lights.clone().with_capability(Capability::Brightness);
+        match state {
+            OccupancyState::Occupied => vec![
+                (self.lights.clone(), CapabilityValue::OnOff(true)),
+                (dimmable, CapabilityValue::Brightness(100)),
+            ],
+            OccupancyState::Dimming => vec![(dimmable, CapabilityValue::Brightness(self.dimmed_brightness))],
+            OccupancyState::Vacant => vec![(self.lights.clone(), CapabilityValue::OnOff(false))],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_occupancy_lighting() {
+        let lighting = SpaceStateMachine::new("office", OccupancyLighting::default());
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(lighting.handle(&Input::Presence(1.0), at(0)), Some(OccupancyState::Occupied));
+
+        // a dropout doesn't push the deadline out, and presence coming back cancels it
+        assert_eq!(lighting.handle(&Input::Presence(0.4), at(10)), None);
+        assert_eq!(lighting.handle(&Input::Presence(0.2), at(40)), None);
+        assert_eq!(lighting.deadline(), Some(at(70)));
+        assert_eq!(lighting.handle(&Input::Presence(0.9), at(50)), None);
+        assert_eq!(lighting.deadline(), None);
+
+        assert_eq!(lighting.handle(&Input::Presence(0.3), at(100)), None);
+        assert_eq!(lighting.handle(&Input::Timeout, at(160)), Some(OccupancyState::Dimming));
+        assert_eq!(lighting.deadline(), Some(at(190)));
+        assert_eq!(lighting.handle(&Input::Timeout, at(190)), Some(OccupancyState::Vacant));
+        assert_eq!(lighting.deadline(), None);
+        assert_eq!(lighting.state(), OccupancyState::Vacant);
+    }
+
+    /// Starts a timer on presence and has nothing to do when it runs out.
+    struct Reminder;
+
+    impl StateMachine for Reminder {
+        type State = bool;
+
+        fn name(&self) -> &str {
+            "Reminder"
+        }
+
+        fn initial(&self) -> bool {
+            false
+        }
+
+        fn next(&self, _state: bool, input: &Input) -> Option<Transition<bool>> {
+            match input {
+                Input::Presence(_) => Some(Transition::to(true, Timer::Start(Duration::from_secs(10)))),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_ignored_timeout() {
+        let reminder = SpaceStateMachine::new("office", Reminder);
+        let start = Instant::now();
+
+        assert_eq!(reminder.handle(&Input::Presence(1.0), start), Some(true));
+        assert_eq!(reminder.deadline(), Some(start + Duration::from_secs(10)));
+        // the timer is used up rather than left to fire over and over
+        assert_eq!(reminder.handle(&Input::Timeout, start + Duration::from_secs(10)), None);
+        assert_eq!(reminder.deadline(), None);
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use futures_util::future::join_all;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use crate::{DynDevice, EventBus, EventKind, LifeCycleStage, LocalBoxFuture, Space, join_path};
+
+#[derive(Debug, Clone, Copy)]
+pub struct SupervisorConfig {
+    /// How long a device may take to init before it counts as failed.
+    pub init_timeout: Duration,
+    pub dispose_timeout: Duration,
+    /// Wait before the first retry of a failed device, doubled on every further failure.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        SupervisorConfig {
+            init_timeout: Duration::from_secs(30),
+            dispose_timeout: Duration::from_secs(10),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnitStatus {
+    Starting,
+    Running,
+    /// Failed to init, retried in the background.
+    Degraded { error: String, attempts: u32 },
+    Stopping,
+    Stopped,
+}
+
+#[derive(Debug)]
+struct Unit {
+    status: UnitStatus,
+    retry_at: Option<Instant>,
+    backoff: Duration,
+}
+
+/// Brings the devices of a space tree up and down, so one unreachable device doesn't hold up the rest.
+///
+/// Devices in a space and the sub-spaces next to them are started concurrently, mirroring the tree.
+/// Devices that fail are marked degraded and retried with backoff by `supervise`.
+pub struct Supervisor {
+    config: SupervisorConfig,
+    events: EventBus,
+    units: Mutex<BTreeMap<String, Unit>>,
+    /// Paths of the running devices, in the order they came up.
+    started: Mutex<Vec<String>>,
+}
+
+impl Supervisor {
+    pub fn new(config: SupervisorConfig, events: &EventBus) -> Self {
+        Supervisor {
+            config,
+            events: events.clone(),
+            units: Mutex::new(BTreeMap::new()),
+            started: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn status(&self, path: &str) -> Option<UnitStatus> {
+        self.units.lock().unwrap().get(path).map(|unit| unit.status.clone())
+    }
+
+    pub fn statuses(&self) -> Vec<(String, UnitStatus)> {
+        self.units.lock().unwrap().iter()
+            .map(|(path, unit)| (path.clone(), unit.status.clone()))
+            .collect()
+    }
+
+    fn set_status(&self, path: &str, status: UnitStatus) {
+        let stage = match &status {
+            UnitStatus::Starting => LifeCycleStage::Initializing,
+            UnitStatus::Running => LifeCycleStage::Initialized,
+            UnitStatus::Degraded { .. } => LifeCycleStage::Failed,
+            UnitStatus::Stopping => LifeCycleStage::Disposing,
+            UnitStatus::Stopped => LifeCycleStage::Disposed,
+        };
+        let mut units = self.units.lock().unwrap();
+        let unit = units.entry(path.to_string()).or_insert(Unit {
+            status: UnitStatus::Starting,
+            retry_at: None,
+            backoff: self.config.initial_backoff,
+        });
+        if let UnitStatus::Degraded { .. } = status {
+            unit.retry_at = Some(Instant::now() + unit.backoff);
+            unit.backoff = (unit.backoff * 2).min(self.config.max_backoff);
+        } else {
+            unit.retry_at = None;
+        }
+        unit.status = status;
+        drop(units);
+        self.events.publisher(path).publish(EventKind::LifeCycle { stage });
+    }
+
+    async fn start_device(&self, path: &str, device: &dyn DynDevice) {
+        let attempts = match self.status(path) {
+            Some(UnitStatus::Running) => return,
+            Some(UnitStatus::Degraded { attempts, .. }) => attempts + 1,
+            _ => 1,
+        };
+        self.set_status(path, UnitStatus::Starting);
+
+        let error = match tokio::time::timeout(self.config.init_timeout, device.init()).await {
+            Ok(Ok(())) => {
+                log::info!("{} is up", path);
+                self.set_status(path, UnitStatus::Running);
+                self.started.lock().unwrap().push(path.to_string());
+                return;
+            },
+            Ok(Err(error)) => error.to_string(),
+            Err(_) => format!("No response within {:?}", self.config.init_timeout),
+        };
+        log::warn!("{} failed to start (attempt {}): {}", path, attempts, error);
+        self.set_status(path, UnitStatus::Degraded { error, attempts });
+    }
+
+    fn start_space<'a>(&'a self, space: &'a dyn Space, path: String) -> LocalBoxFuture<'a, ()> {
+        Box::pin(async move {
+            let devices = space.devices().into_iter().map(|(key, device)| {
+                let path = join_path(&path, key);
+                async move { self.start_device(&path, device).await }
+            });
+            let sub_spaces = space.sub_spaces().into_iter()
+                .map(|(key, sub_space)| self.start_space(sub_space, join_path(&path, key)));
+            futures_util::join!(join_all(devices), join_all(sub_spaces));
+        })
+    }
+
+    /// Inits every device from `root` down that isn't running yet.
+    /// Failures don't stop the others, they leave the device degraded.
+    pub async fn start(&self, root: &dyn Space) {
+        self.start_space(root, String::new()).await;
+        let degraded = self.statuses().into_iter()
+            .filter(|(_, status)| matches!(status, UnitStatus::Degraded { .. }))
+            .count();
+        log::info!("{} devices started, {} degraded", self.started.lock().unwrap().len(), degraded);
+    }
+
+    /// Retries degraded devices with backoff until cancelled. A retry that hangs doesn't hold up the others.
+    pub async fn supervise(&self, root: &dyn Space, cancel: CancellationToken) {
+        let mut retries = FuturesUnordered::new();
+        loop {
+            let next_retry = self.units.lock().unwrap().values().filter_map(|unit| unit.retry_at).min();
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep_until(next_retry.unwrap_or_else(Instant::now)), if next_retry.is_some() => {},
+                Some(()) = retries.next(), if !retries.is_empty() => continue,
+                // nothing to retry, wait for the end
+                _ = std::future::pending::<()>(), if next_retry.is_none() && retries.is_empty() => {},
+            }
+
+            let now = Instant::now();
+            let due: Vec<_> = self.units.lock().unwrap().iter_mut()
+                .filter(|(_, unit)| unit.retry_at.is_some_and(|retry_at| retry_at <= now))
+                .map(|(path, unit)| {
+                    unit.retry_at = None;
+                    path.clone()
+                })
+                .collect();
+            for path in due {
+                if let Some(device) = root.find_device(&path) {
+                    retries.push(async move { self.start_device(&path, device).await });
+                }
+            }
+        }
+    }
+
+    /// Disposes the running devices, in the reverse order they came up.
+    pub async fn stop(&self, root: &dyn Space) {
+        self.stop_where(root, |_| true).await;
+    }
+
+    /// Disposes the devices at `paths` and forgets about them, for devices about to leave the tree.
+    pub async fn stop_devices(&self, root: &dyn Space, paths: &[String]) {
+        self.stop_where(root, |path| paths.iter().any(|p| p == path)).await;
+        self.units.lock().unwrap().retain(|path, _| !paths.contains(path));
+    }
+
+    async fn stop_where(&self, root: &dyn Space, filter: impl Fn(&str) -> bool) {
+        let stopping: Vec<_> = {
+            let mut started = self.started.lock().unwrap();
+            let (stopping, running) = started.drain(..).partition(|path| filter(path));
+            *started = running;
+            stopping
+        };
+        for path in stopping.iter().rev() {
+            let Some(device) = root.find_device(path) else { continue };
+            self.set_status(path, UnitStatus::Stopping);
+            match tokio::time::timeout(self.config.dispose_timeout, device.dispose()).await {
+                Ok(Ok(())) => {},
+                Ok(Err(error)) => log::error!("Disposing {} failed: {}", path, error),
+                Err(_) => log::error!("Disposing {} timed out", path),
+            }
+            self.set_status(path, UnitStatus::Stopped);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use crate::{Capabilities, Device, LifeCycle, Metadata, SpacePresence};
+
+    /// Fails to init the first `failures` times, or never answers if `hangs`.
+    #[derive(Default)]
+    struct Sensor {
+        failures: u32,
+        hangs: bool,
+        attempts: AtomicU32,
+        disposed: AtomicU32,
+    }
+
+    impl LifeCycle for Sensor {
+        async fn init(&self) -> Result<(), Box<dyn Error>> {
+            if self.hangs {
+                std::future::pending::<()>().await;
+            }
+            if self.attempts.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err("Unreachable".into());
+            }
+            Ok(())
+        }
+
+        async fn dispose(&self) -> Result<(), Box<dyn Error>> {
+            self.disposed.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    impl Capabilities for Sensor {}
+    impl Device for Sensor {}
+
+    #[derive(Default)]
+    struct Hallway {
+        presence: SpacePresence,
+        metadata: Metadata,
+        door: Sensor,
+        motion: Sensor,
+        smoke: Sensor,
+    }
+
+    impl LifeCycle for Hallway {
+        async fn init(&self) -> Result<(), Box<dyn Error>> { Ok(()) }
+        async fn dispose(&self) -> Result<(), Box<dyn Error>> { Ok(()) }
+    }
+
+    impl Space for Hallway {
+        fn name(&self) -> &str { "Hallway" }
+        fn presence(&self) -> &SpacePresence { &self.presence }
+        fn metadata(&self) -> &Metadata { &self.metadata }
+        fn device_metadata(&self, _key: &str) -> Option<&Metadata> { None }
+        fn sub_spaces(&self) -> Vec<(&str, &dyn Space)> { Vec::new() }
+
+        fn devices(&self) -> Vec<(&str, &dyn DynDevice)> {
+            vec![("door", &self.door), ("motion", &self.motion), ("smoke", &self.smoke)]
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_supervisor() {
+        let hallway = Hallway {
+            motion: Sensor { failures: 2, ..Default::default() },
+            smoke: Sensor { hangs: true, ..Default::default() },
+            ..Default::default()
+        };
+        let root: &dyn Space = &hallway;
+        let supervisor = Supervisor::new(SupervisorConfig::default(), &EventBus::default());
+
+        supervisor.start(root).await;
+        assert_eq!(supervisor.status("door"), Some(UnitStatus::Running));
+        assert_eq!(supervisor.status("motion"), Some(UnitStatus::Degraded { error: "Unreachable".to_string(), attempts: 1 }));
+        assert!(matches!(supervisor.status("smoke"), Some(UnitStatus::Degraded { .. })));
+
+        // the smoke sensor hanging on every retry doesn't hold up the motion sensor's
+        let cancel = CancellationToken::new();
+        let stop = cancel.clone();
+        tokio::join!(supervisor.supervise(root, cancel), async {
+            tokio::time::sleep(Duration::from_millis(3500)).await;
+            stop.cancel();
+        });
+        assert_eq!(supervisor.status("motion"), Some(UnitStatus::Running));
+        assert_eq!(hallway.motion.attempts.load(Ordering::SeqCst), 3);
+
+        supervisor.stop(root).await;
+        assert_eq!(supervisor.status("door"), Some(UnitStatus::Stopped));
+        assert_eq!(hallway.motion.disposed.load(Ordering::SeqCst), 1);
+        assert_eq!(hallway.smoke.disposed.load(Ordering::SeqCst), 0);
+    }
+}
@@ -1,12 +1,27 @@
-use clap::{Arg, Command};
-use core::{Driver, DiscoveryInfo};
-use driver::AqaraFP2Driver; // Import both the struct and the trait
+use clap::{Arg, ArgMatches, Command};
+use clap::builder::PossibleValuesParser;
+use domus_core::DiscoveryOptions;
+use driver::DriverRegistry;
+use futures_util::StreamExt;
+use std::path::Path;
+use std::time::Duration;
 use std::process::exit; // Added this line to import the exit function
+use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
+    let registry = DriverRegistry::builtin();
+
+    // stop scanning on ctrl-c, keeping whatever was found so far
+    let cancel = CancellationToken::new();
+    let interrupted = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            interrupted.cancel();
+        }
+    });
 
     let matches = Command::new("disco")
         .version("1.0")
@@ -20,10 +35,10 @@ async fn main() {
                         .short('d')
                         .long("driver")
                         .value_name("NAME")
-                        .help("Driver to use for discovery")
-                        .value_parser(["aqarafp2"])
-                        .required(true),
+                        .help("Driver to use for discovery, all drivers if omitted")
+                        .value_parser(PossibleValuesParser::new(registry.names())),
                 )
+                .args(discovery_args())
                 .arg(Arg::new("debug").long("debug").help("Turn on debugging")),
         )
         .subcommand(
@@ -34,7 +49,7 @@ async fn main() {
                         .long("driver")
                         .value_name("NAME")
                         .help("Driver to use")
-                        .value_parser(["aqarafp2"])
+                        .value_parser(PossibleValuesParser::new(registry.names()))
                         .required(true),
                 )
                 .arg(
@@ -43,61 +58,127 @@ async fn main() {
                         .value_name("NAME")
                         .help("Device identifier")
                         .required(true),
-                )                
+                )
+                .arg(
+                    Arg::new("out")
+                        .short('o')
+                        .long("out")
+                        .value_name("DIR")
+                        .help("Directory to write the device record to")
+                        .default_value("."),
+                )
+                .args(discovery_args())
                 .arg(Arg::new("debug").long("debug").help("Turn on debugging")),
-        )        
+        )
         .get_matches();
 
     if let Some(subcommand) = matches.subcommand() {
         match subcommand {
             ("scan", cmd) => {
+                let drivers: Vec<_> = match cmd.get_one::<String>("driver") {
+                    Some(name) => registry.get(name).into_iter().collect(),
+                    None => registry.iter().collect(),
+                };
 
-                let driver = cmd.get_one::<String>("driver").unwrap();
-
-                match driver.as_str() {
-                    "aqarafp2" => {
-                        println!("Scanning for Aqara FP2 devices");
-                        let driver = AqaraFP2Driver::new();
-                        let discoveries = driver.discover().await;
-                        
-                        if discoveries.len() == 0 {
-                            println!("No devices found");
-                            exit(0);
-                        }
-
-                        for discovery in discoveries {
-                            println!("Discovered Aqara FP2 device: {} (id: {})", discovery.name(), discovery.id());
+                let mut found = 0;
+                for driver in drivers {
+                    println!("Scanning for {} devices", driver.name());
+                    let mut discoveries = match driver.discover(discovery_options(cmd, &cancel)).await {
+                        Ok(discoveries) => discoveries,
+                        Err(e) => {
+                            println!("Failed to scan for {} devices: {}", driver.name(), e);
+                            continue;
                         }
+                    };
+                    while let Some(discovery) = discoveries.next().await {
+                        println!("Discovered {} device: {} (id: {})", driver.name(), discovery.name(), discovery.id());
+                        found += 1;
+                    }
+                }
 
-                        
-                    },
-                    _ => panic!("Unknown driver: {}", driver)
+                if found == 0 {
+                    println!("No devices found");
+                    exit(0);
                 }
             },
             ("pair", cmd) => {
-                let driver = cmd.get_one::<String>("driver").unwrap();
+                let driver = registry.get(cmd.get_one::<String>("driver").unwrap()).unwrap();
                 let device_id = cmd.get_one::<String>("id").unwrap();
+                let out = cmd.get_one::<String>("out").unwrap();
 
-                match driver.as_str() {
-                    "aqarafp2" => {
-                        println!("Pairing Aqara FP2 device with id {}", device_id);
-                        let driver = AqaraFP2Driver::new();
-                        let discoveries = driver.discover().await;
-                        let discovery = discoveries.iter().filter(|d| d.id() == device_id).next();
-                        if let None = discovery {
-                            println!("Could not find Aqara FP2 device with id: {}", device_id);
+                println!("Pairing {} device with id {}", driver.name(), device_id);
+                let options = discovery_options(cmd, &cancel);
+                let found = options.cancel.clone();
+                let discoveries = match driver.discover(options).await {
+                    Ok(discoveries) => discoveries,
+                    Err(e) => {
+                        println!("Failed to scan for {} devices: {}", driver.name(), e);
+                        exit(1);
+                    }
+                };
+                let discovery = discoveries
+                    .filter(|d| std::future::ready(d.id() == device_id))
+                    .next()
+                    .await;
+                found.cancel();
+                let Some(discovery) = discovery else {
+                    println!("Could not find {} device with id: {}", driver.name(), device_id);
+                    exit(1);
+                };
+                println!("Device found, attempting to pair...");
+                match driver.pair(discovery.as_ref()).await {
+                    Ok(properties) => {
+                        let path = Path::new(out).join(format!("{}.toml", properties.id().replace(':', "")));
+                        if let Err(e) = properties.to_toml().and_then(|text| Ok(std::fs::write(&path, text)?)) {
+                            println!("Device paired, but the record could not be written: {}", e);
                             exit(1);
                         }
-                        println!("Device found, attempting to pair...");
-                        let _pairing = driver.pair(discovery.unwrap()).await;
+                        println!("Device paired, record written to {}", path.display());
                     },
-                    _ => panic!("Unknown driver: {}", driver)
+                    Err(e) => {
+                        println!("Failed to pair device: {}", e);
+                        exit(1);
+                    }
                 }
             },
             ("drivers", _) => {
                 println!("Listing all available drivers");
+                for driver in registry.iter() {
+                    let info = driver.info();
+                    println!("{:<12} {}", info.name, info.description);
+                    let capabilities: Vec<_> = info.capabilities.iter().map(|c| c.name()).collect();
+                    println!("{:<12} capabilities: {}", "", capabilities.join(", "));
+                }
             }
             _ => {}
         }
     }
 }
+
+fn discovery_args() -> [Arg; 2] {
+    [
+        Arg::new("timeout")
+            .short('t')
+            .long("timeout")
+            .value_name("SECONDS")
+            .help("How long to scan for, 0 to scan until interrupted")
+            .value_parser(clap::value_parser!(u64))
+            .default_value("5"),
+        Arg::new("interface")
+            .short('i')
+            .long("interface")
+            .value_name("NAME")
+            .help("Network interface to scan on"),
+    ]
+}
+
+fn discovery_options(cmd: &ArgMatches, cancel: &CancellationToken) -> DiscoveryOptions {
+    DiscoveryOptions {
+        timeout: match cmd.get_one::<u64>("timeout").unwrap() {
+            0 => None,
+            seconds => Some(Duration::from_secs(*seconds)),
+        },
+        interface: cmd.get_one::<String>("interface").cloned(),
+        cancel: cancel.child_token(),
+    }
+}
\ No newline at end of file
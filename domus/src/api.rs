@@ -0,0 +1,530 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use domus_core::{Capability, CapabilityValue, CommandError, CommandOptions, DeviceGroup, DeviceQuery, DynDevice, Health, Metadata, Scene, SceneOptions, Space};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::{Method, Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use crate::rules::OCCUPIED_THRESHOLD;
+use crate::store::{HistoryPoint, Metric, StateStore};
+
+/// Largest request body taken, commands being tiny.
+const MAX_BODY: usize = 64 * 1024;
+/// What a history covers when the query doesn't say.
+const DEFAULT_HISTORY: Duration = Duration::from_secs(86400);
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// A space with everything below it. Paths are relative to the root, times in milliseconds since the epoch.
+#[derive(Debug, Serialize)]
+pub struct SpaceResource {
+    pub path: String,
+    pub name: String,
+    pub metadata: Metadata,
+    pub presence: PresenceResource,
+    pub devices: Vec<DeviceResource>,
+    pub spaces: Vec<SpaceResource>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceResource {
+    pub path: String,
+    pub capabilities: Vec<Capability>,
+    /// The current value of every capability the device has reported.
+    pub state: Vec<CapabilityValue>,
+    pub controllable: bool,
+    pub health: Health,
+    pub last_seen: Option<u64>,
+    pub metadata: Metadata,
+    /// Keys of the members, if the device is a group.
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PresenceResource {
+    pub path: String,
+    pub probability: f32,
+    pub occupied: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryResource {
+    pub path: String,
+    pub metric: String,
+    pub since: u64,
+    pub until: u64,
+    /// Each value weighted by how long it held, e.g. the share of the time a space was occupied.
+    pub average: Option<f64>,
+    pub points: Vec<HistoryPointResource>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryPointResource {
+    pub time: u64,
+    pub mean: f64,
+    pub min: f64,
+    pub max: f64,
+    pub samples: u32,
+}
+
+impl From<HistoryPoint> for HistoryPointResource {
+    fn from(point: HistoryPoint) -> Self {
+        HistoryPointResource { time: millis(point.time), mean: point.mean, min: point.min, max: point.max, samples: point.samples }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SceneReportResource {
+    pub scene: String,
+    pub applied: Vec<String>,
+    pub failed: Vec<CommandFailureResource>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CommandFailureResource {
+    pub device: String,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorResource {
+    pub error: String,
+}
+
+/// JSON schema of a capability value, `{"capability": "brightness", "value": 40}` and the like.
+fn value_schema(capability: Capability) -> Value {
+    let value = match capability {
+        Capability::Occupancy | Capability::OnOff => json!({ "type": "boolean" }),
+        Capability::Brightness | Capability::WindowCovering => json!({ "type": "integer", "minimum": 0, "maximum": 100 }),
+        Capability::ColorTemperature => json!({ "type": "integer", "minimum": 0, "maximum": u16::MAX }),
+        Capability::Color => json!({
+            "type": "object",
+            "properties": { "hue": { "type": "number" }, "saturation": { "type": "number" } },
+            "required": ["hue", "saturation"],
+        }),
+        Capability::Contact => json!({ "enum": ["open", "closed"] }),
+        Capability::Temperature | Capability::Humidity | Capability::Illuminance => json!({ "type": "number" }),
+        Capability::Lock => json!({ "enum": ["unlocked", "locked", "jammed"] }),
+    };
+    json!({
+        "type": "object",
+        "properties": { "capability": { "const": capability.to_string() }, "value": value },
+        "required": ["capability", "value"],
+    })
+}
+
+fn object(properties: Value) -> Value {
+    let required: Vec<_> = properties.as_object().into_iter().flat_map(|properties| properties.keys().cloned()).collect();
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+fn array_of(name: &str) -> Value {
+    json!({ "type": "array", "items": { "$ref": format!("#/$defs/{}", name) } })
+}
+
+/// Schemas of every response body and of commands, under `$defs` by resource name.
+fn schemas() -> Value {
+    let string = json!({ "type": "string" });
+    let strings = json!({ "type": "array", "items": string });
+    let millis = json!({ "type": "integer", "minimum": 0, "description": "Milliseconds since the epoch" });
+    let capabilities: Vec<_> = Capability::ALL.iter().map(|capability| capability.to_string()).collect();
+    let reason = |status: &str| object(json!({ "status": { "const": status }, "reason": string }));
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$defs": {
+            "Space": object(json!({
+                "path": string,
+                "name": string,
+                "metadata": { "$ref": "#/$defs/Metadata" },
+                "presence": { "$ref": "#/$defs/Presence" },
+                "devices": array_of("Device"),
+                "spaces": array_of("Space"),
+            })),
+            "Device": object(json!({
+                "path": string,
+                "capabilities": { "type": "array", "items": { "enum": capabilities } },
+                "state": array_of("CapabilityValue"),
+                "controllable": { "type": "boolean" },
+                "health": { "oneOf": [object(json!({ "status": { "const": "online" } })), reason("degraded"), reason("offline")] },
+                "last_seen": { "oneOf": [millis, { "type": "null" }] },
+                "metadata": { "$ref": "#/$defs/Metadata" },
+                "members": strings,
+            })),
+            "Metadata": object(json!({
+                "tags": strings,
+                "values": { "type": "object", "additionalProperties": string },
+            })),
+            "Presence": object(json!({
+                "path": string,
+                "probability": { "type": "number", "minimum": 0, "maximum": 1 },
+                "occupied": { "type": "boolean" },
+            })),
+            "CapabilityValue": { "oneOf": Capability::ALL.map(value_schema) },
+            "History": object(json!({
+                "path": string,
+                "metric": string,
+                "since": millis,
+                "until": millis,
+                "average": { "type": ["number", "null"] },
+                "points": { "type": "array", "items": object(json!({
+                    "time": millis,
+                    "mean": { "type": "number" },
+                    "min": { "type": "number" },
+                    "max": { "type": "number" },
+                    "samples": { "type": "integer", "minimum": 1 },
+                })) },
+            })),
+            "Scene": object(json!({
+                "name": string,
+                "space": string,
+                "targets": { "type": "array", "items": object(json!({ "device": string, "values": array_of("CapabilityValue") })) },
+            })),
+            "SceneReport": object(json!({
+                "scene": string,
+                "applied": strings,
+                "failed": { "type": "array", "items": object(json!({ "device": string, "error": string })) },
+            })),
+            "Error": object(json!({ "error": string })),
+        },
+    })
+}
+
+/// A JSON response.
+#[derive(Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl Response {
+    fn json(status: StatusCode, body: &impl Serialize) -> Self {
+        match serde_json::to_string(body) {
+            Ok(body) => Response { status, body },
+            Err(error) => Response::error(StatusCode::INTERNAL_SERVER_ERROR, error),
+        }
+    }
+
+    fn ok(body: &impl Serialize) -> Self {
+        Response::json(StatusCode::OK, body)
+    }
+
+    fn error(status: StatusCode, error: impl ToString) -> Self {
+        let body = serde_json::to_string(&ErrorResource { error: error.to_string() }).unwrap_or_default();
+        Response { status, body }
+    }
+
+    fn not_found(what: &str, path: &str) -> Self {
+        Response::error(StatusCode::NOT_FOUND, format!("No {} at {:?}", what, path))
+    }
+}
+
+/// The query's parameters, decoded.
+fn query_params(query: &str) -> BTreeMap<String, String> {
+    form_urlencoded::parse(query.as_bytes()).into_owned().collect()
+}
+
+/// Reads and controls a tree over HTTP, with JSON in and out:
+///
+/// - `GET /api/spaces[/path]` the space and everything below it
+/// - `GET /api/devices[?tag=..&capability=..]` every device, `GET /api/devices/path` one of them
+/// - `POST /api/devices/path` sends the `CapabilityValue` in the body to the device
+/// - `GET /api/presence[/path]` presence of every space, or of one
+/// - `GET /api/scenes` the scenes, `POST /api/scenes/name` applies one
+/// - `GET /api/history/path?metric=occupancy&since=..&until=..` the history of a device capability, or of a space's presence
+/// - `GET /api/schema` JSON schemas of the responses
+pub struct Api<'a> {
+    root: &'a dyn Space,
+    store: Option<&'a StateStore>,
+    scenes: &'a [Scene],
+}
+
+impl<'a> Api<'a> {
+    pub fn new(root: &'a dyn Space) -> Self {
+        Api { root, store: None, scenes: &[] }
+    }
+
+    /// Where the history comes from, without one history queries fail.
+    pub fn store(mut self, store: Option<&'a StateStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    pub fn scenes(mut self, scenes: &'a [Scene]) -> Self {
+        self.scenes = scenes;
+        self
+    }
+
+    /// Answers a request, `target` being its path and query.
+    pub async fn handle(&self, method: &Method, target: &str, body: &[u8]) -> Response {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let Some(path) = path.strip_prefix("/api") else {
+            return Response::not_found("resource", path);
+        };
+        let (resource, rest) = path.trim_start_matches('/').split_once('/').unwrap_or((path.trim_start_matches('/'), ""));
+        // e.g. scene names with spaces
+        let Ok(rest) = percent_encoding::percent_decode_str(rest.trim_end_matches('/')).decode_utf8() else {
+            return Response::error(StatusCode::BAD_REQUEST, "The path isn't valid UTF-8 once decoded");
+        };
+        let rest = rest.as_ref();
+        match (method, resource) {
+            (&Method::GET, "spaces") => self.space(rest),
+            (&Method::GET, "devices") if rest.is_empty() => self.devices(&query_params(query)),
+            (&Method::GET, "devices") => match self.root.find_device(rest) {
+                Some(device) => Response::ok(&self.device(rest, device)),
+                None => Response::not_found("device", rest),
+            },
+            (&Method::POST, "devices") => self.command(rest, body).await,
+            (&Method::GET, "presence") if rest.is_empty() => {
+                let presence: Vec<_> = self.root.walk().into_iter().map(|visit| presence(&visit.path, visit.space)).collect();
+                Response::ok(&presence)
+            },
+            (&Method::GET, "presence") => match self.root.find_space(rest) {
+                Some(space) => Response::ok(&presence(rest, space)),
+                None => Response::not_found("space", rest),
+            },
+            (&Method::GET, "scenes") if rest.is_empty() => Response::ok(&self.scenes),
+            (&Method::POST, "scenes") => self.apply_scene(rest).await,
+            (&Method::GET, "history") => self.history(rest, &query_params(query)),
+            (&Method::GET, "schema") => Response::ok(&schemas()),
+            (_, "spaces" | "devices" | "presence" | "scenes" | "history" | "schema") =>
+                Response::error(StatusCode::METHOD_NOT_ALLOWED, format!("{} isn't supported on /api/{}", method, resource)),
+            _ => Response::not_found("resource", resource),
+        }
+    }
+
+    fn space(&self, path: &str) -> Response {
+        match self.root.find_space(path) {
+            Some(space) => Response::ok(&self.space_resource(path, space)),
+            None => Response::not_found("space", path),
+        }
+    }
+
+    fn space_resource(&self, path: &str, space: &dyn Space) -> SpaceResource {
+        SpaceResource {
+            path: path.to_string(),
+            name: space.name().to_string(),
+            metadata: space.metadata().clone(),
+            presence: presence(path, space),
+            devices: space.devices().into_iter()
+                .map(|(key, device)| self.device(&domus_core::join_path(path, key), device))
+                .collect(),
+            spaces: space.sub_spaces().into_iter()
+                .map(|(key, sub_space)| self.space_resource(&domus_core::join_path(path, key), sub_space))
+                .collect(),
+        }
+    }
+
+    fn device(&self, path: &str, device: &dyn DynDevice) -> DeviceResource {
+        let capabilities = device.capabilities();
+        DeviceResource {
+            path: path.to_string(),
+            state: capabilities.iter().filter_map(|capability| device.state(*capability)).collect(),
+            capabilities,
+            controllable: device.as_controllable().is_some(),
+            health: device.health(),
+            last_seen: device.last_seen().map(millis),
+            metadata: self.root.parent_of(path)
+                .and_then(|space| space.device_metadata(path.rsplit(domus_core::PATH_SEPARATOR).next()?))
+                .cloned()
+                .unwrap_or_default(),
+            members: device.as_any().downcast_ref::<DeviceGroup>()
+                .map(|group| group.members().into_iter().map(|(key, _)| key.to_string()).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    fn devices(&self, params: &BTreeMap<String, String>) -> Response {
+        let mut query = DeviceQuery::new();
+        for (key, value) in params {
+            query = match key.as_str() {
+                "tag" => query.tagged(value),
+                "capability" => match value.parse() {
+                    Ok(capability) => query.with_capability(capability),
+                    Err(error) => return Response::error(StatusCode::BAD_REQUEST, error),
+                },
+                _ => return Response::error(StatusCode::BAD_REQUEST, format!("Unknown parameter {:?}", key)),
+            };
+        }
+        let devices: Vec<_> = self.root.query_devices(&query).into_iter()
+            .map(|visit| self.device(&visit.path, visit.device))
+            .collect();
+        Response::ok(&devices)
+    }
+
+    async fn command(&self, path: &str, body: &[u8]) -> Response {
+        let Some(device) = self.root.find_device(path) else {
+            return Response::not_found("device", path);
+        };
+        let value: CapabilityValue = match serde_json::from_slice(body) {
+            Ok(value) => value,
+            Err(error) => return Response::error(StatusCode::BAD_REQUEST, format!("Invalid command: {}", error)),
+        };
+        log::info!("API: setting {:?} on {}", value, path);
+        match device.command(value, CommandOptions::default()).await {
+            Ok(()) => Response::ok(&self.device(path, device)),
+            Err(error @ CommandError::Unsupported(_)) => Response::error(StatusCode::UNPROCESSABLE_ENTITY, error),
+            Err(error @ CommandError::Failed(_)) => Response::error(StatusCode::BAD_GATEWAY, error),
+            Err(error @ CommandError::Timeout) => Response::error(StatusCode::GATEWAY_TIMEOUT, error),
+        }
+    }
+
+    async fn apply_scene(&self, name: &str) -> Response {
+        let Some(scene) = self.scenes.iter().find(|scene| scene.name == name) else {
+            return Response::not_found("scene", name);
+        };
+        let options = SceneOptions::default();
+        let report = self.root.apply_scene(scene, options).await;
+        let status = if report.is_complete() { StatusCode::OK } else { StatusCode::BAD_GATEWAY };
+        Response::json(status, &SceneReportResource {
+            scene: scene.name.clone(),
+            applied: report.applied,
+            failed: report.failed.into_iter()
+                .map(|(device, error)| CommandFailureResource { device, error: error.to_string() })
+                .collect(),
+        })
+    }
+
+    fn history(&self, path: &str, params: &BTreeMap<String, String>) -> Response {
+        let Some(store) = self.store else {
+            return Response::error(StatusCode::SERVICE_UNAVAILABLE, "No history is kept, run with --state");
+        };
+        let time = |key| params.get(key)
+            .map(|millis| millis.parse().map(|millis| UNIX_EPOCH + Duration::from_millis(millis)))
+            .transpose()
+            .map_err(|_| Response::error(StatusCode::BAD_REQUEST, format!("{} has to be milliseconds since the epoch", key)));
+        let (until, since) = match (time("until"), time("since")) {
+            (Ok(until), Ok(since)) => {
+                let until = until.unwrap_or_else(domus_core::now);
+                (until, since.unwrap_or(until - DEFAULT_HISTORY))
+            },
+            (Err(error), _) | (_, Err(error)) => return error,
+        };
+        let metric: Metric = match params.get("metric").map(|metric| metric.parse()) {
+            Some(Ok(metric)) => metric,
+            Some(Err(error)) => return Response::error(StatusCode::BAD_REQUEST, error),
+            None if self.root.find_space(path).is_some() => Metric::Presence,
+            None => return Response::error(StatusCode::BAD_REQUEST, "Which metric? Devices need one, such as metric=occupancy"),
+        };
+        let known = match metric {
+            Metric::Presence => self.root.find_space(path).is_some(),
+            Metric::Capability(capability) => self.root.find_device(path).is_some_and(|device| device.has_capability(capability)),
+        };
+        if !known {
+            return Response::not_found(&metric.to_string(), path);
+        }
+        Response::ok(&HistoryResource {
+            path: path.to_string(),
+            metric: metric.to_string(),
+            since: millis(since),
+            until: millis(until),
+            average: store.time_average(path, metric, since, until),
+            points: store.history(path, metric, since).into_iter()
+                .filter(|point| point.time <= until)
+                .map(HistoryPointResource::from)
+                .collect(),
+        })
+    }
+}
+
+fn presence(path: &str, space: &dyn Space) -> PresenceResource {
+    let probability = space.presence().probability();
+    PresenceResource { path: path.to_string(), probability, occupied: probability >= OCCUPIED_THRESHOLD }
+}
+
+/// Listens for the API. Bound up front so a taken port fails the start, and kept across reloads.
+pub struct ApiServer {
+    listener: TcpListener,
+}
+
+impl ApiServer {
+    pub async fn bind(address: SocketAddr) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(address).await?;
+        log::info!("API listening on http://{}/api", listener.local_addr()?);
+        Ok(ApiServer { listener })
+    }
+
+    /// Serves the API until cancelled, abandoning the requests still running then.
+    pub async fn serve(&self, api: &Api<'_>, cancel: CancellationToken) {
+        let mut connections = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        let service = hyper::service::service_fn(move |request| respond(api, request));
+                        connections.push(hyper::server::conn::http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), service));
+                    },
+                    Err(error) => log::warn!("API: accepting a connection failed: {}", error),
+                },
+                Some(served) = connections.next(), if !connections.is_empty() => if let Err(error) = served {
+                    log::debug!("API: connection failed: {}", error);
+                },
+            }
+        }
+    }
+}
+
+async fn respond(api: &Api<'_>, request: Request<Incoming>) -> Result<hyper::Response<Full<Bytes>>, hyper::http::Error> {
+    let method = request.method().clone();
+    let target = request.uri().path_and_query().map_or("/", |target| target.as_str()).to_string();
+    let response = match Limited::new(request.into_body(), MAX_BODY).collect().await {
+        Ok(body) => api.handle(&method, &target, &body.to_bytes()).await,
+        Err(error) => Response::error(StatusCode::PAYLOAD_TOO_LARGE, error),
+    };
+    hyper::Response::builder()
+        .status(response.status)
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(response.body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use driver::{SimLight, SimMotionSensor};
+    use macros::domus;
+
+    #[tokio::test]
+    async fn test_api() {
+        let house = domus! {
+            name: "House",
+            hallway: Space {
+                name: "Hallway",
+                #[tags("presence")]
+                motion_sensor: SimMotionSensor { name: "Hallway motion sensor" },
+                light: SimLight { name: "Hallway light" }
+            }
+        };
+        let api = Api::new(&house);
+        let get = |target: &'static str| api.handle(&Method::GET, target, b"");
+        let json = |response: Response| serde_json::from_str::<serde_json::Value>(&response.body).unwrap();
+
+        let tree = json(get("/api/spaces").await);
+        assert_eq!(tree["spaces"][0]["devices"][1]["path"], "hallway/light");
+        assert_eq!(tree["spaces"][0]["devices"][0]["metadata"]["tags"][0], "presence");
+
+        let command = br#"{"capability": "brightness", "value": 40}"#;
+        let response = api.handle(&Method::POST, "/api/devices/hallway/light", command).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(json(response)["state"].as_array().unwrap().contains(&serde_json::json!({"capability": "brightness", "value": 40})));
+        let response = api.handle(&Method::POST, "/api/devices/hallway/motion_sensor", command).await;
+        assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        assert_eq!(json(get("/api/devices?capability=occupancy").await).as_array().unwrap().len(), 1);
+        assert_eq!(json(get("/api/presence/hallway").await)["occupied"], false);
+        // paths and parameters are percent-decoded
+        assert_eq!(json(get("/api/devices?tag=pres%65nce").await).as_array().unwrap().len(), 1);
+        assert_eq!(json(get("/api/devices/hall%77ay%2Flight").await)["path"], "hallway/light");
+        assert_eq!(get("/api/devices/hallway/lamp").await.status, StatusCode::NOT_FOUND);
+        assert_eq!(get("/api/history/hallway").await.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(json(get("/api/schema").await)["$defs"]["CapabilityValue"]["oneOf"].as_array().unwrap().len(), Capability::ALL.len());
+    }
+}
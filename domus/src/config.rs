@@ -0,0 +1,667 @@
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+use chrono::{NaiveTime, TimeDelta};
+use domus_core::{Capability, CapabilityValue, DynDevice, DynLifeCycle, LifeCycle, LifeCycleStage, LocalBoxFuture, Metadata, Scene, Space, SpacePresence, join_path};
+use driver::DriverRegistry;
+use serde::Deserialize;
+use chrono_tz::Tz;
+use crate::rules::{Action, Condition, Rule, Trigger};
+use crate::scheduler::{Location, MissedPolicy, Schedule, SunEvent, When};
+
+/// A configuration problem, at the path of the space or device it was found in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub path: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(path: &str, message: impl Into<String>) -> Self {
+        ConfigError { path: path.to_string(), message: message.into() }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceConfig {
+    pub driver: String,
+    pub metadata: Metadata,
+    /// The device record handed to the driver, the same as the one it saves when pairing.
+    pub record: toml::Table,
+}
+
+/// The house layout read from a file, the runtime counterpart of `domus!`.
+///
+/// ```toml
+/// name = "Apartment"
+///
+/// [office]
+/// name = "Office"
+/// tags = ["work"]
+///
+/// [office.motion_sensor]
+/// driver = "aqarafp2"
+/// tags = ["presence"]
+/// metadata = { zone = "desk" }
+/// # the rest is the driver's device record
+/// ```
+///
+/// Tables with a `driver` are devices, other tables are spaces. The top level `location`, `schedules`
+/// and `rules` hold the automation, see `AutomationConfig`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpaceConfig {
+    pub name: String,
+    pub metadata: Metadata,
+    pub spaces: Vec<(String, SpaceConfig)>,
+    pub devices: Vec<(String, DeviceConfig)>,
+}
+
+fn take_metadata(table: &mut toml::Table, path: &str) -> Result<Metadata, ConfigError> {
+    let mut metadata = Metadata::default();
+    if let Some(tags) = table.remove("tags") {
+        let tags = tags.as_array()
+            .ok_or_else(|| ConfigError::new(path, "tags must be a list of strings"))?;
+        for tag in tags {
+            let tag = tag.as_str().ok_or_else(|| ConfigError::new(path, "tags must be a list of strings"))?;
+            metadata.tags.push(tag.to_string());
+        }
+    }
+    if let Some(values) = table.remove("metadata") {
+        let values = values.as_table()
+            .ok_or_else(|| ConfigError::new(path, "metadata must be a table of strings"))?;
+        for (key, value) in values {
+            let value = value.as_str()
+                .ok_or_else(|| ConfigError::new(path, format!("metadata {} must be a string", key)))?;
+            metadata.values.insert(key.clone(), value.to_string());
+        }
+    }
+    Ok(metadata)
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+impl DeviceConfig {
+    fn parse(mut table: toml::Table, path: &str, registry: &DriverRegistry) -> Result<Self, ConfigError> {
+        let metadata = take_metadata(&mut table, path)?;
+        let driver = table.get("driver").and_then(|driver| driver.as_str())
+            .ok_or_else(|| ConfigError::new(path, "driver must be a string"))?
+            .to_string();
+        let Some(dyn_driver) = registry.get(&driver) else {
+            return Err(ConfigError::new(path, format!("Unknown driver {}, known drivers are {}", driver, registry.names().join(", "))));
+        };
+        dyn_driver.load(&toml::to_string(&table).map_err(|e| ConfigError::new(path, e.to_string()))?)
+            .map_err(|e| ConfigError::new(path, format!("Invalid {} device: {}", driver, e)))?;
+        Ok(DeviceConfig { driver, metadata, record: table })
+    }
+
+    async fn attach(&self, registry: &DriverRegistry) -> Result<Rc<dyn DynDevice>, Box<dyn Error>> {
+        let driver = registry.get(&self.driver).ok_or_else(|| format!("Unknown driver {}", self.driver))?;
+        let properties = driver.load(&toml::to_string(&self.record)?)?;
+        Ok(Rc::from(driver.attach(properties.as_ref()).await?))
+    }
+
+    /// A simulated device in place of the configured one, doing what the driver's devices do.
+    fn stand_in(&self, registry: &DriverRegistry) -> Result<Rc<dyn DynDevice>, Box<dyn Error>> {
+        let driver = registry.get(&self.driver).ok_or_else(|| format!("Unknown driver {}", self.driver))?;
+        let name = self.record.get("name").and_then(|name| name.as_str()).unwrap_or(&self.driver);
+        driver::sim::stand_in(name, driver.info().capabilities).map(Rc::from)
+            .ok_or_else(|| format!("No simulated device does what a {} device does", self.driver).into())
+    }
+}
+
+impl SpaceConfig {
+    fn parse(mut table: toml::Table, path: &str, registry: &DriverRegistry) -> Result<Self, ConfigError> {
+        let name = match table.remove("name") {
+            Some(toml::Value::String(name)) if !name.is_empty() => name,
+            Some(_) => return Err(ConfigError::new(path, "name must be a non-empty string")),
+            None => return Err(ConfigError::new(path, "Missing name")),
+        };
+        let metadata = take_metadata(&mut table, path)?;
+
+        let mut config = SpaceConfig { name, metadata, spaces: Vec::new(), devices: Vec::new() };
+        for (key, value) in table {
+            if path.is_empty() && AUTOMATION_KEYS.contains(&key.as_str()) {
+                continue;
+            }
+            let child = join_path(path, &key);
+            if !is_valid_key(&key) {
+                return Err(ConfigError::new(&child, "Names may only hold lowercase letters, digits and underscores"));
+            }
+            let toml::Value::Table(table) = value else {
+                return Err(ConfigError::new(&child, "Expected a space or device table"));
+            };
+            if table.contains_key("driver") {
+                config.devices.push((key, DeviceConfig::parse(table, &child, registry)?));
+            } else {
+                config.spaces.push((key, SpaceConfig::parse(table, &child, registry)?));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Reads and checks the configuration, every device against its driver.
+    pub fn from_toml(text: &str, registry: &DriverRegistry) -> Result<Self, ConfigError> {
+        let table = text.parse::<toml::Table>().map_err(|e| ConfigError::new("", e.to_string()))?;
+        SpaceConfig::parse(table, "", registry)
+    }
+
+    pub fn load(path: impl AsRef<Path>, registry: &DriverRegistry) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read configuration {}: {}", path.display(), e))?;
+        Self::from_toml(&text, registry)
+            .map_err(|e| format!("Invalid configuration {}: {}", path.display(), e).into())
+    }
+
+    /// Every space in the tree by its path, the root as the empty path.
+    pub fn space_paths(&self) -> Vec<String> {
+        let mut spaces = vec![String::new()];
+        for (key, space) in &self.spaces {
+            spaces.extend(space.space_paths().into_iter().map(|path| match path.is_empty() {
+                true => key.clone(),
+                false => join_path(key, &path),
+            }));
+        }
+        spaces
+    }
+
+    /// Every device in the tree by its path.
+    pub fn device_configs(&self) -> Vec<(String, &DeviceConfig)> {
+        let mut devices: Vec<_> = self.devices.iter().map(|(key, device)| (key.clone(), device)).collect();
+        for (key, space) in &self.spaces {
+            devices.extend(space.device_configs().into_iter().map(|(path, device)| (join_path(key, &path), device)));
+        }
+        devices
+    }
+
+    /// Paths of the devices that are gone or configured differently in `next`.
+    pub fn changed_devices(&self, next: &SpaceConfig) -> Vec<String> {
+        let next = next.device_configs();
+        self.device_configs().into_iter()
+            .filter(|(path, device)| !next.iter().any(|(p, d)| p == path && d == device))
+            .map(|(path, _)| path)
+            .collect()
+    }
+}
+
+/// Top level keys that belong to the automation rather than the tree.
+const AUTOMATION_KEYS: [&str; 3] = ["location", "schedules", "rules"];
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TriggerConfig {
+    Changed { device: String, capability: Option<Capability> },
+    Occupied(String),
+    Vacant(String),
+    /// Seconds.
+    Every(u64),
+    /// Local time of day, `HH:MM`.
+    At(String),
+    LifeCycle(LifeCycleStage),
+    Scheduled(String),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ConditionConfig {
+    State { device: String, value: CapabilityValue },
+    Occupied(String),
+    Vacant(String),
+    Between { from: String, to: String },
+    Not(Box<ConditionConfig>),
+    Any(Vec<ConditionConfig>),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ActionConfig {
+    Command { device: String, value: CapabilityValue },
+    /// Seconds.
+    Delay(u64),
+    /// A scene file, read along with the configuration, and the seconds to fade over.
+    Scene { file: String, transition: Option<u64> },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    name: String,
+    when: Vec<TriggerConfig>,
+    #[serde(default)]
+    only_if: Vec<ConditionConfig>,
+    then: Vec<ActionConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LocationConfig {
+    latitude: f64,
+    longitude: f64,
+    /// IANA name such as `Europe/Amsterdam`.
+    timezone: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScheduleConfig {
+    name: String,
+    cron: Option<String>,
+    sun: Option<SunEvent>,
+    /// Minutes after the sun event, negative for before.
+    #[serde(default)]
+    offset: i64,
+    #[serde(default)]
+    missed: MissedPolicy,
+}
+
+#[derive(Deserialize, Default)]
+struct AutomationTable {
+    location: Option<LocationConfig>,
+    #[serde(default)]
+    schedules: Vec<ScheduleConfig>,
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+}
+
+/// The paths and schedules rules may refer to, to check them against.
+struct TreePaths {
+    spaces: Vec<String>,
+    devices: Vec<String>,
+    schedules: Vec<String>,
+}
+
+impl TreePaths {
+    fn space(&self, path: &str, space: String) -> Result<String, ConfigError> {
+        match self.spaces.contains(&space) {
+            true => Ok(space),
+            false => Err(ConfigError::new(path, format!("No space at {}", space))),
+        }
+    }
+
+    fn device(&self, path: &str, device: String) -> Result<String, ConfigError> {
+        match self.devices.contains(&device) {
+            true => Ok(device),
+            false => Err(ConfigError::new(path, format!("No device at {}", device))),
+        }
+    }
+}
+
+fn parse_time(path: &str, time: &str) -> Result<NaiveTime, ConfigError> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|_| ConfigError::new(path, format!("Expected a time of day such as 07:30, got {:?}", time)))
+}
+
+impl TriggerConfig {
+    fn build(self, path: &str, tree: &TreePaths) -> Result<Trigger, ConfigError> {
+        Ok(match self {
+            TriggerConfig::Changed { device, capability } => Trigger::Changed { device: tree.device(path, device)?, capability },
+            TriggerConfig::Occupied(space) => Trigger::Occupied { space: tree.space(path, space)? },
+            TriggerConfig::Vacant(space) => Trigger::Vacant { space: tree.space(path, space)? },
+            TriggerConfig::Every(0) => return Err(ConfigError::new(path, "every needs a period of at least a second")),
+            TriggerConfig::Every(seconds) => Trigger::Every(Duration::from_secs(seconds)),
+            TriggerConfig::At(time) => Trigger::At(parse_time(path, &time)?),
+            TriggerConfig::LifeCycle(stage) => Trigger::LifeCycle(stage),
+            TriggerConfig::Scheduled(name) if !tree.schedules.contains(&name) => return Err(ConfigError::new(path, format!("No schedule {}", name))),
+            TriggerConfig::Scheduled(name) => Trigger::scheduled(&name),
+        })
+    }
+}
+
+impl ConditionConfig {
+    fn build(self, path: &str, tree: &TreePaths) -> Result<Condition, ConfigError> {
+        Ok(match self {
+            ConditionConfig::State { device, value } => Condition::State { device: tree.device(path, device)?, value },
+            ConditionConfig::Occupied(space) => Condition::Occupied { space: tree.space(path, space)? },
+            ConditionConfig::Vacant(space) => Condition::Vacant { space: tree.space(path, space)? },
+            ConditionConfig::Between { from, to } => Condition::between(parse_time(path, &from)?, parse_time(path, &to)?),
+            ConditionConfig::Not(condition) => Condition::Not(Box::new(condition.build(path, tree)?)),
+            ConditionConfig::Any(conditions) => Condition::Any(conditions.into_iter()
+                .map(|condition| condition.build(path, tree))
+                .collect::<Result<_, _>>()?),
+        })
+    }
+}
+
+impl ActionConfig {
+    fn build(self, path: &str, tree: &TreePaths) -> Result<Action, ConfigError> {
+        Ok(match self {
+            ActionConfig::Command { device, value } => Action::Command { device: tree.device(path, device)?, value },
+            ActionConfig::Delay(seconds) => Action::Delay(Duration::from_secs(seconds)),
+            ActionConfig::Scene { file, transition } => Action::Scene {
+                scene: Scene::load(&file).map_err(|e| ConfigError::new(path, e.to_string()))?,
+                transition: transition.map(Duration::from_secs),
+            },
+        })
+    }
+}
+
+/// The automation of a configuration file: rules written next to the tree, with the same
+/// triggers, conditions and actions as the ones declared in code, and the schedules they can trigger on.
+///
+/// ```toml
+/// [location]
+/// latitude = 52.37
+/// longitude = 4.90
+/// timezone = "Europe/Amsterdam"
+///
+/// [[schedules]]
+/// name = "Before sunset"
+/// sun = "sunset"
+/// offset = -30
+/// missed = "run_once"
+///
+/// [[rules]]
+/// name = "Evening"
+/// when = [{ occupied = "living_room" }]
+/// only_if = [{ between = { from = "18:00", to = "23:00" } }]
+/// then = [{ command = { device = "living_room/lamp", value = { capability = "on_off", value = true } } }]
+/// ```
+///
+/// Schedules take a `cron` expression or a `sun` event instead, and need the location.
+/// Every path a rule refers to has to be in the tree.
+#[derive(Default)]
+pub struct AutomationConfig {
+    pub location: Option<Location>,
+    pub schedules: Vec<Schedule>,
+    pub rules: Vec<Rule>,
+}
+
+impl AutomationConfig {
+    pub fn from_toml(text: &str, tree: &SpaceConfig) -> Result<Self, ConfigError> {
+        let mut table = text.parse::<toml::Table>().map_err(|e| ConfigError::new("", e.to_string()))?;
+        table.retain(|key, _| AUTOMATION_KEYS.contains(&key));
+        let automation: AutomationTable = table.try_into().map_err(|e: toml::de::Error| ConfigError::new("rules", e.message()))?;
+
+        let location = automation.location.map(|location| Ok::<_, ConfigError>(Location {
+            latitude: location.latitude,
+            longitude: location.longitude,
+            timezone: location.timezone.parse::<Tz>().map_err(|_| ConfigError::new("location", format!("Unknown timezone {}", location.timezone)))?,
+        })).transpose()?;
+        if location.is_none() && !automation.schedules.is_empty() {
+            return Err(ConfigError::new("schedules", "Schedules need a location"));
+        }
+        let mut schedules = Vec::new();
+        for schedule in automation.schedules {
+            let path = format!("schedules/{}", schedule.name);
+            let when = match (schedule.cron, schedule.sun) {
+                (Some(cron), None) => When::cron(&cron).map_err(|e| ConfigError::new(&path, e))?,
+                (None, Some(event)) => When::sun(event, TimeDelta::minutes(schedule.offset)),
+                _ => return Err(ConfigError::new(&path, "Expected either cron or sun")),
+            };
+            schedules.push(Schedule::new(&schedule.name, when).missed(schedule.missed));
+        }
+
+        let paths = TreePaths {
+            spaces: tree.space_paths(),
+            devices: tree.device_configs().into_iter().map(|(path, _)| path).collect(),
+            schedules: schedules.iter().map(|schedule| schedule.name.clone()).collect(),
+        };
+        let mut rules = Vec::new();
+        for rule in automation.rules {
+            let path = format!("rules/{}", rule.name);
+            let mut built = Rule::new(&rule.name);
+            for trigger in rule.when {
+                built = built.when(trigger.build(&path, &paths)?);
+            }
+            for condition in rule.only_if {
+                built = built.only_if(condition.build(&path, &paths)?);
+            }
+            for action in rule.then {
+                built = built.then(action.build(&path, &paths)?);
+            }
+            rules.push(built);
+        }
+        Ok(AutomationConfig { location, schedules, rules })
+    }
+
+    pub fn load(path: impl AsRef<Path>, tree: &SpaceConfig) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read configuration {}: {}", path.display(), e))?;
+        Self::from_toml(&text, tree)
+            .map_err(|e| format!("Invalid configuration {}: {}", path.display(), e).into())
+    }
+}
+
+/// A space built from configuration at runtime.
+#[derive(Default)]
+pub struct ConfigSpace {
+    name: String,
+    presence: Rc<SpacePresence>,
+    metadata: Metadata,
+    device_metadata: Vec<(String, Metadata)>,
+    spaces: Vec<(String, ConfigSpace)>,
+    devices: Vec<(String, Rc<dyn DynDevice>)>,
+}
+
+impl ConfigSpace {
+    /// Builds the tree, attaching every device through its driver.
+    ///
+    /// Devices configured the same in `previous` are shared with the tree built from it rather than attached again,
+    /// and spaces still at the same path keep their presence.
+    pub async fn build(config: &SpaceConfig, registry: &DriverRegistry, previous: Option<(&SpaceConfig, &ConfigSpace)>) -> Result<Self, Box<dyn Error>> {
+        Self::build_at(config, "", registry, previous, false).await
+    }
+
+    /// Builds the same tree with every device stood in for by a simulated one, e.g. to replay a recording on.
+    pub async fn build_simulated(config: &SpaceConfig, registry: &DriverRegistry) -> Result<Self, Box<dyn Error>> {
+        Self::build_at(config, "", registry, None, true).await
+    }
+
+    fn build_at<'a>(config: &'a SpaceConfig, path: &'a str, registry: &'a DriverRegistry, previous: Option<(&'a SpaceConfig, &'a ConfigSpace)>, simulated: bool) -> LocalBoxFuture<'a, Result<Self, Box<dyn Error>>> {
+        Box::pin(async move {
+            let mut space = ConfigSpace {
+                name: config.name.clone(),
+                presence: previous.map(|(_, previous_space)| previous_space.presence.clone()).unwrap_or_default(),
+                metadata: config.metadata.clone(),
+                ..Default::default()
+            };
+            for (key, device_config) in &config.devices {
+                let unchanged = previous.and_then(|(previous_config, previous_space)| {
+                    previous_config.devices.iter().find(|(k, d)| k == key && d == device_config)?;
+                    previous_space.devices.iter().find(|(k, _)| k == key).map(|(_, device)| device.clone())
+                });
+                let device = match unchanged {
+                    Some(device) => device,
+                    None if simulated => device_config.stand_in(registry)
+                        .map_err(|e| ConfigError::new(&join_path(path, key), e.to_string()))?,
+                    None => device_config.attach(registry).await
+                        .map_err(|e| ConfigError::new(&join_path(path, key), format!("Failed to attach: {}", e)))?,
+                };
+                space.device_metadata.push((key.clone(), device_config.metadata.clone()));
+                space.devices.push((key.clone(), device));
+            }
+            for (key, space_config) in &config.spaces {
+                let previous = previous.and_then(|(previous_config, previous_space)| Some((
+                    previous_config.spaces.iter().find(|(k, _)| k == key).map(|(_, config)| config)?,
+                    previous_space.spaces.iter().find(|(k, _)| k == key).map(|(_, space)| space)?,
+                )));
+                let sub_space = Self::build_at(space_config, &join_path(path, key), registry, previous, simulated).await?;
+                space.spaces.push((key.clone(), sub_space));
+            }
+            Ok(space)
+        })
+    }
+}
+
+impl Space for ConfigSpace {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn presence(&self) -> &SpacePresence {
+        &self.presence
+    }
+
+    fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    fn device_metadata(&self, key: &str) -> Option<&Metadata> {
+        self.device_metadata.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, metadata)| metadata)
+    }
+
+    fn sub_spaces(&self) -> Vec<(&str, &dyn Space)> {
+        self.spaces.iter().map(|(key, space)| (key.as_str(), space as &dyn Space)).collect()
+    }
+
+    fn devices(&self) -> Vec<(&str, &dyn DynDevice)> {
+        self.devices.iter().map(|(key, device)| (key.as_str(), device.as_ref())).collect()
+    }
+}
+
+impl LifeCycle for ConfigSpace {
+    async fn init(&self) -> Result<(), Box<dyn Error>> {
+        log::info!("Initializing {}", self.name);
+        for (_, device) in &self.devices {
+            device.init().await?;
+        }
+        for (_, space) in &self.spaces {
+            DynLifeCycle::init(space).await?;
+        }
+        Ok(())
+    }
+
+    async fn dispose(&self) -> Result<(), Box<dyn Error>> {
+        log::info!("Disposing {}", self.name);
+        for (_, device) in &self.devices {
+            device.dispose().await?;
+        }
+        for (_, space) in &self.spaces {
+            DynLifeCycle::dispose(space).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AutomationConfig, ConfigSpace, DriverRegistry, Metadata, MissedPolicy, Rc, Space, SpaceConfig};
+    use driver::{AqaraFP2, SimMotionSensor};
+    use macros::domus;
+
+    const APARTMENT: &str = r#"
+        name = "Apartment"
+
+        [office]
+        name = "Office"
+        tags = ["work"]
+
+        [office.motion_sensor]
+        driver = "aqarafp2"
+        tags = ["presence"]
+        metadata = { zone = "desk" }
+        id = "AA:BB:CC:DD:EE:FF"
+        name = "Office motion sensor"
+        address = "192.168.22.51"
+        port = 56431
+        pairing = { accessory_id = "AA:BB:CC:DD:EE:FF", accessory_ltpk = "0707070707070707070707070707070707070707070707070707070707070707", controller_id = "Domus", controller_ltsk = "2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a2a" }
+    "#;
+
+    #[tokio::test]
+    async fn test_config_matches_macro() {
+        let registry = DriverRegistry::builtin();
+        let config = SpaceConfig::from_toml(APARTMENT, &registry).unwrap();
+        let configured = ConfigSpace::build(&config, &registry, None).await.unwrap();
+
+        let compiled = domus! {
+            name: "Apartment",
+            #[tags("work")]
+            office: Space {
+                name: "Office",
+                #[tags("presence")]
+                #[metadata(zone = "desk")]
+                motion_sensor: AqaraFP2 {
+                    name: "Office motion sensor"
+                }
+            }
+        };
+
+        let describe = |root: &dyn Space| -> Vec<(String, String, Metadata)> {
+            let spaces = root.walk().into_iter().map(|visit| (visit.path, visit.space.name().to_string(), visit.space.metadata().clone()));
+            let devices = root.walk_devices().into_iter().map(|visit| (visit.path, String::new(), visit.metadata.clone()));
+            spaces.chain(devices).collect()
+        };
+        assert_eq!(describe(&configured), describe(&compiled));
+
+        // an unchanged device is carried over, not attached again
+        let rebuilt = ConfigSpace::build(&config, &registry, Some((&config, &configured))).await.unwrap();
+        assert!(Rc::ptr_eq(&configured.spaces[0].1.devices[0].1, &rebuilt.spaces[0].1.devices[0].1));
+        // and so is the presence of a space that's still there
+        assert!(Rc::ptr_eq(&configured.spaces[0].1.presence, &rebuilt.spaces[0].1.presence));
+
+        // a replay gets the same tree, of simulated devices
+        let simulated = ConfigSpace::build_simulated(&config, &registry).await.unwrap();
+        assert_eq!(describe(&simulated), describe(&compiled));
+        assert!(simulated.spaces[0].1.devices[0].1.as_any().is::<SimMotionSensor>());
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let registry = DriverRegistry::builtin();
+        let error = |text: &str| SpaceConfig::from_toml(text, &registry).unwrap_err();
+
+        assert_eq!(error("[office]\nname = \"Office\"").to_string(), "Missing name");
+        assert_eq!(error("name = \"Home\"\n[office]\ntags = [\"work\"]").path, "office");
+        let unknown = error("name = \"Home\"\n[office]\nname = \"Office\"\n[office.lamp]\ndriver = \"hue\"");
+        assert_eq!(unknown.to_string(), "office/lamp: Unknown driver hue, known drivers are aqarafp2");
+        assert!(error(&APARTMENT.replace("port = 56431", "")).to_string().starts_with("office/motion_sensor: Invalid aqarafp2 device"));
+
+        let config = SpaceConfig::from_toml(APARTMENT, &registry).unwrap();
+        let moved = SpaceConfig::from_toml(&APARTMENT.replace("192.168.22.51", "192.168.22.52"), &registry).unwrap();
+        assert_eq!(config.changed_devices(&moved), vec!["office/motion_sensor"]);
+        assert!(config.changed_devices(&config).is_empty());
+    }
+
+    #[test]
+    fn test_automation_config() {
+        let registry = DriverRegistry::builtin();
+        let rules = format!("{}{}", APARTMENT, r#"
+            [location]
+            latitude = 52.37
+            longitude = 4.90
+            timezone = "Europe/Amsterdam"
+
+            [[schedules]]
+            name = "Dusk"
+            sun = "civil_dusk"
+            missed = "run_once"
+
+            [[rules]]
+            name = "Working late"
+            when = [{ occupied = "office" }, { at = "19:00" }, { scheduled = "Dusk" }]
+            only_if = [{ not = { between = { from = "07:00", to = "18:00" } } }]
+            then = [{ delay = 5 }, { command = { device = "office/motion_sensor", value = { capability = "occupancy", value = true } } }]
+        "#);
+        let config = SpaceConfig::from_toml(&rules, &registry).unwrap();
+        assert_eq!(config, SpaceConfig::from_toml(APARTMENT, &registry).unwrap());
+        let automation = AutomationConfig::from_toml(&rules, &config).unwrap();
+        assert_eq!(automation.rules.iter().map(|rule| rule.name.as_str()).collect::<Vec<_>>(), vec!["Working late"]);
+        assert_eq!(automation.schedules[0].missed, MissedPolicy::RunOnce);
+        assert!(automation.location.is_some());
+
+        let error = |from: &str, to: &str| AutomationConfig::from_toml(&rules.replace(from, to), &config).err().unwrap().to_string();
+        assert_eq!(error("office/motion_sensor", "office/lamp"), "rules/Working late: No device at office/lamp");
+        assert_eq!(error("19:00", "7pm"), "rules/Working late: Expected a time of day such as 07:30, got \"7pm\"");
+        assert_eq!(error("scheduled = \"Dusk\"", "scheduled = \"Dawn\""), "rules/Working late: No schedule Dawn");
+        assert_eq!(error("Europe/Amsterdam", "Europe/Atlantis"), "location: Unknown timezone Europe/Atlantis");
+    }
+}
@@ -1,141 +1,47 @@
-
-
-macro_rules! define_space_field_type {
-    (
-        $field_name:ident,
-        Space 
-    ) => {
-        paste! {
-            [<$field_name:camel>]
-        }
-    };    
-    (
-        $field_name:ident,
-        $field_type:ty 
-    ) => {
-        $field_type
-    };
+/// Path of a space or device in the tree, e.g. `path!(apartment.office.motion_sensor)` is `"office/motion_sensor"`.
+/// Doesn't compile if the field doesn't exist.
+macro_rules! path {
+    ($root:ident $(. $field:ident)+) => {{
+        let _ = &$root $(.$field)+;
+        &concat!($("/", stringify!($field)),+)[1..]
+    }};
 }
 
-
-macro_rules! define_space {
-    (
-        $name:ident, 
-        Space { 
-            name: $display_name:expr
-            $(, $field_name:ident: $field_type:ident { $($props:tt)+ })*
-            $(,)?
-        }
-    ) => {
-        paste! {
-
-            #[derive(Debug)]
-            #[allow(unused)]
-            struct [<$name:camel>] {
-                name: &'static str,
-                $(pub $field_name: define_space_field_type!($field_name, $field_type),)*
-            }
-
-            impl Space for [<$name:camel>] {
-                fn name(&self) -> &str {
-                    &self.name
+#[cfg(test)]
+mod tests {
+    use domus_core::Space;
+    use driver::AqaraFP2;
+    use macros::domus;
+
+    #[test]
+    fn test_handles_and_groups() {
+        let apartment = domus! {
+            name: "Apartment",
+            office: Space {
+                name: "Office",
+                desk: Space {
+                    name: "Desk",
+                    motion_sensor: AqaraFP2 { name: "Desk motion sensor" }
+                },
+                motion_sensors: Group {
+                    name: "Office motion sensors",
+                    door: AqaraFP2 { name: "Door motion sensor" },
+                    window: AqaraFP2 { name: "Window motion sensor" }
                 }
             }
-
-            impl LifeCycle for [<$name:camel>] {
-                async fn init(&self) -> Result<(), Box<dyn std::error::Error>> {
-                    log::info!("Initializing {}", self.name);
-                    $(let _ = &self.$field_name.init().await?;)*
-                    Ok(())
-                }
-
-                async fn dispose(&self) -> Result<(), Box<dyn std::error::Error>> {
-                    log::info!("Disposing {}", self.name);
-                    $(let _ = &self.$field_name.dispose().await?;)*
-                    Ok(())
-                }
-            }
-        }   
-        $(define_space!($field_name, $field_type { $($props)* });)*
-        
-    };
-    ($name:ident, $device_type:ident $($rest:tt)*
-    ) => {
-        // Device definition doesn't generate any typess
-    };
-}
-
-macro_rules! init_space_field_value {
-    (
-        $name:ident:
-        Space { 
-            name: $display_name:expr
-            $(, $field_name:ident: $field_type:ident { $($subspace:tt)* })*
-            $(,)?
-        }
-    ) => {
-        paste! {
-            [<$name:camel>] {
-                name: $display_name,
-                $($field_name: init_space_field_value!($field_name: $field_type { $($subspace)* }),)*
-            }
-        }
-    };    
-    (
-        $name:ident:
-        $device_type:ident { $($props:tt)* }
-    ) => {
-        $device_type {
-            $($props)*
-        }
-    };
+        };
+        let handle = apartment.handle().office.desk.motion_sensor;
+        assert_eq!(handle.path(), path!(apartment.office.desk.motion_sensor));
+        assert_eq!(apartment.handle().office.desk.path(), "office/desk");
+
+        let root: &dyn Space = &apartment;
+        assert_eq!(handle.resolve(root).map(|sensor| sensor.name.as_str()), Some("Desk motion sensor"));
+        assert_eq!(apartment.handle().office.resolve(root).map(|office| office.name()), Some("Office"));
+        assert!(apartment.handle().resolve(root).is_some());
+
+        let sensors = apartment.handle().office.motion_sensors.resolve(root).unwrap();
+        assert_eq!(sensors.members().len(), 2);
+        assert!(root.find_device("office/motion_sensors/window").is_some());
+        assert!(std::ptr::eq(root.group_of("office/motion_sensors/window").unwrap(), sensors));
+    }
 }
-
-macro_rules! domus {
-    (
-        name: $name:expr
-        $(, $field_name:ident: $field_type:ident { $($subspace:tt)+ })*
-        $(,)?
-    ) => {
-        paste! {
-            {
-                #[derive(Debug)]
-                #[allow(unused)]
-                struct Domus {
-                    name: String,
-                    $($field_name: define_space_field_type!($field_name, $field_type),)*
-                }
-
-                impl Space for Domus {
-                    fn name(&self) -> &str {
-                        &self.name
-                    }
-
-                }
-
-                impl LifeCycle for Domus {
-                    async fn init(&self) -> Result<(), Box<dyn std::error::Error>> {
-                        log::info!("Initializing {}", self.name);
-                        $(let _ = &self.$field_name.init().await?;)*
-                        Ok(())
-                    }
-
-                    async fn dispose(&self) -> Result<(), Box<dyn std::error::Error>> {
-                        log::info!("Disposing {}", self.name);
-                        $(let _ = &self.$field_name.dispose().await?;)*
-                        Ok(())
-                    }
-                }
-
-                $(
-                    define_space!($field_name, $field_type { $($subspace)* });
-                )*
-
-                Domus {
-                    name: $name.to_string(),
-                    $($field_name: init_space_field_value!($field_name: $field_type { $($subspace)* }),)*
-                }
-            }
-        }
-    };
-}
\ No newline at end of file
@@ -1,199 +1,567 @@
 #[macro_use]
 mod domus_macro;
-use paste::paste;
+mod rules;
+mod scheduler;
+mod shutdown;
+mod config;
+mod api;
+mod recorder;
+mod simulation;
+mod store;
 
-use core::LifeCycle;
-use core::Space;
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command};
+use domus_core::Space;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+use domus_core::{Capability, CapabilityValue, ContactState, EventBus, EventFilter, EventKind, LifeCycleStage, OccupancyLighting, SpaceStateMachine, Supervisor, SupervisorConfig};
+use api::{Api, ApiServer};
+use config::{AutomationConfig, ConfigSpace, SpaceConfig};
+use recorder::Recorder;
+use rules::{Action, Condition, Rule, RuleEngine, Trigger};
+use scheduler::Scheduler;
+use shutdown::Signals;
+use store::{StateStore, StoreConfig};
+use futures_util::future::join_all;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
 
 
+use driver::{AqaraFP2, DriverRegistry, Scenario, SimContactSensor, SimCurtains, SimLight, SimMotionSensor, SimThermometer};
+use macros::domus;
 
 
-/*
-pub struct SubSpaceIterator<'a, S> {
-    owner: &'a S,
-    //space: &'a dyn Space,
-    index: usize,
+/// How long disposing the devices may take before the process exits anyway.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+/// Where the scheduler remembers its last runs, in the `--state` directory.
+const SCHEDULER_STATE: &str = "schedules.toml";
+
+/// What runs next to the tree, as asked for on the command line.
+#[derive(Clone, Copy, Default)]
+struct Services<'a> {
+    store: Option<&'a StateStore>,
+    api: Option<&'a ApiServer>,
+    scenes: &'a [domus_core::Scene],
+    scheduler: Option<&'a Scheduler>,
 }
- */
 
-use driver::AqaraFP2;
+/// Binds the tree to the bus and brings its devices up. Devices that fail to start are retried
+/// in the background instead of keeping the rest down.
+///
+/// After a reload only the `rebuilt` devices are restored and bound, the others are still running as they were.
+async fn start(root: &dyn Space, events: &EventBus, supervisor: &Supervisor, services: Services<'_>, rebuilt: Option<&[String]>) {
+    let fresh = |path: &str| rebuilt.is_none_or(|paths| paths.iter().any(|p| p == path));
+    let publisher = events.publisher("");
+    publisher.publish(EventKind::LifeCycle { stage: LifeCycleStage::Initializing });
+    // before binding, so the remembered values aren't announced as news
+    if let Some(store) = services.store {
+        log::info!("Restored {} remembered values", store.restore_where(root, fresh));
+    }
+    root.bind_events_where(events, fresh);
+    supervisor.start(root).await;
+    publisher.publish(EventKind::LifeCycle { stage: LifeCycleStage::Initialized });
+}
 
+/// What every tree runs, compiled in or configured, until cancelled.
+async fn serve(root: &dyn Space, events: &EventBus, supervisor: &Supervisor, services: Services<'_>, cancel: CancellationToken) {
+    tokio::join!(
+        supervisor.supervise(root, cancel.clone()),
+        root.monitor_health(events, Duration::from_secs(30), cancel.clone()),
+        root.track_presence(events, Duration::from_secs(5), cancel.clone()),
+        root.track_groups(events, cancel.clone()),
+        async {
+            if let Some(store) = services.store {
+                store.track(events, cancel.clone()).await;
+            }
+        },
+        async {
+            if let Some(scheduler) = services.scheduler {
+                scheduler.run(events, cancel.clone()).await;
+            }
+        },
+        async {
+            if let Some(server) = services.api {
+                let api = Api::new(root).store(services.store).scenes(services.scenes);
+                server.serve(&api, cancel.clone()).await;
+            }
+        },
+    );
+}
 
-// TODO
-// presence
-// spaces should have presence, it should be a probability
-// probably the propagation upwards should just be max()
+async fn stop(root: &dyn Space, events: &EventBus, supervisor: &Supervisor) {
+    log::info!("Shutting down...");
+    let publisher = events.publisher("");
+    publisher.publish(EventKind::LifeCycle { stage: LifeCycleStage::Disposing });
+    if shutdown::within(SHUTDOWN_TIMEOUT, "Disposing devices", supervisor.stop(root)).await.is_none() {
+        std::process::exit(1);
+    }
+    publisher.publish(EventKind::LifeCycle { stage: LifeCycleStage::Disposed });
+}
 
+/// The scheduler for the configured schedules, if there is a location to run them at.
+fn configured_scheduler(automation: &AutomationConfig, store: Option<&StateStore>) -> Option<Scheduler> {
+    let scheduler = automation.schedules.iter().cloned().fold(Scheduler::new(automation.location?), Scheduler::schedule);
+    Some(match store {
+        Some(store) => scheduler.state_path(store.dir().join(SCHEDULER_STATE)),
+        None => scheduler,
+    })
+}
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
+/// Reads the tree and its automation from the configuration file.
+fn load_config(path: &str, registry: &DriverRegistry) -> Result<(SpaceConfig, AutomationConfig), Box<dyn Error>> {
+    let config = SpaceConfig::load(path, registry)?;
+    let automation = AutomationConfig::load(path, &config)?;
+    Ok((config, automation))
+}
 
-    let apartment = domus! {
-        name: "Apartment",
-        
-        office: Space {
-            name: "Office",
-            motion_sensor: AqaraFP2 {
-                name: "Offic motion sensor",
-                ip: "192.168.22.51"
-            }
+/// Waits for a reload that yields a working configuration, `None` once shutting down.
+/// A broken configuration is reported and the running one kept.
+async fn next_config(path: &str, registry: &DriverRegistry, current: (&SpaceConfig, &ConfigSpace), reloads: &mut watch::Receiver<u64>, cancel: &CancellationToken) -> Option<(SpaceConfig, AutomationConfig, ConfigSpace)> {
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => return None,
+            changed = reloads.changed() => changed.ok()?,
+        }
+        let built = match load_config(path, registry) {
+            Ok((config, automation)) => ConfigSpace::build(&config, registry, Some(current)).await.map(|space| (config, automation, space)),
+            Err(error) => Err(error),
+        };
+        match built {
+            Ok(next) => return Some(next),
+            Err(error) => log::error!("Keeping the running configuration: {}", error),
         }
-    };
-
-    if let Err(error) = apartment.init().await {
-        log::error!("Error initializing: {:?}", error);
-    } else {
-        // start up the state-machines
     }
+}
 
-    log::info!("Shutting down...");
-    if let Err(error) = apartment.dispose().await {
-        log::error!("Error disposing: {:?}", error);
-        std::process::exit(1);
+/// Runs the tree described by the configuration file at `path`, rebuilding it on every reload.
+/// Devices configured the same as before keep running through a reload.
+async fn run_configured(path: &str, events: &EventBus, supervisor: &Supervisor, services: Services<'_>, signals: &Signals) -> Result<(), Box<dyn Error>> {
+    let registry = DriverRegistry::builtin();
+    let (mut config, mut automation) = load_config(path, &registry)?;
+    let mut home = ConfigSpace::build(&config, &registry, None).await?;
+    let mut rule_events = events.subscribe(EventFilter::all());
+    start(&home, events, supervisor, services, None).await;
+
+    let cancel = signals.shutdown();
+    let mut reloads = signals.reloads();
+    loop {
+        let generation = cancel.child_token();
+        let rules = automation.rules.iter().cloned().fold(RuleEngine::new().announce_on(events), RuleEngine::rule);
+        let scheduler = configured_scheduler(&automation, services.store);
+        let (_, _, next) = tokio::join!(
+            serve(&home, events, supervisor, Services { scheduler: scheduler.as_ref(), ..services }, generation.clone()),
+            rules.run(&home, rule_events, generation.clone()),
+            async {
+                let next = next_config(path, &registry, (&config, &home), &mut reloads, &cancel).await;
+                generation.cancel();
+                next
+            },
+        );
+        let Some((next_config, next_automation, next_home)) = next else { break };
+
+        let changed = config.changed_devices(&next_config);
+        let rebuilt = next_config.changed_devices(&config);
+        log::info!("Configuration reloaded, {} devices changed", changed.len());
+        supervisor.stop_devices(&home, &changed).await;
+        (config, automation, home) = (next_config, next_automation, next_home);
+        rule_events = events.subscribe(EventFilter::all());
+        start(&home, events, supervisor, services, Some(&rebuilt)).await;
     }
 
+    stop(&home, events, supervisor).await;
+    Ok(())
+}
 
-/*
+/// What happens in the simulated apartment.
+enum Playback {
+    /// Someone coming home, over and over.
+    Scenario,
+    /// A recorded event stream, once, reporting the actions it sets off.
+    Recording(Vec<domus_core::Event>),
+}
+
+/// Runs a virtual apartment of simulated devices, `speed` times faster than real time.
+/// Expects the paused runtime of `simulation::runtime`.
+async fn run_simulated(playback: Playback, speed: f64, events: &EventBus, supervisor: &Supervisor, services: Services<'_>, signals: &Signals) {
+    let pacer = tokio::spawn(simulation::pace(speed));
     let apartment = domus! {
         name: "Apartment",
-        
+
         entrance: Space {
             name: "Entryway",
-            door_sensor: DummyDevice { 
-                device_type: "Aqara P2 Door Sensor",
-                name: "Apartment door"
-            },
-            ceiling_light: DummyDevice {
-                device_type: "Ceiling Light",
-                name: "Entryway light"
-            },
+            door_sensor: SimContactSensor { name: "Apartment door" },
+            ceiling_light: SimLight { name: "Entryway light" },
+            motion_sensor: SimMotionSensor { name: "Entryway motion sensor" },
         },
-        
+
         main_living_area: Space {
             name: "Main Living Area",
             kitchen: Space {
                 name: "Kitchen",
-                ceiling_light: DummyDevice {
-                    device_type: "Ceiling Light",
-                    name: "Kitchen light"
-                },
-                motion_sensor: DummyDevice {
-                    device_type: "Motion Sensor",
-                    name: "Kitchen motion sensor"
-                },
-                curtains: DummyDevice {
-                    device_type: "Smart Curtains",
-                    name: "Kitchen curtains"
-                },
+                ceiling_light: SimLight { name: "Kitchen light" },
+                motion_sensor: SimMotionSensor { name: "Kitchen motion sensor" },
+                curtains: SimCurtains { name: "Kitchen curtains" },
             },
             dining_area: Space {
                 name: "Dining Area",
-                ceiling_light: DummyDevice {
-                    device_type: "Ceiling Light",
-                    name: "Dining area light"
-                },
-                motion_sensor: DummyDevice {
-                    device_type: "Motion Sensor",
-                    name: "Dining area motion sensor"
-                },
+                ceiling_light: SimLight { name: "Dining area light" },
+                motion_sensor: SimMotionSensor { name: "Dining area motion sensor" },
             },
             living_room: Space {
                 name: "Living Room",
-                ceiling_light: DummyDevice {
-                    device_type: "Ceiling Light",
-                    name: "Living room light"
-                },
-                motion_sensor: DummyDevice {
-                    device_type: "Motion Sensor",
-                    name: "Living room motion sensor"
-                },
-                curtains: DummyDevice {
-                    device_type: "Smart Curtains",
-                    name: "Living room curtains"
-                },
-                balcony_curtains: DummyDevice {
-                    device_type: "Smart Curtains",
-                    name: "Balcony door curtains"
+                ceiling_light: SimLight { name: "Living room light" },
+                motion_sensor: SimMotionSensor { name: "Living room motion sensor" },
+                thermometer: SimThermometer { name: "Living room thermometer" },
+                curtains: Group {
+                    name: "Living room curtains",
+                    window: SimCurtains { name: "Living room curtains" },
+                    balcony: SimCurtains { name: "Balcony door curtains" },
                 },
             },
         },
-        
+
         hallway: Space {
             name: "Hallway",
-            ceiling_light: DummyDevice {
-                device_type: "Ceiling Light",
-                name: "Hallway light"
-            },
-            motion_sensor: DummyDevice {
-                device_type: "Motion Sensor",
-                name: "Hallway motion sensor"
-            },
+            ceiling_light: SimLight { name: "Hallway light", dimmable: false },
+            motion_sensor: SimMotionSensor { name: "Hallway motion sensor" },
         },
-        
+
         bedroom: Space {
             name: "Bedroom",
-            ceiling_light: DummyDevice {
-                device_type: "Ceiling Light",
-                name: "Bedroom light"
-            },
-            motion_sensor: DummyDevice {
-                device_type: "Motion Sensor",
-                name: "Bedroom motion sensor"
-            },
+            ceiling_light: SimLight { name: "Bedroom light" },
+            motion_sensor: SimMotionSensor { name: "Bedroom motion sensor" },
+            thermometer: SimThermometer { name: "Bedroom thermometer" },
         },
-        
+
         wc: Space {
             name: "WC",
-            ceiling_light: DummyDevice {
-                device_type: "Ceiling Light",
-                name: "WC light"
-            },
-            motion_sensor: DummyDevice {
-                device_type: "Motion Sensor",
-                name: "WC motion sensor"
-            },
+            ceiling_light: SimLight { name: "WC light", dimmable: false },
+            motion_sensor: SimMotionSensor { name: "WC motion sensor" },
         },
-        
+
         bathroom: Space {
             name: "Bathroom",
-            ceiling_light: DummyDevice {
-                device_type: "Ceiling Light",
-                name: "Bathroom light"
-            },
-            motion_sensor: DummyDevice {
-                device_type: "Motion Sensor",
-                name: "Bathroom motion sensor"
-            },
+            ceiling_light: SimLight { name: "Bathroom light" },
+            motion_sensor: SimMotionSensor { name: "Bathroom motion sensor" },
         },
-        
+
         office: Space {
             name: "Office",
-            motion_sensor: AqaraFP2 { 
-                name: "Motion sensor"
-            },
-            ceiling_light: DummyDevice {
-                device_type: "Ceiling Light",
-                name: "Office light"
+            motion_sensor: SimMotionSensor { name: "Office motion sensor" },
+            ceiling_light: SimLight { name: "Office light" },
+            curtains: SimCurtains { name: "Office curtains" },
+        },
+    };
+
+    let door = path!(apartment.entrance.door_sensor);
+    let coming_home = Scenario::new("Coming home")
+        .report(Duration::from_secs(10), door, CapabilityValue::Contact(ContactState::Open))
+        .enter(Duration::from_secs(2), path!(apartment.entrance))
+        .report(Duration::from_secs(5), door, CapabilityValue::Contact(ContactState::Closed))
+        .walk(Duration::from_secs(30), &[
+            path!(apartment.hallway),
+            path!(apartment.main_living_area.kitchen),
+            path!(apartment.main_living_area.dining_area),
+            path!(apartment.main_living_area.living_room),
+        ], Duration::from_secs(120))
+        .leave(Duration::ZERO, path!(apartment.entrance))
+        .report(Duration::from_secs(60), path!(apartment.main_living_area.living_room.thermometer), CapabilityValue::Temperature(22.5))
+        .leave(Duration::from_secs(600), path!(apartment.main_living_area.living_room));
+
+    let house = apartment.handle();
+    let living_room = house.main_living_area.living_room;
+    let rules = RuleEngine::new()
+        .announce_on(events)
+        .rule(Rule::new("Entryway light while the door is open")
+            .when(Trigger::changed(house.entrance.door_sensor, Capability::Contact))
+            .only_if(Condition::occupied(house.entrance))
+            .only_if(Condition::state(house.entrance.door_sensor, CapabilityValue::Contact(ContactState::Open)))
+            .then(Action::command(house.entrance.ceiling_light, CapabilityValue::OnOff(true))))
+        .rule(Rule::new("Open the living room curtains")
+            .when(Trigger::occupied(living_room))
+            .then(Action::command(living_room.curtains, CapabilityValue::WindowCovering(100))))
+        .rule(Rule::new("Close the living room curtains")
+            .when(Trigger::vacant(living_room))
+            .only_if(Condition::vacant(house.main_living_area))
+            .then(Action::command(living_room.curtains, CapabilityValue::WindowCovering(0))));
+    let rule_events = events.subscribe(EventFilter::all());
+
+    let root: &dyn Space = &apartment;
+    let lighting: Vec<_> = root.walk().into_iter()
+        .filter(|visit| visit.space.devices().iter().any(|(_, device)| device.has_capability(Capability::OnOff)))
+        .map(|visit| SpaceStateMachine::new(&visit.path, OccupancyLighting::default()))
+        .collect();
+
+    start(&apartment, events, supervisor, services, None).await;
+
+    // a replay ends the run once it is through
+    let cancel = signals.shutdown().child_token();
+    tokio::join!(
+        serve(&apartment, events, supervisor, services, cancel.clone()),
+        rules.run(&apartment, rule_events, cancel.clone()),
+        join_all(lighting.iter().map(|machine| machine.run(&apartment, events, cancel.clone()))),
+        async {
+            match playback {
+                Playback::Scenario => loop {
+                    tokio::select! {
+                        _ = cancel.cancelled() => break,
+                        played = coming_home.run(&apartment) => if let Err(error) = played {
+                            log::error!("{}", error);
+                            break;
+                        },
+                    }
+                },
+                Playback::Recording(recording) => replay_once(&recording, &apartment, events, cancel.clone()).await,
+            }
+        },
+    );
+
+    stop(&apartment, events, supervisor).await;
+    pacer.abort();
+}
+
+/// Replays a recording on the tree and rules of the configuration file at `path`, `speed` times faster
+/// than it happened, with every device stood in for by a simulated one. Schedules aren't run, their
+/// recorded runs are replayed instead. Expects the paused runtime of `simulation::runtime`.
+async fn replay_configured(recording: &[domus_core::Event], path: &str, speed: f64, events: &EventBus, supervisor: &Supervisor, services: Services<'_>, signals: &Signals) -> Result<(), Box<dyn Error>> {
+    let registry = DriverRegistry::builtin();
+    let (config, automation) = load_config(path, &registry)?;
+    let home = ConfigSpace::build_simulated(&config, &registry).await?;
+    let rules = automation.rules.iter().cloned().fold(RuleEngine::new().announce_on(events), RuleEngine::rule);
+    let rule_events = events.subscribe(EventFilter::all());
+
+    let pacer = tokio::spawn(simulation::pace(speed));
+    start(&home, events, supervisor, services, None).await;
+    let cancel = signals.shutdown().child_token();
+    tokio::join!(
+        serve(&home, events, supervisor, services, cancel.clone()),
+        rules.run(&home, rule_events, cancel.clone()),
+        replay_once(recording, &home, events, cancel.clone()),
+    );
+    stop(&home, events, supervisor).await;
+    pacer.abort();
+    Ok(())
+}
+
+/// Replays the recording and prints the report, then ends the run.
+async fn replay_once(recording: &[domus_core::Event], root: &dyn Space, events: &EventBus, cancel: CancellationToken) {
+    tokio::select! {
+        _ = cancel.cancelled() => {},
+        report = recorder::replay(recording, root, events) => print!("{}", report),
+    }
+    cancel.cancel();
+}
+
+fn fail(message: impl std::fmt::Display) -> ! {
+    log::error!("{}", message);
+    std::process::exit(1);
+}
+
+/// How many times faster than real time a simulation runs, as given on the command line.
+fn parse_speed(speed: &str) -> Result<f64, String> {
+    match speed.parse::<f64>() {
+        Ok(speed) if speed > 0.0 => Ok(speed),
+        _ => Err("The speed has to be a positive number".to_string()),
+    }
+}
+
+fn command() -> Command {
+    Command::new("domus")
+        .about("Runs the automations of a house")
+        .arg(
+            Arg::new("config")
+                .value_name("CONFIG")
+                .help("Configuration file of the house, the one compiled in if omitted")
+                .conflicts_with("simulate"),
+        )
+        .arg(
+            Arg::new("simulate")
+                .long("simulate")
+                .help("Run the simulated apartment instead")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("replay")
+                .long("replay")
+                .value_name("RECORDING")
+                .help("Replay a recording on the configured house, or with --simulate on the simulated apartment, and report what it set off")
+                .requires("tree"),
+        )
+        .group(ArgGroup::new("tree").args(["config", "simulate"]))
+        .arg(
+            Arg::new("speed")
+                .long("speed")
+                .value_name("FACTOR")
+                .help("How many times faster than real time to simulate or replay")
+                .value_parser(parse_speed)
+                .default_value("1")
+                .requires("virtual"),
+        )
+        .group(ArgGroup::new("virtual").args(["simulate", "replay"]).multiple(true))
+        .arg(
+            Arg::new("record")
+                .long("record")
+                .value_name("FILE")
+                .help("Record every event to a file"),
+        )
+        .arg(
+            Arg::new("state")
+                .long("state")
+                .value_name("DIR")
+                .help("Directory to keep the last values, history and schedules in"),
+        )
+        .arg(
+            Arg::new("api")
+                .long("api")
+                .value_name("ADDRESS")
+                .help("Serve the HTTP API on this address, such as 127.0.0.1:8080")
+                .value_parser(clap::value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("api-public")
+                .long("api-public")
+                .help("Allow serving the API on an address other machines can reach")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("scene")
+                .long("scene")
+                .value_name("FILE")
+                .help("Load a scene for the API to apply, can be repeated")
+                .action(ArgAction::Append),
+        )
+}
+
+fn main() {
+    env_logger::init();
+    let matches = command().get_matches();
+    // a simulation runs on virtual time, everything else on the real clock
+    let simulated = matches.get_flag("simulate") || matches.contains_id("replay");
+    let runtime = if simulated { simulation::runtime() } else { tokio::runtime::Runtime::new() };
+    runtime.expect("Failed to start the runtime").block_on(run(matches));
+}
+
+async fn run(matches: ArgMatches) {
+    let signals = Signals::listen().expect("Failed to listen for signals");
+
+    let events = EventBus::default();
+    let mut subscription = events.subscribe(EventFilter::all());
+    tokio::spawn(async move {
+        while let Some(event) = subscription.recv().await {
+            log::debug!("{} {:?}", event.path, event.kind);
+        }
+    });
+    let supervisor = Supervisor::new(SupervisorConfig::default(), &events);
+
+    if let Some(path) = matches.get_one::<String>("record").cloned() {
+        match Recorder::open(&path, &events) {
+            Ok(recorder) => {
+                let cancel = signals.shutdown();
+                tokio::spawn(async move {
+                    if let Err(error) = recorder.run(cancel).await {
+                        log::error!("Recording to {} failed: {}", path, error);
+                    }
+                });
             },
-            curtains: DummyDevice {
-                device_type: "Smart Curtains",
-                name: "Office curtains"
+            Err(error) => fail(format!("Failed to open recording {}: {}", path, error)),
+        }
+    }
+    let store = matches.get_one::<String>("state")
+        .map(|dir| StateStore::open(dir, StoreConfig::default()).unwrap_or_else(|error| fail(error)));
+    let scenes: Vec<_> = matches.get_many::<String>("scene").into_iter().flatten()
+        .map(|path| domus_core::Scene::load(path).unwrap_or_else(|error| fail(error)))
+        .collect();
+
+    // the API controls the devices without any authentication, so it stays on this machine unless asked otherwise
+    let api = match matches.get_one::<SocketAddr>("api").copied() {
+        Some(address) if !address.ip().is_loopback() && !matches.get_flag("api-public") =>
+            fail(format!("Refusing to serve the API on {}, which isn't a loopback address, without --api-public", address)),
+        Some(address) => Some(ApiServer::bind(address).await.unwrap_or_else(|error| fail(format!("Failed to listen on {}: {}", address, error)))),
+        None => None,
+    };
+    let services = Services { store: store.as_ref(), api: api.as_ref(), scenes: &scenes, scheduler: None };
+
+    // with a configuration file the layout comes from there, otherwise it is the one compiled in below
+    let speed = *matches.get_one::<f64>("speed").unwrap();
+    let config = matches.get_one::<String>("config");
+    if let Some(path) = matches.get_one::<String>("replay") {
+        let recording = recorder::load_recording(path).unwrap_or_else(|error| fail(error));
+        // on the configured tree, or the simulated apartment for what a simulation recorded
+        match config {
+            Some(config) => if let Err(error) = replay_configured(&recording, config, speed, &events, &supervisor, services, &signals).await {
+                fail(error);
             },
+            None => run_simulated(Playback::Recording(recording), speed, &events, &supervisor, services, &signals).await,
+        }
+        return;
+    }
+    if matches.get_flag("simulate") {
+        run_simulated(Playback::Scenario, speed, &events, &supervisor, services, &signals).await;
+        return;
+    }
+    if let Some(path) = config {
+        if let Err(error) = run_configured(path, &events, &supervisor, services, &signals).await {
+            fail(error);
+        }
+        return;
+    }
+
+    let apartment = domus! {
+        name: "Apartment",
+        
+        #[tags("work")]
+        office: Space {
+            name: "Office",
+            #[tags("presence")]
+            #[metadata(zone = "desk")]
+            motion_sensor: AqaraFP2 {
+                name: "Offic motion sensor",
+                ip: "192.168.22.51"
+            }
         },
+
+        living_room: Space {
+            name: "Living Room",
+            motion_sensor: AqaraFP2 {
+                name: "Living room motion sensor"
+            }
+        }
     };
- */
-    
-
- 
- /*
-    // You can still reference devices like this:
-    let entrance_door = &apartment.entrance_hallway.door_sensor;
-    let kitchen_temp = &apartment.main_living_area.kitchen.temperature_sensor;
-    let office_motion = &apartment.office.motion_sensor;
-    let living_room_speakers = &apartment.main_living_area.living_room.speakers;
-    let bedroom_scale = &apartment.bedroom.smart_scale;
-    let kitchen_curtains = &apartment.main_living_area.kitchen.curtains;
-    let balcony_curtains = &apartment.main_living_area.living_room.balcony_curtains;
-    */ 
+
+    let office_motion = apartment.handle().office.motion_sensor;
+    let rules = RuleEngine::new()
+        .announce_on(&events)
+        .rule(Rule::new("Office occupancy")
+            .when(Trigger::changed(office_motion, Capability::Occupancy))
+            .then(Action::custom(move |root| if let Some(sensor) = office_motion.resolve(root) {
+                log::info!("Office occupancy changed: {:?}", sensor.state.occupied());
+            })));
+    let rule_events = events.subscribe(EventFilter::all());
+
+    let office_lighting = SpaceStateMachine::new(path!(apartment.office), OccupancyLighting::default());
+    let living_room_lighting = SpaceStateMachine::new(path!(apartment.living_room), OccupancyLighting::default());
+
+    start(&apartment, &events, &supervisor, services, None).await;
+
+    let cancel = signals.shutdown();
+    let mut reloads = signals.reloads();
+    tokio::join!(
+        serve(&apartment, &events, &supervisor, services, cancel.clone()),
+        rules.run(&apartment, rule_events, cancel.clone()),
+        office_lighting.run(&apartment, &events, cancel.clone()),
+        living_room_lighting.run(&apartment, &events, cancel.clone()),
+        async {
+            loop {
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    changed = reloads.changed() => if changed.is_err() {
+                        break;
+                    },
+                }
+                log::info!("The layout is compiled in, run with a configuration file to reload it");
+            }
+        },
+    );
+
+    stop(&apartment, &events, &supervisor).await;
 }
@@ -0,0 +1,266 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+use domus_core::{DeviceGroup, Event, EventBus, EventFilter, EventKind, EventSubscription, Space};
+use serde::{Serialize, Deserialize};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// How long a replay keeps watching after the last event, for the rules it set off to finish.
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// One line of a recording, the event's time in milliseconds since the epoch.
+#[derive(Serialize, Deserialize)]
+struct Line {
+    t: u64,
+    p: String,
+    #[serde(flatten)]
+    kind: EventKind,
+}
+
+/// Appends every event on the bus to a file, a JSON line each, so an evening can be replayed later.
+pub struct Recorder {
+    file: BufWriter<File>,
+    events: EventSubscription,
+}
+
+impl Recorder {
+    /// Opens the recording for appending and starts listening, so nothing published from here on is missed.
+    pub fn open(path: impl AsRef<Path>, events: &EventBus) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Recorder { file: BufWriter::new(file), events: events.subscribe(EventFilter::all()) })
+    }
+
+    /// Records until cancelled, flushing whenever it has caught up with the bus.
+    pub async fn run(mut self, cancel: CancellationToken) -> io::Result<()> {
+        loop {
+            let event = tokio::select! {
+                _ = cancel.cancelled() => break,
+                event = self.events.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+            };
+            self.write(&event)?;
+            self.catch_up()?;
+        }
+        self.catch_up()
+    }
+
+    fn catch_up(&mut self) -> io::Result<()> {
+        while let Some(event) = self.events.try_recv() {
+            self.write(&event)?;
+        }
+        self.file.flush()
+    }
+
+    fn write(&mut self, event: &Event) -> io::Result<()> {
+        let line = Line {
+            t: event.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            p: event.path.clone(),
+            kind: event.kind.clone(),
+        };
+        serde_json::to_writer(&mut self.file, &line)?;
+        self.file.write_all(b"\n")
+    }
+}
+
+/// Reads back what a `Recorder` wrote.
+pub fn load_recording(path: impl AsRef<Path>) -> Result<Vec<Event>, Box<dyn Error>> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| format!("Failed to read recording {}: {}", path.display(), e))?;
+    let mut recording = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line: Line = serde_json::from_str(&line)
+            .map_err(|e| format!("{}:{}: {}", path.display(), number + 1, e))?;
+        recording.push(Event { path: line.p, time: UNIX_EPOCH + Duration::from_millis(line.t), kind: line.kind });
+    }
+    Ok(recording)
+}
+
+/// Whether the event is something an automation did, rather than something it reacts to.
+fn is_action(kind: &EventKind) -> bool {
+    matches!(kind, EventKind::RuleFired { .. } | EventKind::CommandSent { .. })
+}
+
+/// The actions of a recording and those its replay set off, each at its time since the recording started.
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    pub replayed: usize,
+    pub recorded: Vec<(Duration, Event)>,
+    pub fired: Vec<(Duration, Event)>,
+}
+
+impl ReplayReport {
+    /// Pairs the recorded actions with the fired ones, in order, returning which of each found a match.
+    fn matches(&self) -> (Vec<bool>, Vec<bool>) {
+        let mut fired = vec![false; self.fired.len()];
+        let recorded = self.recorded.iter()
+            .map(|(_, recorded)| {
+                let found = self.fired.iter().zip(&fired)
+                    .position(|((_, event), matched)| !matched && event.path == recorded.path && event.kind == recorded.kind);
+                found.map(|i| fired[i] = true).is_some()
+            })
+            .collect();
+        (recorded, fired)
+    }
+}
+
+/// Lists every action in time, marking those that fired but weren't recorded with `+` and those that were
+/// recorded but didn't fire with `-`.
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (recorded, fired) = self.matches();
+        let differing = recorded.iter().chain(&fired).filter(|matched| !**matched).count();
+        writeln!(f, "Replayed {} events, {} actions were recorded and {} fired, {} differ:", self.replayed, self.recorded.len(), self.fired.len(), differing)?;
+        let mut actions: Vec<_> = self.fired.iter().zip(fired)
+            .map(|((at, event), matched)| (*at, if matched { ' ' } else { '+' }, event))
+            .chain(self.recorded.iter().zip(recorded)
+                .filter(|(_, matched)| !matched)
+                .map(|((at, event), _)| (*at, '-', event)))
+            .collect();
+        actions.sort_by_key(|(at, ..)| *at);
+        for (at, marker, event) in actions {
+            match &event.kind {
+                EventKind::RuleFired { rule } => writeln!(f, "{} {:>8.1}s  rule {}", marker, at.as_secs_f64(), rule)?,
+                EventKind::CommandSent { value } => writeln!(f, "{} {:>8.1}s    {} <- {:?}", marker, at.as_secs_f64(), event.path, value)?,
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Feeds a recording back through the automations watching `events`, as it happened.
+/// On the virtual clock of a simulation that can be many times faster than it did.
+///
+/// Readings are injected into the simulated devices at the recorded paths, and published as they were
+/// for any other device. What the automations derived from them, such as presence, isn't replayed but
+/// recomputed, and what they did ends up in the report.
+pub async fn replay(recording: &[Event], root: &dyn Space, events: &EventBus) -> ReplayReport {
+    let mut report = ReplayReport::default();
+    let Some(start) = recording.first().map(|event| event.time) else { return report };
+    let since_start = |event: &Event| event.time.duration_since(start).unwrap_or_default();
+    report.recorded = recording.iter()
+        .filter(|event| is_action(&event.kind))
+        .map(|event| (since_start(event), event.clone()))
+        .collect();
+
+    let mut actions = events.subscribe(EventFilter::all());
+    let began = Instant::now();
+    let done = CancellationToken::new();
+    let feed = async {
+        for event in recording {
+            tokio::time::sleep_until(began + since_start(event)).await;
+            let replayed = Event { time: domus_core::now(), ..event.clone() };
+            match &event.kind {
+                EventKind::CapabilityChanged { value, .. } => match root.find_device(&event.path) {
+                    Some(device) if driver::sim::inject(device, *value) => {},
+                    // groups publish their aggregate themselves
+                    Some(device) if device.as_any().is::<DeviceGroup>() => continue,
+                    _ => events.publish(replayed),
+                },
+                EventKind::Scheduled { .. } => events.publish(replayed),
+                _ => continue,
+            }
+            report.replayed += 1;
+        }
+        tokio::time::sleep(SETTLE_TIME).await;
+        done.cancel();
+    };
+    let collect = async {
+        let mut fired = Vec::new();
+        loop {
+            tokio::select! {
+                _ = done.cancelled() => break,
+                Some(event) = actions.recv() => if is_action(&event.kind) {
+                    fired.push((began.elapsed(), event));
+                },
+            }
+        }
+        fired
+    };
+    let ((), fired) = tokio::join!(feed, collect);
+    report.fired = fired;
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domus_core::{Capability, CapabilityValue};
+    use driver::{SimLight, SimMotionSensor};
+    use macros::domus;
+    use crate::rules::{Action, Rule, RuleEngine, Trigger};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("domus-recording-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let events = EventBus::default();
+        let recorder = Recorder::open(&path, &events).unwrap();
+        let publisher = events.publisher("hallway/motion_sensor");
+        publisher.publish(EventKind::CapabilityChanged { value: CapabilityValue::Occupancy(true), previous: None });
+        events.publisher("hallway").publish(EventKind::PresenceChanged { previous: 0.0, probability: 1.0 });
+        // stamped on the virtual clock, which skips the wait
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        events.publisher("").publish(EventKind::RuleFired { rule: "Hallway light".to_string() });
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        recorder.run(cancel).await.unwrap();
+
+        let recording = load_recording(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(recording.len(), 3);
+        assert_eq!(recording[1].kind, EventKind::PresenceChanged { previous: 0.0, probability: 1.0 });
+        assert!(recording[2].time.duration_since(recording[1].time).unwrap() >= Duration::from_secs(2));
+
+        let house = domus! {
+            name: "House",
+            hallway: Space {
+                name: "Hallway",
+                motion_sensor: SimMotionSensor { name: "Hallway motion sensor" },
+                light: SimLight { name: "Hallway light" }
+            }
+        };
+        let replay_events = EventBus::default();
+        (&house as &dyn Space).bind_events(&replay_events);
+        let hallway = house.handle().hallway;
+        let rules = RuleEngine::new()
+            .announce_on(&replay_events)
+            .rule(Rule::new("Hallway light")
+                .when(Trigger::changed(hallway.motion_sensor, Capability::Occupancy))
+                .then(Action::command(hallway.light, CapabilityValue::OnOff(true))));
+        let cancel = CancellationToken::new();
+        let (report, ()) = tokio::join!(
+            async {
+                let report = replay(&recording, &house, &replay_events).await;
+                cancel.cancel();
+                report
+            },
+            rules.run(&house, replay_events.subscribe(EventFilter::all()), cancel.clone()),
+        );
+
+        assert_eq!(report.replayed, 1);
+        assert_eq!(report.recorded.len(), 1);
+        let fired: Vec<_> = report.fired.iter().map(|(_, event)| (event.path.as_str(), &event.kind)).collect();
+        assert_eq!(fired, vec![
+            ("", &EventKind::RuleFired { rule: "Hallway light".to_string() }),
+            ("hallway/light", &EventKind::CommandSent { value: CapabilityValue::OnOff(true) }),
+        ]);
+        assert_eq!(house.hallway.light.state.is_on(), Some(true));
+
+        // the command wasn't in the recording
+        let lines: Vec<_> = report.to_string().lines().map(str::to_string).collect();
+        assert_eq!(lines[0], "Replayed 1 events, 1 actions were recorded and 2 fired, 1 differ:");
+        assert!(lines[1].starts_with("  ") && lines[1].ends_with("rule Hallway light"));
+        assert!(lines[2].starts_with("+ ") && lines[2].ends_with("hallway/light <- OnOff(true)"));
+    }
+}
@@ -0,0 +1,323 @@
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{Local, NaiveTime};
+use domus_core::{Capability, CapabilityValue, CommandOptions, DeviceHandle, Event, EventBus, EventKind, EventSubscription, LifeCycleStage, Scene, SceneOptions, Space, SpaceHandle};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// Presence probability from which a space counts as occupied.
+pub const OCCUPIED_THRESHOLD: f32 = 0.5;
+
+/// What makes a rule run. The constructors take the handles `domus!` generates, so rules in code are checked against
+/// the tree at compile time; config files build the variants from paths they check on load.
+#[derive(Debug, Clone)]
+pub enum Trigger {
+    /// The device reported a new value, of any capability if `None`.
+    Changed { device: String, capability: Option<Capability> },
+    /// The space went from vacant to occupied.
+    Occupied { space: String },
+    /// The space went from occupied to vacant.
+    Vacant { space: String },
+    Every(Duration),
+    /// Every day at this local time.
+    At(NaiveTime),
+    LifeCycle(LifeCycleStage),
+    /// The schedule of this name came due, see `Scheduler`.
+    Scheduled { name: String },
+}
+
+impl Trigger {
+    pub fn changed<T>(device: DeviceHandle<T>, capability: Capability) -> Self {
+        Trigger::Changed { device: device.path().to_string(), capability: Some(capability) }
+    }
+
+    pub fn occupied(space: impl SpaceHandle) -> Self {
+        Trigger::Occupied { space: space.path().to_string() }
+    }
+
+    pub fn vacant(space: impl SpaceHandle) -> Self {
+        Trigger::Vacant { space: space.path().to_string() }
+    }
+
+    pub fn scheduled(name: &str) -> Self {
+        Trigger::Scheduled { name: name.to_string() }
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        match (self, &event.kind) {
+            (Trigger::Changed { device, capability }, EventKind::CapabilityChanged { value, .. }) =>
+                *device == event.path && capability.is_none_or(|capability| capability == value.capability()),
+            (Trigger::Occupied { space }, EventKind::PresenceChanged { previous, probability }) =>
+                *space == event.path && *previous < OCCUPIED_THRESHOLD && *probability >= OCCUPIED_THRESHOLD,
+            (Trigger::Vacant { space }, EventKind::PresenceChanged { previous, probability }) =>
+                *space == event.path && *previous >= OCCUPIED_THRESHOLD && *probability < OCCUPIED_THRESHOLD,
+            (Trigger::LifeCycle(expected), EventKind::LifeCycle { stage }) =>
+                event.path.is_empty() && expected == stage,
+            (Trigger::Scheduled { name }, EventKind::Scheduled { schedule, .. }) => name == schedule,
+            _ => false,
+        }
+    }
+
+    /// When a time trigger fires next, `None` for triggers driven by events.
+    fn next_due(&self, now: Instant) -> Option<Instant> {
+        match self {
+            Trigger::Every(period) => Some(now + *period),
+            Trigger::At(time) => {
+                let local = Local::now().naive_local();
+                let mut next = local.date().and_time(*time);
+                if next <= local {
+                    next += chrono::Duration::days(1);
+                }
+                Some(now + (next - local).to_std().unwrap_or_default())
+            },
+            _ => None,
+        }
+    }
+}
+
+pub type ActionFn = Arc<dyn Fn(&dyn Space)>;
+
+/// What has to hold when a rule triggers, for it to run.
+#[derive(Clone)]
+pub enum Condition {
+    State { device: String, value: CapabilityValue },
+    Occupied { space: String },
+    Vacant { space: String },
+    /// Local time of day within `from..to`, wrapping around midnight when `to` comes before `from`.
+    Between { from: NaiveTime, to: NaiveTime },
+    Not(Box<Condition>),
+    Any(Vec<Condition>),
+}
+
+impl Condition {
+    pub fn state<T>(device: DeviceHandle<T>, value: CapabilityValue) -> Self {
+        Condition::State { device: device.path().to_string(), value }
+    }
+
+    pub fn occupied(space: impl SpaceHandle) -> Self {
+        Condition::Occupied { space: space.path().to_string() }
+    }
+
+    pub fn vacant(space: impl SpaceHandle) -> Self {
+        Condition::Vacant { space: space.path().to_string() }
+    }
+
+    pub fn between(from: NaiveTime, to: NaiveTime) -> Self {
+        Condition::Between { from, to }
+    }
+
+    pub fn holds(&self, root: &dyn Space, now: NaiveTime) -> bool {
+        match self {
+            Condition::State { device, value } => root.find_device(device)
+                .is_some_and(|device| device.state(value.capability()) == Some(*value)),
+            Condition::Occupied { space } => root.find_space(space)
+                .is_some_and(|space| space.presence().probability() >= OCCUPIED_THRESHOLD),
+            Condition::Vacant { space } => root.find_space(space)
+                .is_some_and(|space| space.presence().probability() < OCCUPIED_THRESHOLD),
+            Condition::Between { from, to } if from <= to => *from <= now && now < *to,
+            Condition::Between { from, to } => *from <= now || now < *to,
+            Condition::Not(condition) => !condition.holds(root, now),
+            Condition::Any(conditions) => conditions.iter().any(|condition| condition.holds(root, now)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Action {
+    Command { device: String, value: CapabilityValue },
+    Delay(Duration),
+    /// Applies the scene, fading over `transition` if set.
+    Scene { scene: Scene, transition: Option<Duration> },
+    Custom(ActionFn),
+}
+
+impl Action {
+    pub fn command<T>(device: DeviceHandle<T>, value: CapabilityValue) -> Self {
+        Action::Command { device: device.path().to_string(), value }
+    }
+
+    pub fn custom(action: impl Fn(&dyn Space) + 'static) -> Self {
+        Action::Custom(Arc::new(action))
+    }
+}
+
+/// Runs its actions in order when any of its triggers fire and all of its conditions hold.
+#[derive(Clone)]
+pub struct Rule {
+    pub name: String,
+    triggers: Vec<Trigger>,
+    conditions: Vec<Condition>,
+    actions: Vec<Action>,
+}
+
+impl Rule {
+    pub fn new(name: &str) -> Self {
+        Rule {
+            name: name.to_string(),
+            triggers: Vec::new(),
+            conditions: Vec::new(),
+            actions: Vec::new(),
+        }
+    }
+
+    pub fn when(mut self, trigger: Trigger) -> Self {
+        self.triggers.push(trigger);
+        self
+    }
+
+    pub fn only_if(mut self, condition: Condition) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+
+    pub fn then(mut self, action: Action) -> Self {
+        self.actions.push(action);
+        self
+    }
+
+    fn conditions_hold(&self, root: &dyn Space) -> bool {
+        let now = Local::now().time();
+        self.conditions.iter().all(|condition| condition.holds(root, now))
+    }
+}
+
+#[derive(Default)]
+pub struct RuleEngine {
+    rules: Vec<Rule>,
+    /// Where fired rules and their commands are announced, if anywhere.
+    events: Option<EventBus>,
+}
+
+impl RuleEngine {
+    pub fn new() -> Self {
+        RuleEngine::default()
+    }
+
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Announces fired rules and the commands they send on the bus, so they end up in recordings.
+    pub fn announce_on(mut self, events: &EventBus) -> Self {
+        self.events = Some(events.clone());
+        self
+    }
+
+    fn announce(&self, path: &str, kind: EventKind) {
+        if let Some(events) = &self.events {
+            events.publisher(path).publish(kind);
+        }
+    }
+
+    /// Runs the rules against the tree until cancelled. Rules run concurrently, so a delay in one doesn't hold up the others.
+    pub async fn run(&self, root: &dyn Space, mut events: EventSubscription, cancel: CancellationToken) {
+        let start = Instant::now();
+        let mut timers: Vec<(&Rule, &Trigger, Instant)> = self.rules.iter()
+            .flat_map(|rule| rule.triggers.iter().map(move |trigger| (rule, trigger)))
+            .filter_map(|(rule, trigger)| Some((rule, trigger, trigger.next_due(start)?)))
+            .collect();
+        let mut running = FuturesUnordered::new();
+
+        log::info!("Running {} rules", self.rules.len());
+        loop {
+            let next_timer = timers.iter().map(|(_, _, due)| *due).min();
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                event = events.recv() => {
+                    let Some(event) = event else { break };
+                    for rule in &self.rules {
+                        if rule.triggers.iter().any(|trigger| trigger.matches(&event)) && rule.conditions_hold(root) {
+                            running.push(self.execute(rule, root));
+                        }
+                    }
+                },
+                _ = tokio::time::sleep_until(next_timer.unwrap_or(start)), if next_timer.is_some() => {
+                    let now = Instant::now();
+                    for (rule, trigger, due) in timers.iter_mut().filter(|(_, _, due)| *due <= now) {
+                        if rule.conditions_hold(root) {
+                            running.push(self.execute(rule, root));
+                        }
+                        *due = trigger.next_due(now).unwrap_or(now);
+                    }
+                },
+                Some(()) = running.next(), if !running.is_empty() => {},
+            }
+        }
+        log::info!("Rules stopped, abandoning {} running", running.len());
+    }
+
+    async fn execute(&self, rule: &Rule, root: &dyn Space) {
+        log::info!("Running rule {}", rule.name);
+        self.announce("", EventKind::RuleFired { rule: rule.name.clone() });
+        for action in &rule.actions {
+            match action {
+                Action::Command { device, value } => match root.find_device(device) {
+                    Some(target) => {
+                        self.announce(device, EventKind::CommandSent { value: *value });
+                        if let Err(error) = target.command(*value, CommandOptions::default()).await {
+                            log::error!("Rule {}: setting {:?} on {} failed: {}", rule.name, value, device, error);
+                        }
+                    },
+                    None => log::error!("Rule {}: no device at {}", rule.name, device),
+                },
+                Action::Delay(delay) => tokio::time::sleep(*delay).await,
+                Action::Scene { scene, transition } => {
+                    let options = SceneOptions { transition: *transition, ..Default::default() };
+                    if !root.apply_scene(scene, options).await.is_complete() {
+                        log::error!("Rule {}: scene {} was only partly applied", rule.name, scene.name);
+                    }
+                },
+                Action::Custom(action) => action(root),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use driver::SimMotionSensor;
+    use macros::domus;
+
+    #[test]
+    fn test_triggers() {
+        let apartment = domus! {
+            name: "Apartment",
+            office: Space {
+                name: "Office",
+                motion_sensor: SimMotionSensor { name: "Office motion sensor" }
+            }
+        };
+        let office = apartment.handle().office;
+        let occupied = Trigger::occupied(office);
+        let presence = |previous, probability| Event::new("office", EventKind::PresenceChanged { previous, probability });
+
+        assert!(occupied.matches(&presence(0.0, 1.0)));
+        assert!(!occupied.matches(&presence(0.6, 1.0)));
+        assert!(Trigger::vacant(office).matches(&presence(0.6, 0.4)));
+
+        let changed = Trigger::changed(office.motion_sensor, Capability::Occupancy);
+        let value = Event::new("office/motion_sensor", EventKind::CapabilityChanged {
+            value: CapabilityValue::Occupancy(true),
+            previous: None,
+        });
+        assert!(changed.matches(&value));
+        assert!(!Trigger::changed(office.motion_sensor, Capability::Illuminance).matches(&value));
+    }
+
+    #[test]
+    fn test_time_window() {
+        let time = |hour| NaiveTime::from_hms_opt(hour, 0, 0).unwrap();
+        let evening = Condition::between(time(18), time(23));
+        let night = Condition::between(time(22), time(6));
+        let apartment = domus! { name: "Apartment" };
+        let root: &dyn Space = &apartment;
+
+        assert!(evening.holds(root, time(20)));
+        assert!(!evening.holds(root, time(23)));
+        assert!(night.holds(root, time(2)));
+        assert!(!night.holds(root, time(12)));
+    }
+}
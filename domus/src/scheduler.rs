@@ -0,0 +1,438 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use chrono::{DateTime, Datelike, LocalResult, NaiveDate, NaiveDateTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use domus_core::{EventBus, EventKind};
+use serde::{Serialize, Deserialize};
+use tokio_util::sync::CancellationToken;
+
+/// Longest the scheduler sleeps before looking at the clock again, so clock changes and suspends are noticed.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+/// Most occurrences caught up on at once with `MissedPolicy::RunAll`.
+const MAX_CATCH_UP: usize = 100;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Location {
+    pub latitude: f64,
+    /// Degrees east.
+    pub longitude: f64,
+    pub timezone: Tz,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SunEvent {
+    CivilDawn,
+    Sunrise,
+    Sunset,
+    CivilDusk,
+}
+
+impl SunEvent {
+    /// Altitude of the sun's centre at the event, in degrees.
+    fn altitude(&self) -> f64 {
+        match self {
+            SunEvent::Sunrise | SunEvent::Sunset => -0.833,
+            SunEvent::CivilDawn | SunEvent::CivilDusk => -6.0,
+        }
+    }
+
+    fn is_morning(&self) -> bool {
+        matches!(self, SunEvent::CivilDawn | SunEvent::Sunrise)
+    }
+}
+
+/// When the sun event happens at the location on the given local date, `None` on days it doesn't (polar day or night).
+///
+/// Uses the sunrise equation, good to a minute or two away from the poles.
+pub fn sun_event(location: &Location, date: NaiveDate, event: SunEvent) -> Option<DateTime<Utc>> {
+    let days = (date - NaiveDate::from_ymd_opt(2000, 1, 1)?).num_days() as f64;
+    let mean_solar_noon = days - location.longitude / 360.0;
+    let anomaly = (357.5291 + 0.98560028 * mean_solar_noon).rem_euclid(360.0).to_radians();
+    let center = 1.9148 * anomaly.sin() + 0.0200 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic_longitude = (anomaly.to_degrees() + center + 180.0 + 102.9372).rem_euclid(360.0).to_radians();
+    let transit = 2451545.0 + mean_solar_noon + 0.0053 * anomaly.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin();
+    let declination = (ecliptic_longitude.sin() * 23.4397f64.to_radians().sin()).asin();
+
+    let latitude = location.latitude.to_radians();
+    let cos_hour_angle = (event.altitude().to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees() / 360.0;
+    let julian_day = if event.is_morning() { transit - hour_angle } else { transit + hour_angle };
+    DateTime::from_timestamp(((julian_day - 2440587.5) * 86400.0).round() as i64, 0)
+}
+
+/// Turns a local time into an instant. Times skipped by clocks going forward run when the clocks jump,
+/// times repeated by clocks going back run the first time round.
+fn resolve(timezone: Tz, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+    (0..=180).find_map(|minutes| match timezone.from_local_datetime(&(local + TimeDelta::minutes(minutes))) {
+        LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => Some(time.with_timezone(&Utc)),
+        LocalResult::None => None,
+    })
+}
+
+/// Cron-style schedule: minute, hour, day of month, month and day of week (0 or 7 is Sunday),
+/// each `*`, a number, a range `a-b`, a step `*/n` or `a-b/n`, or a comma separated list of those.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| format!("Invalid step in {}", part))?),
+            None => (part, 1),
+        };
+        let parse = |value: &str| value.parse::<u32>().map_err(|_| format!("Invalid value in {}", part));
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((from, to)) => (parse(from)?, parse(to)?),
+                None => (parse(range)?, parse(range)?),
+            },
+        };
+        if step == 0 || from < min || to > max || from > to {
+            return Err(format!("{} is out of range {}-{}", part, min, max));
+        }
+        for value in (from..=to).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<_> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("Expected 5 fields in {:?}", s));
+        };
+        let weekday_bits = parse_cron_field(weekdays, 0, 7)?;
+        Ok(Cron {
+            minutes: parse_cron_field(minutes, 0, 59)?,
+            hours: parse_cron_field(hours, 0, 23)?,
+            days: parse_cron_field(days, 1, 31)?,
+            months: parse_cron_field(months, 1, 12)?,
+            // Sunday is both 0 and 7
+            weekdays: (weekday_bits | weekday_bits >> 7) & 0x7F,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+}
+
+impl Cron {
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+        // like cron, a restricted day of month and day of week match either
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        day && self.months & (1 << date.month()) != 0
+    }
+
+    pub fn next_after(&self, after: DateTime<Utc>, timezone: Tz) -> Option<DateTime<Utc>> {
+        let mut date = after.with_timezone(&timezone).date_naive();
+        // four years and a day finds even the 29th of February
+        for _ in 0..(4 * 366) {
+            if self.matches_date(date) {
+                let times = (0..24).filter(|hour| self.hours & (1 << hour) != 0)
+                    .flat_map(|hour| (0..60).filter(|minute| self.minutes & (1 << minute) != 0).map(move |minute| (hour, minute)));
+                for (hour, minute) in times {
+                    let due = resolve(timezone, date.and_hms_opt(hour, minute, 0)?);
+                    if due.is_some_and(|due| due > after) {
+                        return due;
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum When {
+    Cron(Cron),
+    /// A sun event, shifted by `offset`, e.g. 30 minutes before sunset.
+    Sun { event: SunEvent, offset: TimeDelta },
+}
+
+impl When {
+    pub fn cron(expression: &str) -> Result<Self, String> {
+        Ok(When::Cron(expression.parse()?))
+    }
+
+    pub fn sun(event: SunEvent, offset: TimeDelta) -> Self {
+        When::Sun { event, offset }
+    }
+
+    pub fn next_after(&self, after: DateTime<Utc>, location: &Location) -> Option<DateTime<Utc>> {
+        match self {
+            When::Cron(cron) => cron.next_after(after, location.timezone),
+            When::Sun { event, offset } => {
+                // start a day early, an offset may reach back over midnight
+                let today = after.with_timezone(&location.timezone).date_naive();
+                (-1..=366)
+                    .filter_map(|days| today.checked_add_signed(TimeDelta::days(days)))
+                    .filter_map(|date| sun_event(location, date, *event))
+                    .map(|time| time + *offset)
+                    .find(|due| *due > after)
+            },
+        }
+    }
+}
+
+/// What to do about occurrences that passed while domus wasn't running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedPolicy {
+    #[default]
+    Skip,
+    /// Run once for all of them.
+    RunOnce,
+    /// Run for each of them, oldest first.
+    RunAll,
+}
+
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub name: String,
+    pub when: When,
+    pub missed: MissedPolicy,
+}
+
+impl Schedule {
+    pub fn new(name: &str, when: When) -> Self {
+        Schedule {
+            name: name.to_string(),
+            when,
+            missed: MissedPolicy::default(),
+        }
+    }
+
+    pub fn missed(mut self, policy: MissedPolicy) -> Self {
+        self.missed = policy;
+        self
+    }
+}
+
+/// Last run per schedule, as unix seconds, kept across restarts to catch up on missed runs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SchedulerState {
+    last_run: BTreeMap<String, i64>,
+}
+
+/// Publishes a `Scheduled` event on the bus every time a schedule comes due, for rules to trigger on.
+pub struct Scheduler {
+    location: Location,
+    schedules: Vec<Schedule>,
+    state_path: Option<PathBuf>,
+}
+
+impl Scheduler {
+    pub fn new(location: Location) -> Self {
+        Scheduler {
+            location,
+            schedules: Vec::new(),
+            state_path: None,
+        }
+    }
+
+    pub fn schedule(mut self, schedule: Schedule) -> Self {
+        self.schedules.push(schedule);
+        self
+    }
+
+    /// Where to remember when each schedule last ran. Without it, missed runs can't be caught up on.
+    pub fn state_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.state_path = Some(path.into());
+        self
+    }
+
+    fn occurrences<'a>(&'a self, schedule: &'a Schedule, since: DateTime<Utc>, now: DateTime<Utc>) -> impl Iterator<Item = DateTime<Utc>> + 'a {
+        std::iter::successors(schedule.when.next_after(since, &self.location), |due| schedule.when.next_after(*due, &self.location))
+            .take_while(move |due| *due <= now)
+    }
+
+    /// Occurrences of the schedule after `since` up to and including `now`, the oldest `MAX_CATCH_UP` of them.
+    pub fn missed(&self, schedule: &Schedule, since: DateTime<Utc>, now: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+        self.occurrences(schedule, since, now).take(MAX_CATCH_UP).collect()
+    }
+
+    /// The most recent occurrence of the schedule after `since` up to and including `now`.
+    ///
+    /// Looks back over a doubling window, so a long downtime doesn't mean walking every occurrence since.
+    pub fn last_missed(&self, schedule: &Schedule, since: DateTime<Utc>, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut window = TimeDelta::hours(1);
+        loop {
+            let from = (now - window).max(since);
+            let last = self.occurrences(schedule, from, now).last();
+            if last.is_some() || from == since {
+                return last;
+            }
+            window = window * 2;
+        }
+    }
+
+    fn load_state(&self) -> SchedulerState {
+        let Some(path) = &self.state_path else {
+            return SchedulerState::default();
+        };
+        match std::fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text).unwrap_or_else(|error| {
+                log::warn!("Ignoring invalid scheduler state {}: {}", path.display(), error);
+                SchedulerState::default()
+            }),
+            Err(_) => SchedulerState::default(),
+        }
+    }
+
+    fn save_state(&self, state: &SchedulerState) {
+        if let Some(path) = &self.state_path {
+            let result = toml::to_string(state).map_err(|e| e.to_string())
+                .and_then(|text| std::fs::write(path, text).map_err(|e| e.to_string()));
+            if let Err(error) = result {
+                log::error!("Failed to save scheduler state {}: {}", path.display(), error);
+            }
+        }
+    }
+
+    fn fire(&self, events: &EventBus, schedule: &Schedule, due: DateTime<Utc>) {
+        log::info!("Schedule {} due at {}", schedule.name, due.with_timezone(&self.location.timezone));
+        events.publisher("").publish(EventKind::Scheduled {
+            schedule: schedule.name.clone(),
+            due: due.into(),
+        });
+    }
+
+    pub async fn run(&self, events: &EventBus, cancel: CancellationToken) {
+        let mut state = self.load_state();
+        let now = Utc::now();
+
+        for schedule in &self.schedules {
+            let last_run = state.last_run.get(&schedule.name).and_then(|last_run| DateTime::from_timestamp(*last_run, 0));
+            if let Some(last_run) = last_run {
+                let catch_up = match schedule.missed {
+                    MissedPolicy::Skip => Vec::new(),
+                    MissedPolicy::RunOnce => self.last_missed(schedule, last_run, now).into_iter().collect(),
+                    MissedPolicy::RunAll => self.missed(schedule, last_run, now),
+                };
+                if !catch_up.is_empty() {
+                    log::info!("Schedule {} missed runs, catching up on {} of them", schedule.name, catch_up.len());
+                }
+                for due in catch_up {
+                    self.fire(events, schedule, due);
+                }
+            }
+            state.last_run.insert(schedule.name.clone(), now.timestamp());
+        }
+        self.save_state(&state);
+
+        let mut next: Vec<_> = self.schedules.iter()
+            .map(|schedule| schedule.when.next_after(now, &self.location))
+            .collect();
+        loop {
+            let now = Utc::now();
+            let mut fired = false;
+            for (schedule, next) in self.schedules.iter().zip(next.iter_mut()) {
+                while let Some(due) = next.filter(|due| *due <= now) {
+                    self.fire(events, schedule, due);
+                    state.last_run.insert(schedule.name.clone(), due.timestamp());
+                    *next = schedule.when.next_after(due, &self.location);
+                    fired = true;
+                }
+            }
+            if fired {
+                self.save_state(&state);
+            }
+
+            let sleep = next.iter().flatten().min()
+                .map(|due| (*due - now).to_std().unwrap_or_default())
+                .unwrap_or(MAX_SLEEP)
+                .min(MAX_SLEEP);
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep(sleep) => {},
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::Amsterdam;
+
+    const AMSTERDAM: Location = Location { latitude: 52.37, longitude: 4.90, timezone: Amsterdam };
+
+    fn local(text: &str) -> DateTime<Utc> {
+        let local = NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap();
+        resolve(Amsterdam, local).unwrap()
+    }
+
+    #[test]
+    fn test_sun_events() {
+        let midsummer = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        let sunrise = sun_event(&AMSTERDAM, midsummer, SunEvent::Sunrise).unwrap();
+        let sunset = sun_event(&AMSTERDAM, midsummer, SunEvent::Sunset).unwrap();
+        assert!((sunrise - local("2024-06-21 05:18")).num_minutes().abs() <= 3);
+        assert!((sunset - local("2024-06-21 22:06")).num_minutes().abs() <= 3);
+        assert!(sun_event(&AMSTERDAM, midsummer, SunEvent::CivilDusk).unwrap() > sunset);
+
+        let svalbard = Location { latitude: 78.22, longitude: 15.65, timezone: chrono_tz::Arctic::Longyearbyen };
+        assert_eq!(sun_event(&svalbard, midsummer, SunEvent::Sunset), None);
+
+        let before_sunset = When::sun(SunEvent::Sunset, TimeDelta::minutes(-30));
+        assert_eq!(before_sunset.next_after(local("2024-06-21 12:00"), &AMSTERDAM), Some(sunset - TimeDelta::minutes(30)));
+    }
+
+    #[test]
+    fn test_cron() {
+        let weekdays = When::cron("0 7 * * 1-5").unwrap();
+        // Friday evening, next is Monday morning
+        assert_eq!(weekdays.next_after(local("2024-03-29 20:00"), &AMSTERDAM), Some(local("2024-04-01 07:00")));
+
+        // clocks go forward at 02:00 on the 31st of March, 02:30 doesn't exist that day
+        let half_past_two = When::cron("30 2 * * *").unwrap();
+        assert_eq!(half_past_two.next_after(local("2024-03-30 12:00"), &AMSTERDAM), Some(local("2024-03-31 03:00")));
+        // clocks go back at 03:00 on the 27th of October, 02:30 happens twice but runs once
+        let first = half_past_two.next_after(local("2024-10-27 00:00"), &AMSTERDAM).unwrap();
+        assert_eq!(half_past_two.next_after(first, &AMSTERDAM), Some(local("2024-10-28 02:30")));
+
+        assert!("0 7 * *".parse::<Cron>().is_err());
+        assert!("61 7 * * *".parse::<Cron>().is_err());
+        assert_eq!("*/15 8-9 1,15 * 0".parse::<Cron>().unwrap(), "0,15,30,45 8,9 1,15 * 7".parse::<Cron>().unwrap());
+    }
+
+    #[test]
+    fn test_missed() {
+        let scheduler = Scheduler::new(AMSTERDAM);
+        let schedule = Schedule::new("Morning", When::cron("0 7 * * *").unwrap()).missed(MissedPolicy::RunAll);
+        let missed = scheduler.missed(&schedule, local("2024-05-01 08:00"), local("2024-05-04 07:00"));
+        assert_eq!(missed, vec![local("2024-05-02 07:00"), local("2024-05-03 07:00"), local("2024-05-04 07:00")]);
+
+        // running once after a long downtime runs the latest, not the last of the oldest few
+        let every_minute = Schedule::new("Poll", When::cron("* * * * *").unwrap()).missed(MissedPolicy::RunOnce);
+        assert_eq!(scheduler.last_missed(&every_minute, local("2024-05-01 08:00"), local("2024-06-01 12:34")), Some(local("2024-06-01 12:34")));
+        assert_eq!(scheduler.last_missed(&schedule, local("2024-05-01 08:00"), local("2024-05-04 06:59")), Some(local("2024-05-03 07:00")));
+        assert_eq!(scheduler.last_missed(&schedule, local("2024-05-01 08:00"), local("2024-05-02 06:59")), None);
+    }
+}
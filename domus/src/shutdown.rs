@@ -0,0 +1,114 @@
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+/// Process signals as the runtime sees them: SIGINT or SIGTERM to shut down, SIGHUP to reload the configuration.
+///
+/// A second SIGINT or SIGTERM while shutting down exits straight away.
+pub struct Signals {
+    shutdown: CancellationToken,
+    reloads: watch::Receiver<u64>,
+}
+
+impl Signals {
+    /// Starts listening in the background, must be called from within the runtime.
+    pub fn listen() -> std::io::Result<Self> {
+        let shutdown = CancellationToken::new();
+        let (reload, reloads) = watch::channel(0);
+        let mut listener = Listener::new()?;
+
+        let token = shutdown.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.next().await {
+                    Signal::Stop if token.is_cancelled() => {
+                        log::warn!("Second stop signal, exiting without finishing shutdown");
+                        std::process::exit(130);
+                    },
+                    Signal::Stop => {
+                        log::info!("Stop signal received, shutting down");
+                        token.cancel();
+                    },
+                    Signal::Reload => {
+                        log::info!("Hangup signal received, reloading");
+                        reload.send_modify(|generation| *generation += 1);
+                    },
+                }
+            }
+        });
+
+        Ok(Signals { shutdown, reloads })
+    }
+
+    /// Cancelled once a stop signal arrives.
+    pub fn shutdown(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Yields once per SIGHUP, its value counting the reloads so far.
+    pub fn reloads(&self) -> watch::Receiver<u64> {
+        self.reloads.clone()
+    }
+}
+
+enum Signal {
+    Stop,
+    Reload,
+}
+
+#[cfg(unix)]
+struct Listener {
+    interrupt: tokio::signal::unix::Signal,
+    terminate: tokio::signal::unix::Signal,
+    hangup: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Listener {
+    fn new() -> std::io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Listener {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    async fn next(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.interrupt.recv() => Signal::Stop,
+            _ = self.terminate.recv() => Signal::Stop,
+            _ = self.hangup.recv() => Signal::Reload,
+        }
+    }
+}
+
+/// Only Ctrl-C elsewhere, there is no SIGTERM or SIGHUP to listen for.
+#[cfg(not(unix))]
+struct Listener;
+
+#[cfg(not(unix))]
+impl Listener {
+    fn new() -> std::io::Result<Self> {
+        Ok(Listener)
+    }
+
+    async fn next(&mut self) -> Signal {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+        Signal::Stop
+    }
+}
+
+/// Runs a shutdown step, giving up on it after `timeout` so a stuck device can't keep the process alive.
+pub async fn within<F: Future>(timeout: Duration, what: &str, step: F) -> Option<F::Output> {
+    match tokio::time::timeout(timeout, step).await {
+        Ok(output) => Some(output),
+        Err(_) => {
+            log::error!("{} didn't finish within {:?}, giving up on it", what, timeout);
+            None
+        },
+    }
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+/// How much real time passes between two steps of the virtual clock.
+const PACE_TICK: Duration = Duration::from_millis(10);
+
+/// A runtime whose clock starts out paused, for a simulation to run on virtual time.
+///
+/// Every timer in the process follows that clock, so with `pace` moving it along the whole run is
+/// sped up alike: the scenario, the devices, presence, the state machines and the rules.
+pub fn runtime() -> std::io::Result<Runtime> {
+    tokio::runtime::Builder::new_current_thread().enable_all().start_paused(true).build()
+}
+
+/// Moves the paused clock along `speed` times as fast as real time, forever.
+///
+/// Sleeping off the real time on the blocking pool keeps the runtime from skipping ahead to the next timer meanwhile.
+pub async fn pace(speed: f64) {
+    loop {
+        let started = std::time::Instant::now();
+        let _ = tokio::task::spawn_blocking(|| std::thread::sleep(PACE_TICK)).await;
+        tokio::time::advance(started.elapsed().mul_f64(speed)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domus_core::{CapabilityValue, EventBus, OccupancyLighting, Space, SpaceStateMachine};
+    use driver::{SimLight, SimMotionSensor};
+    use macros::domus;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lighting_times_out_when_sped_up() {
+        let office = domus! {
+            name: "Office",
+            motion_sensor: SimMotionSensor { name: "Office motion sensor" },
+            ceiling_light: SimLight { name: "Office light" }
+        };
+        let root: &dyn Space = &office;
+        let events = EventBus::default();
+        root.bind_events(&events);
+        let lighting = SpaceStateMachine::new("", OccupancyLighting::default());
+        office.motion_sensor.state.set(CapabilityValue::Occupancy(true));
+
+        let pacer = tokio::spawn(pace(600.0));
+        let cancel = CancellationToken::new();
+        let real = std::time::Instant::now();
+        let virtual_start = tokio::time::Instant::now();
+        let light_off = async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            assert_eq!(office.ceiling_light.state.is_on(), Some(true));
+            office.motion_sensor.state.set(CapabilityValue::Occupancy(false));
+            while office.ceiling_light.state.is_on() != Some(false) {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            cancel.cancel();
+        };
+        tokio::join!(
+            root.track_presence(&events, Duration::from_secs(5), cancel.clone()),
+            lighting.run(root, &events, cancel.clone()),
+            light_off,
+        );
+        pacer.abort();
+
+        // presence halves in 2 minutes, then the lights wait a minute and dim for 30 seconds
+        assert!(virtual_start.elapsed() >= Duration::from_secs(220));
+        assert!(real.elapsed() < Duration::from_secs(5));
+    }
+}
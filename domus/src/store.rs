@@ -0,0 +1,506 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use domus_core::{Capability, CapabilityValue, ContactState, EventBus, EventFilter, EventKind, Space};
+use serde::{Serialize, Deserialize};
+use tokio_util::sync::CancellationToken;
+
+const LATEST_FILE: &str = "latest.json";
+const HISTORY_FILE: &str = "history.jsonl";
+/// How often the history is downsampled and cut to its retention.
+const COMPACT_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Copy)]
+pub struct StoreConfig {
+    /// How long every sample is kept as it came in.
+    pub raw_retention: Duration,
+    /// What samples older than that are downsampled into.
+    pub bucket: Duration,
+    /// How long any history is kept.
+    pub retention: Duration,
+    /// How often changes are written out.
+    pub flush_interval: Duration,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            raw_retention: Duration::from_secs(2 * 86400),
+            bucket: Duration::from_secs(15 * 60),
+            retention: Duration::from_secs(90 * 86400),
+            flush_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What a history is kept of: a capability of a device, or the presence of a space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Metric {
+    Capability(Capability),
+    Presence,
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Metric::Capability(capability) => capability.fmt(f),
+            Metric::Presence => f.write_str("presence"),
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "presence" => Ok(Metric::Presence),
+            _ => s.parse().map(Metric::Capability),
+        }
+    }
+}
+
+/// A value over time. Booleans are 0 or 1, open contacts 1, values without a scale such as colors have no history.
+fn numeric(value: &CapabilityValue) -> Option<f64> {
+    match *value {
+        CapabilityValue::Occupancy(on) | CapabilityValue::OnOff(on) => Some(if on { 1.0 } else { 0.0 }),
+        CapabilityValue::Brightness(percent) | CapabilityValue::WindowCovering(percent) => Some(percent.into()),
+        CapabilityValue::ColorTemperature(kelvin) => Some(kelvin.into()),
+        CapabilityValue::Contact(contact) => Some(if contact == ContactState::Open { 1.0 } else { 0.0 }),
+        CapabilityValue::Temperature(value) | CapabilityValue::Humidity(value) | CapabilityValue::Illuminance(value) => Some(value.into()),
+        CapabilityValue::Color(_) | CapabilityValue::Lock(_) => None,
+    }
+}
+
+/// A sample, or once downsampled a bucket starting at `time`: the time-weighted mean over its `span`,
+/// the range of its samples and the value it ended on, which holds from the end of the span to the next point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistoryPoint {
+    pub time: SystemTime,
+    pub mean: f64,
+    pub min: f64,
+    pub max: f64,
+    pub samples: u32,
+    pub last: f64,
+    /// `None` for a sample, which holds until the next point.
+    pub span: Option<Duration>,
+}
+
+impl HistoryPoint {
+    fn sample(time: SystemTime, value: f64) -> Self {
+        HistoryPoint { time, mean: value, min: value, max: value, samples: 1, last: value, span: None }
+    }
+
+    /// Where the mean stops holding and `last` takes over.
+    fn span_end(&self) -> SystemTime {
+        self.time + self.span.unwrap_or_default()
+    }
+}
+
+/// Downsamples the points of one bucket, from `start` until `end`, weighting every value by how long it held.
+/// `carried` is the value going into the bucket, `None` at the start of the history.
+fn downsample(points: &[HistoryPoint], start: SystemTime, end: SystemTime, carried: Option<f64>) -> HistoryPoint {
+    let time = if carried.is_some() { start } else { points[0].time };
+    let (mut cursor, mut current) = (time, carried);
+    let mut total = 0.0;
+    let (mut min, mut max, mut samples) = (f64::INFINITY, f64::NEG_INFINITY, 0);
+    for point in points {
+        if let Some(value) = current {
+            total += value * point.time.duration_since(cursor).unwrap_or_default().as_secs_f64();
+        }
+        total += point.mean * point.span.unwrap_or_default().as_secs_f64();
+        cursor = point.span_end();
+        current = Some(point.last);
+        min = min.min(point.min);
+        max = max.max(point.max);
+        samples += point.samples;
+    }
+    let last = points[points.len() - 1].last;
+    total += last * end.duration_since(cursor).unwrap_or_default().as_secs_f64();
+    let span = end.duration_since(time).unwrap_or_default();
+    let mean = if span.is_zero() { last } else { total / span.as_secs_f64() };
+    HistoryPoint { time, mean, min, max, samples, last, span: Some(span) }
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// A history point in the file, the range, count, last value and span left out for single samples.
+#[derive(Serialize, Deserialize)]
+struct Line {
+    t: u64,
+    p: String,
+    m: String,
+    v: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lo: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hi: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    n: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    l: Option<f64>,
+    /// Span of a bucket in milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    s: Option<u64>,
+}
+
+impl Line {
+    fn new(path: &str, metric: Metric, point: &HistoryPoint) -> Self {
+        let bucket = point.span.is_some();
+        Line {
+            t: millis(point.time),
+            p: path.to_string(),
+            m: metric.to_string(),
+            v: point.mean,
+            lo: bucket.then_some(point.min),
+            hi: bucket.then_some(point.max),
+            n: bucket.then_some(point.samples),
+            l: bucket.then_some(point.last),
+            s: point.span.map(|span| span.as_millis() as u64),
+        }
+    }
+
+    fn point(&self) -> HistoryPoint {
+        HistoryPoint {
+            time: UNIX_EPOCH + Duration::from_millis(self.t),
+            mean: self.v,
+            min: self.lo.unwrap_or(self.v),
+            max: self.hi.unwrap_or(self.v),
+            samples: self.n.unwrap_or(1),
+            last: self.l.unwrap_or(self.v),
+            span: self.s.map(Duration::from_millis),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Latest {
+    path: String,
+    time: u64,
+    value: CapabilityValue,
+}
+
+#[derive(Default)]
+struct Inner {
+    latest: BTreeMap<(String, Capability), (SystemTime, CapabilityValue)>,
+    latest_changed: bool,
+    history: BTreeMap<(String, Metric), Vec<HistoryPoint>>,
+    /// Points not yet appended to the history file.
+    unsaved: Vec<(String, Metric, HistoryPoint)>,
+}
+
+/// Remembers the last value of every capability across restarts, and keeps a history of them and of presence.
+///
+/// Everything lives in a directory: the last values in `latest.json`, the history appended to `history.jsonl`.
+/// Samples older than `raw_retention` are downsampled into buckets, and anything older than `retention` dropped.
+pub struct StateStore {
+    dir: PathBuf,
+    config: StoreConfig,
+    inner: Mutex<Inner>,
+}
+
+impl StateStore {
+    /// Opens the store in `dir`, creating it if needed, and compacts what was kept last time.
+    pub fn open(dir: impl AsRef<Path>, config: StoreConfig) -> Result<Self, Box<dyn Error>> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(|e| format!("Failed to create state store {}: {}", dir.display(), e))?;
+        let mut inner = Inner::default();
+
+        let latest = dir.join(LATEST_FILE);
+        if latest.exists() {
+            let stored: Vec<Latest> = serde_json::from_str(&fs::read_to_string(&latest)?)
+                .map_err(|e| format!("Invalid {}: {}", latest.display(), e))?;
+            for Latest { path, time, value } in stored {
+                inner.latest.insert((path, value.capability()), (UNIX_EPOCH + Duration::from_millis(time), value));
+            }
+        }
+
+        let history = dir.join(HISTORY_FILE);
+        if history.exists() {
+            for (number, line) in BufReader::new(File::open(&history)?).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                // a line cut short by a crash loses just that point
+                let Ok(line) = serde_json::from_str::<Line>(&line) else {
+                    log::warn!("{}:{}: skipping unreadable history", history.display(), number + 1);
+                    continue;
+                };
+                let metric = line.m.parse().map_err(|e| format!("{}:{}: {}", history.display(), number + 1, e))?;
+                inner.history.entry((line.p.clone(), metric)).or_default().push(line.point());
+            }
+        }
+
+        let store = StateStore { dir, config, inner: Mutex::new(inner) };
+        store.compact(domus_core::now())?;
+        Ok(store)
+    }
+
+    pub fn record(&self, path: &str, value: CapabilityValue, time: SystemTime) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.latest.insert((path.to_string(), value.capability()), (time, value));
+        inner.latest_changed = true;
+        if let Some(numeric) = numeric(&value) {
+            inner.push(path, Metric::Capability(value.capability()), HistoryPoint::sample(time, numeric));
+        }
+    }
+
+    /// The directory the store keeps its files in, for others that keep state next to it.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn record_presence(&self, path: &str, probability: f32, time: SystemTime) {
+        self.inner.lock().unwrap().push(path, Metric::Presence, HistoryPoint::sample(time, probability.into()));
+    }
+
+    /// Hands every device from `root` down whose path passes `filter` the values it last reported,
+    /// returning how many were restored.
+    pub fn restore_where(&self, root: &dyn Space, filter: impl Fn(&str) -> bool) -> usize {
+        let inner = self.inner.lock().unwrap();
+        let mut restored = 0;
+        for visit in root.walk_devices().into_iter().filter(|visit| filter(&visit.path)) {
+            let values = inner.latest.range((visit.path.clone(), Capability::ALL[0])..)
+                .take_while(|((path, _), _)| *path == visit.path);
+            for ((_, capability), (_, value)) in values {
+                if visit.device.has_capability(*capability) {
+                    visit.device.restore(*value);
+                    restored += 1;
+                }
+            }
+        }
+        restored
+    }
+
+    /// The history of a metric since `since`, oldest first.
+    pub fn history(&self, path: &str, metric: Metric, since: SystemTime) -> Vec<HistoryPoint> {
+        let inner = self.inner.lock().unwrap();
+        inner.history.get(&(path.to_string(), metric)).into_iter()
+            .flatten()
+            .filter(|point| point.time >= since)
+            .copied()
+            .collect()
+    }
+
+    /// Average of a metric from `since` to `until`, each value counting for as long as it held,
+    /// e.g. the share of the last week the office was occupied. `None` without any history before `until`.
+    pub fn time_average(&self, path: &str, metric: Metric, since: SystemTime, until: SystemTime) -> Option<f64> {
+        let inner = self.inner.lock().unwrap();
+        let points = inner.history.get(&(path.to_string(), metric))?;
+        // the value going into the window is the last one before it
+        let first = points.iter().rposition(|point| point.time <= since).unwrap_or(0);
+        let mut total = 0.0;
+        let mut covered = Duration::ZERO;
+        for (i, point) in points.iter().enumerate().skip(first) {
+            let next = points.get(i + 1).map_or(until, |next| next.time);
+            // a bucket's mean over its span, spread evenly, then the value it ended on
+            for (start, end, value) in [(point.time, point.span_end(), point.mean), (point.span_end(), next, point.last)] {
+                let Ok(held) = end.min(until).duration_since(start.max(since)) else { continue };
+                total += value * held.as_secs_f64();
+                covered += held;
+            }
+        }
+        (!covered.is_zero()).then(|| total / covered.as_secs_f64())
+    }
+
+    /// Writes out the changes since the last flush.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.latest_changed {
+            let latest: Vec<_> = inner.latest.iter()
+                .map(|((path, _), (time, value))| Latest { path: path.clone(), time: millis(*time), value: *value })
+                .collect();
+            self.replace(LATEST_FILE, |file| Ok(serde_json::to_writer(file, &latest)?))?;
+            inner.latest_changed = false;
+        }
+        if !inner.unsaved.is_empty() {
+            let mut file = BufWriter::new(OpenOptions::new().create(true).append(true).open(self.dir.join(HISTORY_FILE))?);
+            for (path, metric, point) in &inner.unsaved {
+                serde_json::to_writer(&mut file, &Line::new(path, *metric, point))?;
+                file.write_all(b"\n")?;
+            }
+            file.flush()?;
+            inner.unsaved.clear();
+        }
+        Ok(())
+    }
+
+    /// Downsamples what is past the raw retention, drops what is past the retention and rewrites the history.
+    pub fn compact(&self, now: SystemTime) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let raw_since = now.checked_sub(self.config.raw_retention).unwrap_or(UNIX_EPOCH);
+        let keep_since = now.checked_sub(self.config.retention).unwrap_or(UNIX_EPOCH);
+        let bucket = self.config.bucket.as_millis().max(1) as u64;
+
+        let bucket_start = |time: SystemTime| UNIX_EPOCH + Duration::from_millis(millis(time) / bucket * bucket);
+        for points in inner.history.values_mut() {
+            points.retain(|point| point.time >= keep_since);
+            let old = points.partition_point(|point| point.time < raw_since);
+            let mut compacted: Vec<HistoryPoint> = Vec::with_capacity(points.len());
+            let mut i = 0;
+            while i < old {
+                let start = bucket_start(points[i].time);
+                let count = points[i..old].iter().take_while(|point| bucket_start(point.time) == start).count();
+                // the bucket lasts until the next point, or as long as it is
+                let end = points.get(i + count).map_or(now, |next| next.time).min(start + self.config.bucket);
+                let carried = compacted.last().map(|previous| previous.last);
+                compacted.push(downsample(&points[i..i + count], start, end, carried));
+                i += count;
+            }
+            compacted.extend_from_slice(&points[old..]);
+            *points = compacted;
+        }
+        inner.history.retain(|_, points| !points.is_empty());
+
+        self.replace(HISTORY_FILE, |file| {
+            for ((path, metric), points) in &inner.history {
+                for point in points {
+                    serde_json::to_writer(&mut *file, &Line::new(path, *metric, point))?;
+                    file.write_all(b"\n")?;
+                }
+            }
+            Ok(())
+        })?;
+        inner.unsaved.clear();
+        Ok(())
+    }
+
+    /// Writes a file of the store through a temporary one, so a crash leaves either the old or the new version.
+    fn replace(&self, name: &str, write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) -> io::Result<()> {
+        let path = self.dir.join(name);
+        let temporary = path.with_extension("tmp");
+        let mut file = BufWriter::new(File::create(&temporary)?);
+        write(&mut file)?;
+        file.into_inner()?.sync_all()?;
+        fs::rename(temporary, path)
+    }
+
+    /// Records what happens on the bus until cancelled, writing it out every `flush_interval`.
+    pub async fn track(&self, events: &EventBus, cancel: CancellationToken) {
+        let mut changes = events.subscribe(EventFilter::all());
+        let mut flushes = tokio::time::interval(self.config.flush_interval);
+        let mut compacted = domus_core::now();
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                event = changes.recv() => match event {
+                    Some(event) => match event.kind {
+                        EventKind::CapabilityChanged { value, .. } => self.record(&event.path, value, event.time),
+                        EventKind::PresenceChanged { probability, .. } => self.record_presence(&event.path, probability, event.time),
+                        _ => {},
+                    },
+                    None => break,
+                },
+                _ = flushes.tick() => {
+                    let now = domus_core::now();
+                    let result = if now.duration_since(compacted).unwrap_or_default() >= COMPACT_INTERVAL {
+                        compacted = now;
+                        self.flush().and_then(|()| self.compact(now))
+                    } else {
+                        self.flush()
+                    };
+                    if let Err(error) = result {
+                        log::error!("Writing the state store failed: {}", error);
+                    }
+                },
+            }
+        }
+        if let Err(error) = self.flush() {
+            log::error!("Writing the state store failed: {}", error);
+        }
+    }
+}
+
+impl Inner {
+    fn push(&mut self, path: &str, metric: Metric, point: HistoryPoint) {
+        self.history.entry((path.to_string(), metric)).or_default().push(point);
+        self.unsaved.push((path.to_string(), metric, point));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use driver::SimMotionSensor;
+    use macros::domus;
+
+    #[test]
+    fn test_state_store() {
+        let dir = std::env::temp_dir().join(format!("domus-store-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let now = SystemTime::now();
+        let ago = |hours: u64| now - Duration::from_secs(hours * 3600);
+        let occupancy = Metric::Capability(Capability::Occupancy);
+
+        let store = StateStore::open(&dir, StoreConfig::default()).unwrap();
+        // occupied for the first of the last four days, vacant since
+        store.record("office/motion_sensor", CapabilityValue::Occupancy(true), ago(96));
+        store.record("office/motion_sensor", CapabilityValue::Occupancy(false), ago(72));
+        store.record("office/motion_sensor", CapabilityValue::Color(domus_core::HueSaturation { hue: 0.0, saturation: 0.0 }), ago(1));
+        store.record_presence("office", 0.8, ago(3));
+        store.record_presence("office", 0.6, ago(3) + Duration::from_secs(60));
+        store.flush().unwrap();
+
+        let average = store.time_average("office/motion_sensor", occupancy, ago(96), now).unwrap();
+        assert!((average - 0.25).abs() < 1e-9);
+        assert_eq!(store.time_average("office/motion_sensor", occupancy, ago(100), ago(99)), None);
+        assert_eq!(store.history("office", Metric::Presence, ago(7 * 24)).len(), 2);
+
+        // reopening restores the last values and compacts: both old occupancy samples are past the raw retention
+        drop(store);
+        let store = StateStore::open(&dir, StoreConfig::default()).unwrap();
+        let apartment = domus! {
+            name: "Apartment",
+            office: Space {
+                name: "Office",
+                motion_sensor: SimMotionSensor { name: "Office motion sensor" }
+            }
+        };
+        assert_eq!(store.restore_where(&apartment, |_| true), 1);
+        assert_eq!(apartment.office.motion_sensor.state.occupied(), Some(false));
+        let history = store.history("office/motion_sensor", occupancy, ago(7 * 24));
+        assert_eq!(history.len(), 2);
+        assert!(history[0].time <= ago(96) && history[0].samples == 1);
+
+        store.compact(now + Duration::from_secs(365 * 86400)).unwrap();
+        assert!(store.history("office", Metric::Presence, UNIX_EPOCH).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compacted_time_average() {
+        let dir = std::env::temp_dir().join(format!("domus-compact-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        // on a bucket boundary, a few hours back so reopening compacts it again
+        let start = UNIX_EPOCH + Duration::from_secs(millis(SystemTime::now()) / 1000 / 900 * 900 - 3 * 3600);
+        let at = |minutes: u64| start + Duration::from_secs(minutes * 60);
+        let occupancy = Metric::Capability(Capability::Occupancy);
+        let config = StoreConfig { raw_retention: Duration::from_secs(3600), ..Default::default() };
+
+        let store = StateStore::open(&dir, config).unwrap();
+        for (minutes, occupied) in [(1, true), (2, false), (20, true), (50, false)] {
+            store.record("office/motion_sensor", CapabilityValue::Occupancy(occupied), at(minutes));
+        }
+        // occupied for 31 of the 69 minutes since the first sample
+        let average = |store: &StateStore| store.time_average("office/motion_sensor", occupancy, at(0), at(70)).unwrap();
+        assert!((average(&store) - 31.0 / 69.0).abs() < 1e-9);
+
+        // the same once every sample is downsampled into 15 minute buckets, and again after reopening
+        store.compact(at(120)).unwrap();
+        assert_eq!(store.history("office/motion_sensor", occupancy, UNIX_EPOCH).len(), 3);
+        assert!((average(&store) - 31.0 / 69.0).abs() < 1e-9);
+        drop(store);
+        let store = StateStore::open(&dir, config).unwrap();
+        assert!((average(&store) - 31.0 / 69.0).abs() < 1e-9);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,7 +1,16 @@
-use core::{DiscoveryInfo, DeviceProperties, Device, Driver, LifeCycle};
-use std::time::Duration;
-use crate::hap::{HapAccessory, HapClient, HapDiscovery};
-use log::{info, error};
+use domus_core::{Capabilities, Capability, CapabilityState, CapabilityValue, Controllable, DiscoveryInfo, DiscoveryOptions, DiscoveryStream, DeviceProperties, Device, Driver, DriverInfo, EventPublisher, Health, Heartbeat, Illuminance, LifeCycle, LocalBoxFuture, Occupancy, discovery_stream};
+use futures_util::StreamExt;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+use crate::hap::{HapAccessory, HapClient, HapDiscovery, HapPairing, HapSession, short_type, writable_characteristics};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use log::{info, error, warn};
 
 /* 
 enum Category {
@@ -67,6 +76,7 @@ format!("ci={}", self.category as u8),
 discovery() -> impl DeviceDiscovery
 pair(DeviceDiscovery) -> impl DeviceProperties
 attach(DeviceProperties) -> impl Device
+unpair(DeviceProperties)
 */
 
 pub struct AqaraFP2Discovery {
@@ -86,41 +96,215 @@ impl DiscoveryInfo for AqaraFP2Discovery {
 
 // device properties
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AqaraFP2Properties {
+    pub driver: String,
+    pub id: String,
+    pub name: String,
+    pub address: IpAddr,
+    pub port: u16,
+    /// The keys from pairing, secret: the record has to be kept private.
+    pub pairing: HapPairing,
+}
+
+impl DeviceProperties for AqaraFP2Properties {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn driver(&self) -> &str {
+        &self.driver
+    }
+
+    fn address(&self) -> Option<SocketAddr> {
+        Some(SocketAddr::new(self.address, self.port))
+    }
+
+    fn pairing(&self) -> Option<&str> {
+        Some(&self.pairing.accessory_id)
+    }
+}
+
+
+// link, the HAP session that keeps the sensor's state and heartbeat up to date
+
+/// How long the sensor may stay quiet before its values are read to check it is still there.
+const KEEPALIVE: Duration = Duration::from_secs(60);
+const RECONNECT_MIN: Duration = Duration::from_secs(5);
+const RECONNECT_MAX: Duration = Duration::from_secs(300);
+
+/// HAP characteristic types, by their short UUID.
+const OCCUPANCY_DETECTED: u32 = 0x71;
+const CURRENT_AMBIENT_LIGHT_LEVEL: u32 = 0x6B;
+
+/// The readings the sensor reports, by accessory and instance id. The FP2 has an occupancy sensor per zone.
+#[derive(Default)]
+struct Readings {
+    occupancy: BTreeMap<(u64, u64), bool>,
+    light_level: Option<(u64, u64)>,
+}
+
+impl Readings {
+    /// Finds the characteristics to follow in the `/accessories` listing, taking their current values along.
+    fn from_accessories(listing: &Value, state: &CapabilityState) -> Result<Self, Box<dyn Error>> {
+        let mut readings = Readings::default();
+        let mut values = Vec::new();
+        for accessory in listing["accessories"].as_array().ok_or("Accessory listing without accessories")? {
+            let Some(aid) = accessory["aid"].as_u64() else { continue };
+            let characteristics = accessory["services"].as_array().into_iter().flatten()
+                .flat_map(|service| service["characteristics"].as_array().into_iter().flatten());
+            for characteristic in characteristics {
+                let (Some(iid), Some(kind)) = (characteristic["iid"].as_u64(), characteristic["type"].as_str().and_then(short_type)) else { continue };
+                match kind {
+                    OCCUPANCY_DETECTED => { readings.occupancy.insert((aid, iid), false); },
+                    CURRENT_AMBIENT_LIGHT_LEVEL => readings.light_level = Some((aid, iid)),
+                    _ => continue,
+                }
+                values.push(serde_json::json!({ "aid": aid, "iid": iid, "value": characteristic["value"] }));
+            }
+        }
+        if readings.occupancy.is_empty() {
+            return Err("The accessory has no occupancy sensor".into());
+        }
+        readings.apply(&Value::Array(values), state);
+        Ok(readings)
+    }
+
+    fn ids(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.occupancy.keys().copied().chain(self.light_level)
+    }
+
+    /// Takes new values as sent in events and in reads, `[{"aid": 1, "iid": 10, "value": 1}, ...]`.
+    fn apply(&mut self, characteristics: &Value, state: &CapabilityState) {
+        let mut occupancy_changed = false;
+        for characteristic in characteristics.as_array().into_iter().flatten() {
+            let (Some(aid), Some(iid)) = (characteristic["aid"].as_u64(), characteristic["iid"].as_u64()) else { continue };
+            let value = &characteristic["value"];
+            if let Some(occupied) = self.occupancy.get_mut(&(aid, iid)) {
+                *occupied = value.as_u64().map(|value| value != 0).or(value.as_bool()).unwrap_or(*occupied);
+                occupancy_changed = true;
+            } else if self.light_level == Some((aid, iid)) && let Some(lux) = value.as_f64() {
+                state.set(CapabilityValue::Illuminance(lux as f32));
+            }
+        }
+        // the room is occupied when any of its zones is
+        if occupancy_changed {
+            state.set(CapabilityValue::Occupancy(self.occupancy.values().any(|occupied| *occupied)));
+        }
+    }
+}
+
+/// Verifies a session, subscribes to the readings and follows them until the session fails.
+async fn follow(address: SocketAddr, pairing: &HapPairing, state: &CapabilityState, heartbeat: &Heartbeat, backoff: &mut Duration) -> Result<(), Box<dyn Error>> {
+    let mut session = HapSession::verify(address.ip(), address.port(), pairing).await?;
+    let listing = session.request("GET", "/accessories", "application/hap+json", &[]).await?;
+    let mut readings = Readings::from_accessories(&serde_json::from_slice(&listing.body)?, state)?;
+
+    let subscriptions: Vec<_> = readings.ids().map(|(aid, iid)| serde_json::json!({ "aid": aid, "iid": iid, "ev": true })).collect();
+    let body = serde_json::to_vec(&serde_json::json!({ "characteristics": subscriptions }))?;
+    let response = session.request("PUT", "/characteristics", "application/hap+json", &body).await?;
+    if response.status() != 204 {
+        return Err(format!("Accessory refused the subscription: {}", response.start).into());
+    }
+    heartbeat.seen();
+    *backoff = RECONNECT_MIN;
+
+    let ids = readings.ids().map(|(aid, iid)| format!("{}.{}", aid, iid)).collect::<Vec<_>>().join(",");
+    loop {
+        let event = match tokio::time::timeout(KEEPALIVE, session.next_event()).await {
+            Ok(event) => Some(event?),
+            Err(_) => None,
+        };
+        let message = match event {
+            Some(event) => event,
+            None => session.request("GET", &format!("/characteristics?id={}", ids), "application/hap+json", &[]).await?,
+        };
+        let body: Value = serde_json::from_slice(&message.body)?;
+        readings.apply(&body["characteristics"], state);
+        heartbeat.seen();
+    }
+}
+
+/// Keeps following the sensor, reconnecting with a growing delay while it can't be reached.
+async fn link(name: String, address: SocketAddr, pairing: HapPairing, state: Arc<CapabilityState>, heartbeat: Arc<Heartbeat>) {
+    let mut backoff = RECONNECT_MIN;
+    loop {
+        if let Err(error) = follow(address, &pairing, &state, &heartbeat, &mut backoff).await.map_err(|e| e.to_string()) {
+            warn!("Lost {} at {}, retrying in {}s: {}", name, address, backoff.as_secs(), error);
+            heartbeat.lost(&error);
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(RECONNECT_MAX);
+    }
+}
+
+// device
+
 #[derive(Debug)]
 pub struct AqaraFP2 {
-    pub name: &'static str,
-    pub ip: &'static str
+    pub name: String,
+    pub ip: String,
+    /// Path of the device record `disco pair` wrote, read on init when there is no `connection`,
+    /// e.g. `record: "AABBCCDDEEFF.toml"` in `domus!`. Empty for none.
+    pub record: PathBuf,
+    pub state: Arc<CapabilityState>,
+    /// Fed by the readings the sensor sends over its HAP session, and marked lost when the session drops.
+    pub heartbeat: Arc<Heartbeat>,
+    /// Where the sensor is and the keys to verify a session with, from its device record.
+    /// Without them, or a `record` to read them from, the sensor can't be reached and reports as not paired.
+    pub connection: Option<(SocketAddr, HapPairing)>,
+    /// The task following the sensor, while initialized.
+    pub link: Mutex<Option<JoinHandle<()>>>,
 }
 
-impl DeviceProperties for AqaraFP2 {
+impl Default for AqaraFP2 {
+    fn default() -> Self {
+        AqaraFP2 {
+            name: String::new(),
+            ip: String::new(),
+            record: PathBuf::new(),
+            state: Arc::default(),
+            heartbeat: Arc::new(Heartbeat::new(KEEPALIVE)),
+            connection: None,
+            link: Mutex::new(None),
+        }
+    }
 }
 
-impl std::fmt::Display for AqaraFP2 {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, r#"
-AqaraFP2 {{ 
-    name: \"{}\",
-    ip: \"{}\",
-}}
-"#, 
-self.name, 
-self.ip)
+impl AqaraFP2 {
+    /// The attached connection, or the one in the device record. `None` if the sensor isn't paired.
+    fn connection(&self) -> Result<Option<(SocketAddr, HapPairing)>, Box<dyn Error>> {
+        if self.connection.is_some() || self.record.as_os_str().is_empty() {
+            return Ok(self.connection.clone());
+        }
+        let properties = AqaraFP2Properties::load(&self.record)?;
+        Ok(Some((SocketAddr::new(properties.address, properties.port), properties.pairing)))
     }
 }
 
-
 impl LifeCycle for AqaraFP2 {
     async fn init(&self) -> Result<(), Box<dyn std::error::Error>> {
-        // Implement initialization logic for AqaraFP2 device
         log::info!("Initializing AqaraFP2 device: {}", self.name);
-        // Add actual initialization code here
+        let Some((address, pairing)) = self.connection()? else {
+            self.heartbeat.lost("Not paired");
+            return Ok(());
+        };
+        let link = tokio::spawn(link(self.name.clone(), address, pairing, self.state.clone(), self.heartbeat.clone()));
+        if let Some(previous) = self.link.lock().unwrap().replace(link) {
+            previous.abort();
+        }
         Ok(())
     }
 
     async fn dispose(&self) -> Result<(), Box<dyn std::error::Error>> {
-        // Implement disposal logic for AqaraFP2 device
-        println!("Disposing AqaraFP2 device: {}", self.name);
-        // Add actual disposal code here
+        log::info!("Disposing AqaraFP2 device: {}", self.name);
+        if let Some(link) = self.link.lock().unwrap().take() {
+            link.abort();
+        }
         Ok(())
     }
 }
@@ -128,42 +312,108 @@ impl LifeCycle for AqaraFP2 {
 
 
 impl Device for AqaraFP2 {
+    fn bind_events(&self, publisher: EventPublisher) {
+        self.state.bind(publisher);
+    }
 
+    fn health(&self) -> Health {
+        self.heartbeat.health(SystemTime::now())
+    }
+
+    fn last_seen(&self) -> Option<SystemTime> {
+        self.heartbeat.last_seen()
+    }
+
+    /// Only the state, a remembered value doesn't mean the sensor is around.
+    fn restore(&self, value: CapabilityValue) {
+        self.state.set(value);
+    }
+}
+
+impl Occupancy for AqaraFP2 {
+    fn occupied(&self) -> Option<bool> {
+        self.state.occupied()
+    }
+}
+
+impl Illuminance for AqaraFP2 {
+    fn illuminance(&self) -> Option<f32> {
+        self.state.illuminance()
+    }
+}
+
+impl Controllable for AqaraFP2 {
+    /// Writes the target through a session of its own, next to the one the readings are followed on.
+    fn execute(&self, target: CapabilityValue) -> LocalBoxFuture<'_, Result<(), Box<dyn Error>>> {
+        Box::pin(async move {
+            let (address, pairing) = self.connection()?.ok_or("Not paired")?;
+            let mut session = HapSession::verify(address.ip(), address.port(), &pairing).await?;
+            let listing = session.request("GET", "/accessories", "application/hap+json", &[]).await?;
+            let characteristics = writable_characteristics(&serde_json::from_slice(&listing.body)?);
+            session.set(&characteristics, target).await
+        })
+    }
+}
+
+impl Capabilities for AqaraFP2 {
+    fn as_occupancy(&self) -> Option<&dyn Occupancy> {
+        Some(self)
+    }
+
+    fn as_illuminance(&self) -> Option<&dyn Illuminance> {
+        Some(self)
+    }
+
+    fn as_controllable(&self) -> Option<&dyn Controllable> {
+        Some(self)
+    }
 }
 
 
 pub struct AqaraFP2Driver {}
 
 impl AqaraFP2Driver {
+    pub const NAME: &'static str = "aqarafp2";
+
+    pub const INFO: DriverInfo = DriverInfo {
+        name: Self::NAME,
+        description: "Aqara FP2 presence sensor, over HomeKit (HAP)",
+        capabilities: &[Capability::Occupancy, Capability::Illuminance],
+    };
+
     pub fn new() -> Self {
         AqaraFP2Driver {}
     }
 }
 
 
-impl Driver<AqaraFP2Discovery, AqaraFP2, AqaraFP2> for AqaraFP2Driver {
-    async fn discover(&self) -> Vec<AqaraFP2Discovery> {
-        let hap_discovery = HapDiscovery::new().expect("Failed to create Hap discovery");
-        let accessories = hap_discovery.start_discovery(true, Duration::from_secs(5)).expect("Failed to start discovery");
+impl Driver<AqaraFP2Discovery, AqaraFP2Properties, AqaraFP2> for AqaraFP2Driver {
+    async fn discover(&self, options: DiscoveryOptions) -> Result<DiscoveryStream<AqaraFP2Discovery>, Box<dyn std::error::Error>> {
+        let hap_discovery = HapDiscovery::new()?;
+        let accessories = hap_discovery.start_discovery(true, options)?;
 
-        accessories.into_iter()
-            .filter(|accessory| accessory.model == "PS-S02D") // Filter for Aqara FP2 model
+        Ok(discovery_stream(accessories)
+            .filter(|accessory| std::future::ready(accessory.model == "PS-S02D")) // Filter for Aqara FP2 model
             .map(|accessory| AqaraFP2Discovery { hap_accessory: accessory })
-            .collect()
+            .boxed())
     }
     
-    async fn pair(&self, discovery: &AqaraFP2Discovery) -> Result<AqaraFP2, Box<dyn std::error::Error>> {
+    async fn pair(&self, discovery: &AqaraFP2Discovery) -> Result<AqaraFP2Properties, Box<dyn std::error::Error>> {
 
 
         info!("Starting pairing process for Aqara FP2 device: {}", discovery.name());
         let mut client = HapClient::new();
 
         match client.pair(&discovery.hap_accessory, "24637337").await {
-            Ok(_) => {
+            Ok(pairing) => {
                 info!("Successfully paired with Aqara FP2 device: {}", discovery.name());
-                Ok(AqaraFP2 {
-                    name: "",
-                    ip: "",
+                Ok(AqaraFP2Properties {
+                    driver: Self::NAME.to_string(),
+                    id: discovery.id().to_string(),
+                    name: discovery.name().to_string(),
+                    address: discovery.hap_accessory.address,
+                    port: discovery.hap_accessory.port,
+                    pairing,
                 })
             },
             Err(e) => {
@@ -173,10 +423,87 @@ impl Driver<AqaraFP2Discovery, AqaraFP2, AqaraFP2> for AqaraFP2Driver {
         }
     }
 
-    /*
-    async fn attach(&self, device: &AqaraFP2) -> Result<AqaraFP2, Box<dyn std::error::Error>> {
-        // Implement attachment logic
-        todo!("Implement attachment logic")
+    async fn attach(&self, properties: &AqaraFP2Properties) -> Result<AqaraFP2, Box<dyn std::error::Error>> {
+        info!("Attaching Aqara FP2 device: {} (id: {})", properties.name, properties.id);
+        Ok(AqaraFP2 {
+            name: properties.name.clone(),
+            ip: properties.address.to_string(),
+            connection: Some((SocketAddr::new(properties.address, properties.port), properties.pairing.clone())),
+            ..Default::default()
+        })
+    }
+
+    async fn unpair(&self, properties: &AqaraFP2Properties) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Unpairing Aqara FP2 device: {} (id: {})", properties.name, properties.id);
+        let mut client = HapClient::new();
+
+        client.unpair(properties.address, properties.port, &properties.pairing).await.map_err(|e| {
+            error!("Failed to unpair Aqara FP2 device: {}. Error: {}", properties.name, e);
+            e
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_properties_round_trip() {
+        let properties = AqaraFP2Properties {
+            driver: AqaraFP2Driver::NAME.to_string(),
+            id: "AA:BB:CC:DD:EE:FF".to_string(),
+            name: "PS-S02D".to_string(),
+            address: "192.168.22.51".parse().unwrap(),
+            port: 56431,
+            pairing: HapPairing {
+                accessory_id: "AA:BB:CC:DD:EE:FF".to_string(),
+                accessory_ltpk: [7; 32],
+                controller_id: "Domus".to_string(),
+                controller_ltsk: [42; 32],
+            },
+        };
+
+        let text = properties.to_toml().unwrap();
+        let loaded = AqaraFP2Properties::from_toml(&text).unwrap();
+
+        assert_eq!(loaded.driver, "aqarafp2");
+        assert_eq!(loaded.id, properties.id);
+        assert_eq!(loaded.address(), Some("192.168.22.51:56431".parse().unwrap()));
+        assert_eq!(loaded.pairing, properties.pairing);
+        assert_eq!(loaded.pairing(), Some("AA:BB:CC:DD:EE:FF"));
+        assert!(text.contains(&"2a".repeat(32)));
+
+        // a sensor declared in code finds its connection in the record
+        let path = std::env::temp_dir().join(format!("domus-fp2-{}.toml", std::process::id()));
+        properties.save(&path).unwrap();
+        let sensor = AqaraFP2 { record: path.clone(), ..Default::default() };
+        assert_eq!(sensor.connection().unwrap(), Some(("192.168.22.51:56431".parse().unwrap(), properties.pairing.clone())));
+        std::fs::remove_file(&path).unwrap();
+        assert!(sensor.connection().is_err());
+        assert_eq!(AqaraFP2::default().connection().unwrap(), None);
+    }
+
+    #[test]
+    fn test_readings() {
+        let listing = serde_json::json!({ "accessories": [{ "aid": 1, "services": [
+            { "iid": 1, "type": "3E", "characteristics": [{ "iid": 2, "type": "23", "value": "Presence-Sensor-FP2" }] },
+            { "iid": 10, "type": "86", "characteristics": [{ "iid": 11, "type": "00000071-0000-1000-8000-0026BB765291", "value": 0 }] },
+            { "iid": 20, "type": "86", "characteristics": [{ "iid": 21, "type": "71", "value": 1 }] },
+            { "iid": 30, "type": "84", "characteristics": [{ "iid": 31, "type": "6B", "value": 120.5 }] },
+        ] }] });
+        let state = CapabilityState::default();
+        let mut readings = Readings::from_accessories(&listing, &state).unwrap();
+        assert_eq!(readings.ids().collect::<Vec<_>>(), vec![(1, 11), (1, 21), (1, 31)]);
+        assert_eq!(state.occupied(), Some(true));
+        assert_eq!(state.illuminance(), Some(120.5));
+
+        // one zone emptying leaves the room occupied, the last one empties it
+        readings.apply(&serde_json::json!([{ "aid": 1, "iid": 21, "value": 0 }, { "aid": 1, "iid": 11, "value": 1 }]), &state);
+        assert_eq!(state.occupied(), Some(true));
+        readings.apply(&serde_json::json!([{ "aid": 1, "iid": 11, "value": 0 }]), &state);
+        assert_eq!(state.occupied(), Some(false));
+
+        assert!(Readings::from_accessories(&serde_json::json!({ "accessories": [] }), &state).is_err());
     }
-     */
 }
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use domus_core::{CapabilityValue, LockState};
+use serde::Serialize;
+use serde_json::Value;
+
+/// The HAP characteristics capabilities are written through, by their short UUID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CharacteristicType {
+    On = 0x25,
+    Brightness = 0x08,
+    ColorTemperature = 0xCE,
+    Hue = 0x13,
+    Saturation = 0x2F,
+    TargetPosition = 0x7C,
+    LockTargetState = 0x1E,
+}
+
+impl CharacteristicType {
+    pub fn from_short(short: u32) -> Option<Self> {
+        let kind = match short {
+            0x25 => CharacteristicType::On,
+            0x08 => CharacteristicType::Brightness,
+            0xCE => CharacteristicType::ColorTemperature,
+            0x13 => CharacteristicType::Hue,
+            0x2F => CharacteristicType::Saturation,
+            0x7C => CharacteristicType::TargetPosition,
+            0x1E => CharacteristicType::LockTargetState,
+            _ => return None,
+        };
+        Some(kind)
+    }
+}
+
+/// Short form of a characteristic type, given as `71` or as the full `00000071-0000-1000-8000-0026BB765291`.
+pub fn short_type(uuid: &str) -> Option<u32> {
+    u32::from_str_radix(uuid.split('-').next()?, 16).ok()
+}
+
+/// Where a characteristic lives on an accessory: accessory id and instance id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CharacteristicAddress {
+    pub aid: u64,
+    pub iid: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CharacteristicWrite {
+    #[serde(flatten)]
+    pub address: CharacteristicAddress,
+    pub value: Value,
+}
+
+/// Translates a capability value into the characteristic values that set it.
+///
+/// Colour needs two characteristics, hue and saturation, everything else maps to one.
+pub fn characteristic_values(target: CapabilityValue) -> Option<Vec<(CharacteristicType, Value)>> {
+    let values = match target {
+        CapabilityValue::OnOff(on) => vec![(CharacteristicType::On, Value::from(on))],
+        CapabilityValue::Brightness(percent) => vec![(CharacteristicType::Brightness, Value::from(percent.min(100)))],
+        // HAP takes mireds, 140-500
+        CapabilityValue::ColorTemperature(kelvin) => {
+            let mireds = (1_000_000 / u32::from(kelvin.max(1))).clamp(140, 500);
+            vec![(CharacteristicType::ColorTemperature, Value::from(mireds))]
+        },
+        CapabilityValue::Color(color) => vec![
+            (CharacteristicType::Hue, Value::from(color.hue.clamp(0.0, 360.0))),
+            (CharacteristicType::Saturation, Value::from(color.saturation.clamp(0.0, 100.0))),
+        ],
+        CapabilityValue::WindowCovering(position) => vec![(CharacteristicType::TargetPosition, Value::from(position.min(100)))],
+        CapabilityValue::Lock(LockState::Unlocked) => vec![(CharacteristicType::LockTargetState, Value::from(0))],
+        CapabilityValue::Lock(LockState::Locked) => vec![(CharacteristicType::LockTargetState, Value::from(1))],
+        _ => return None,
+    };
+    Some(values)
+}
+
+/// Finds the characteristics capabilities can be written through in an `/accessories` listing.
+///
+/// Only those the accessory allows writing (`pw` among their permissions), the first of each type.
+pub fn writable_characteristics(listing: &Value) -> HashMap<CharacteristicType, CharacteristicAddress> {
+    let mut writable = HashMap::new();
+    for accessory in listing["accessories"].as_array().into_iter().flatten() {
+        let Some(aid) = accessory["aid"].as_u64() else { continue };
+        let characteristics = accessory["services"].as_array().into_iter().flatten()
+            .flat_map(|service| service["characteristics"].as_array().into_iter().flatten());
+        for characteristic in characteristics {
+            let writable_permission = characteristic["perms"].as_array().into_iter().flatten().any(|perm| perm == "pw");
+            let (Some(iid), Some(kind)) = (characteristic["iid"].as_u64(), characteristic["type"].as_str().and_then(short_type).and_then(CharacteristicType::from_short)) else { continue };
+            if writable_permission {
+                writable.entry(kind).or_insert(CharacteristicAddress { aid, iid });
+            }
+        }
+    }
+    writable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domus_core::HueSaturation;
+
+    #[test]
+    fn test_characteristic_values() {
+        assert_eq!(characteristic_values(CapabilityValue::OnOff(true)), Some(vec![(CharacteristicType::On, Value::from(true))]));
+        assert_eq!(characteristic_values(CapabilityValue::ColorTemperature(2700)), Some(vec![(CharacteristicType::ColorTemperature, Value::from(370))]));
+        assert_eq!(characteristic_values(CapabilityValue::Color(HueSaturation { hue: 120.0, saturation: 50.0 })).unwrap().len(), 2);
+        assert_eq!(characteristic_values(CapabilityValue::Lock(LockState::Jammed)), None);
+        assert_eq!(characteristic_values(CapabilityValue::Temperature(21.5)), None);
+
+        let write = CharacteristicWrite { address: CharacteristicAddress { aid: 1, iid: 10 }, value: Value::from(40) };
+        assert_eq!(serde_json::to_string(&write).unwrap(), r#"{"aid":1,"iid":10,"value":40}"#);
+
+        let listing = serde_json::json!({ "accessories": [{ "aid": 1, "services": [{ "characteristics": [
+            { "iid": 9, "type": "00000025-0000-1000-8000-0026BB765291", "perms": ["pr", "pw", "ev"], "value": false },
+            { "iid": 10, "type": "8", "perms": ["pr", "pw"], "value": 40 },
+            { "iid": 11, "type": "71", "perms": ["pr", "ev"], "value": 0 },
+            { "iid": 12, "type": "7C", "perms": ["pr"], "value": 0 },
+        ] }] }] });
+        assert_eq!(writable_characteristics(&listing), HashMap::from([
+            (CharacteristicType::On, CharacteristicAddress { aid: 1, iid: 9 }),
+            (CharacteristicType::Brightness, CharacteristicAddress { aid: 1, iid: 10 }),
+        ]));
+    }
+}
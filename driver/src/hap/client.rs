@@ -1,6 +1,12 @@
 use std::error::Error;
+use std::net::IpAddr;
+use log::{info, debug};
 use crate::hap::discovery::HapAccessory;
-use crate::hap::pairing::PairSetup;
+use crate::hap::pairing::{HapPairing, PairSetup, PairingMethod, PairingState};
+use crate::hap::session::HapSession;
+use crate::hap::tlv8::{Tlv8Writer, Tlv8Reader, TlvType};
+
+pub const CONTROLLER_ID: &str = "Domus";
 
 pub struct HapClient {
 }
@@ -11,16 +17,41 @@ impl HapClient {
         }
     }
 
-    pub async fn pair(&mut self, accessory: &HapAccessory, setup_code: &str) -> Result<(), Box<dyn Error>> {
+    /// Runs pair-setup, returning the keys to keep for verifying later sessions.
+    pub async fn pair(&mut self, accessory: &HapAccessory, setup_code: &str) -> Result<HapPairing, Box<dyn Error>> {
         println!("Initiating pairing with accessory: {:?}", accessory);
 
         let pair_setup = PairSetup::new();
 
-        let result = pair_setup.pair(accessory, setup_code).await?;
+        let pairing = pair_setup.pair(accessory, setup_code).await?;
 
+        println!("Pairing completed successfully");
+        Ok(pairing)
+    }
 
+    /// Removes our pairing from the accessory, over a pair-verified session since accessories refuse it in plain HTTP.
+    pub async fn unpair(&mut self, address: IpAddr, port: u16, pairing: &HapPairing) -> Result<(), Box<dyn Error>> {
+        info!("Removing pairing {} from accessory at {}:{}", pairing.controller_id, address, port);
+        let mut session = HapSession::verify(address, port, pairing).await?;
 
-        println!("Pairing completed successfully");
+        let mut payload = Tlv8Writer::new();
+        payload.add(TlvType::State, &[PairingState::M1.into()]);
+        payload.add(TlvType::Method, &[PairingMethod::RemovePairing as u8]);
+        payload.add(TlvType::Identifier, pairing.controller_id.as_bytes());
+
+        debug!("Remove pairing payload: {:?}", payload);
+        let response = session.request("POST", "/pairings", "application/pairing+tlv8", &payload.to_vec()).await?;
+        if response.status() != 200 {
+            return Err(format!("Expected 200 OK response, got {}", response.start).into());
+        }
+
+        for (tlv_type, value) in Tlv8Reader::new(&response.body).read()? {
+            if tlv_type == TlvType::Error {
+                return Err(format!("Accessory refused to remove pairing, error code: {:?}", value.first()).into());
+            }
+        }
+
+        info!("Pairing removed");
         Ok(())
     }
 }
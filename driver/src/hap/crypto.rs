@@ -0,0 +1,54 @@
+use std::error::Error;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+use chacha20poly1305::aead::{Aead, Payload};
+use hkdf::Hkdf;
+use sha2::Sha512;
+use crate::hap::tlv8::TlvType;
+
+/// Derives a 32 byte key the way HAP does everywhere, HKDF-SHA-512 with a fixed salt and info per purpose.
+pub(crate) fn derive_key(secret: &[u8], salt: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha512>::new(Some(salt), secret)
+        .expand(info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA-512 length");
+    key
+}
+
+/// A nonce from a message label such as `PS-Msg05`, right-aligned in 12 bytes.
+pub(crate) fn nonce(label: &[u8]) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[12 - label.len()..].copy_from_slice(label);
+    nonce
+}
+
+pub(crate) fn seal(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(key.into())
+        .encrypt(Nonce::from_slice(nonce), Payload { msg: plaintext, aad })
+        .expect("ChaCha20-Poly1305 encryption doesn't fail on in-memory buffers")
+}
+
+pub(crate) fn open(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| "Failed to decrypt, the message was tampered with or the keys don't match".into())
+}
+
+/// The value of a TLV item, if the accessory sent it.
+pub(crate) fn field(items: &[(TlvType, Vec<u8>)], tlv_type: TlvType) -> Option<&[u8]> {
+    items.iter().find(|(t, _)| *t == tlv_type).map(|(_, value)| value.as_slice())
+}
+
+/// Fails with the accessory's error if it sent one, or if it isn't at `state`.
+pub(crate) fn expect_state(items: &[(TlvType, Vec<u8>)], state: u8) -> Result<(), Box<dyn Error>> {
+    if let Some(&[error, ..]) = field(items, TlvType::Error) {
+        return Err(format!("Accessory refused with error code {}", error).into());
+    }
+    match field(items, TlvType::State) {
+        Some(&[actual]) if actual == state => Ok(()),
+        actual => Err(format!("Expected state M{}, got {:?}", state, actual).into()),
+    }
+}
+
+pub(crate) fn key32(value: &[u8], what: &str) -> Result<[u8; 32], Box<dyn Error>> {
+    value.try_into().map_err(|_| format!("{} should be 32 bytes, got {}", what, value.len()).into())
+}
@@ -1,7 +1,8 @@
-use mdns_sd::{ServiceDaemon, ServiceEvent};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent};
 use std::error::Error;
-use std::time::Duration;
-use std::collections::HashMap;
+use std::collections::HashSet;
+use tokio::sync::mpsc;
+use domus_core::DiscoveryOptions;
 use std::io;
 use enumflags2::{bitflags, BitFlags};
 use std::net::IpAddr;
@@ -38,23 +39,49 @@ impl HapDiscovery {
         Ok(HapDiscovery { mdns })
     }
 
-    pub fn start_discovery(&self, ipv4_only: bool, timeout: Duration) -> Result<Vec<HapAccessory>, Box<dyn Error>> {
+    /// Browses for HAP accessories in the background, sending each one the first time it resolves.
+    pub fn start_discovery(self, ipv4_only: bool, options: DiscoveryOptions) -> Result<mpsc::UnboundedReceiver<HapAccessory>, Box<dyn Error>> {
+        if let Some(interface) = &options.interface {
+            self.mdns.disable_interface(IfKind::All)?;
+            self.mdns.enable_interface(IfKind::Name(interface.clone()))?;
+        }
 
         let receiver = self.mdns.browse(HAP_SERVICE_TYPE)?;
-        let mut accessories = HashMap::new();
+        let (sender, accessories) = mpsc::unbounded_channel();
+        let mdns = self.mdns;
+
+        tokio::spawn(async move {
+            let expired = async {
+                match options.timeout {
+                    Some(timeout) => tokio::time::sleep(timeout).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::pin!(expired);
+
+            let mut seen = HashSet::new();
+            loop {
+                let event = tokio::select! {
+                    _ = options.cancel.cancelled() => break,
+                    _ = &mut expired => break,
+                    _ = sender.closed() => break,
+                    event = receiver.recv_async() => match event {
+                        Ok(event) => event,
+                        Err(_) => break,
+                    },
+                };
 
-        let start_time = std::time::Instant::now();
-        while start_time.elapsed() < timeout {
-            if let Ok(event) = receiver.recv_timeout(Duration::from_millis(500)) {
                 if let ServiceEvent::ServiceResolved(info) = event {
                     log::info!("Found device: {:#?}", info);
-                    if ipv4_only && info.get_addresses_v4().len() == 0 {
+                    if ipv4_only && info.get_addresses_v4().is_empty() {
                         continue;
                     }
                     match HapAccessory::try_from(&info) {
                         Ok(accessory) => {
                             log::debug!("Found accessory with ID: {}", accessory.id);
-                            accessories.insert(accessory.id.clone(), accessory);
+                            if seen.insert(accessory.id.clone()) && sender.send(accessory).is_err() {
+                                break;
+                            }
                         },
                         Err(error) => {
                             log::debug!("Failed to parse HapAccessory: {:?}. Error: {:?}", info, error);
@@ -62,9 +89,13 @@ impl HapDiscovery {
                     }
                 }
             }
-        }
 
-        Ok(accessories.into_values().collect())
+            if let Err(error) = mdns.shutdown() {
+                log::debug!("Failed to shut down mDNS daemon: {:?}", error);
+            }
+        });
+
+        Ok(accessories)
     }
 }
 
@@ -202,10 +233,11 @@ mod tests {
 
     #[test]
     fn test_hap_discovery() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
         let discovery = HapDiscovery::new().unwrap();
-        let accessories = discovery.start_discovery(true,Duration::from_secs(5)).unwrap();
-        
-        for accessory in accessories {
+        let mut accessories = runtime.block_on(async { discovery.start_discovery(true, DiscoveryOptions::default()) }).unwrap();
+
+        while let Some(accessory) = runtime.block_on(accessories.recv()) {
             println!("Found accessory: {:?}", accessory);
             println!("Is paired: {}", accessory.is_paired());
             println!("Is configured for WiFi: {}", accessory.is_configured_for_wifi());
@@ -2,9 +2,14 @@ mod discovery;
 mod tlv8;
 mod client;
 mod pairing;
+mod crypto;
+mod session;
+mod characteristic;
 
 pub use discovery::*;
 pub use tlv8::*;
 pub use client::*;
 pub use pairing::*;
+pub use session::*;
+pub use characteristic::*;
 
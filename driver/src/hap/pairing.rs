@@ -4,10 +4,48 @@ use sha2::Sha512;
 use rand::{rngs::OsRng, RngCore};
 use log::{info, debug, error};
 use reqwest::Client;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Serialize, Deserialize};
 
+use crate::hap::crypto::{derive_key, expect_state, field, key32, nonce, open, seal};
 use crate::hap::discovery::HapAccessory;
+use crate::hap::client::CONTROLLER_ID;
 use crate::hap::tlv8::{Tlv8Writer, Tlv8Reader, TlvType};
 
+/// The long-term keys both sides keep after pair-setup, needed to pair-verify every later session.
+///
+/// Holds our secret key, so whatever it is persisted in has to be kept private.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HapPairing {
+    /// The accessory's pairing identifier, its `id` as advertised.
+    pub accessory_id: String,
+    /// The accessory's long-term Ed25519 public key.
+    #[serde(with = "hex_key")]
+    pub accessory_ltpk: [u8; 32],
+    pub controller_id: String,
+    /// Our long-term Ed25519 secret key for this accessory.
+    #[serde(with = "hex_key")]
+    pub controller_ltsk: [u8; 32],
+}
+
+/// Keys as hex strings, so device records stay readable text.
+mod hex_key {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(key: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&key.iter().map(|byte| format!("{:02x}", byte)).collect::<String>())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 32], D::Error> {
+        let text = String::deserialize(deserializer)?;
+        let bytes = (0..text.len()).step_by(2)
+            .map(|i| text.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| serde::de::Error::custom("expected a hex string"))?;
+        bytes.try_into().map_err(|_| serde::de::Error::custom("expected 32 bytes"))
+    }
+}
+
 #[derive(Debug)]
 pub enum PairingMethod {
     PairSetup = 0x00,
@@ -47,13 +85,13 @@ impl<'a> PairSetup<'a> {
     pub fn new() -> Self {
         info!("Initializing PairSetup");
         PairSetup {
-            controller_id: "Domus",
+            controller_id: CONTROLLER_ID,
             http_client: Client::new(),
             srp_client: SrpClient::<'a, Sha512>::new(&G_3072),
         }
     }
 
-    pub async fn pair(&self, accessory: &HapAccessory, setup_code: &str) -> Result<(), Box<dyn Error>> {
+    pub async fn pair(&self, accessory: &HapAccessory, setup_code: &str) -> Result<HapPairing, Box<dyn Error>> {
         info!("Starting pairing process with accessory: {:#?}", accessory);
         let url = format!("http://{}:{}/pair-setup", accessory.address, accessory.port);
         debug!("Pairing URL: {}", url);
@@ -73,18 +111,25 @@ impl<'a> PairSetup<'a> {
 
         // M3: Send SRP verify request
         info!("Sending M3: SRP Verify Request");
-        let m3_response = self.send_m3(&url, setup_code, &salt, &public_key).await?;
+        let (m3_response, session_key) = self.send_m3(&url, setup_code, &salt, &public_key).await?;
         debug!("Received M4 response: {:?}", m3_response);
-        let (server_proof, encrypted_data) = self.handle_m4(m3_response)?;
+        self.handle_m4(m3_response)?;
 
         // M5: Send exchange request
         info!("Sending M5: Exchange Request");
-        let m5_response = self.send_m5(&url).await?;
+        let mut controller_ltsk = [0u8; 32];
+        OsRng.fill_bytes(&mut controller_ltsk);
+        let m5_response = self.send_m5(&url, &session_key, &controller_ltsk).await?;
         debug!("Received M6 response: {:?}", m5_response);
-        self.handle_m6(m5_response)?;
+        let (accessory_id, accessory_ltpk) = self.handle_m6(m5_response, &session_key)?;
 
         info!("Pairing process completed successfully");
-        Ok(())
+        Ok(HapPairing {
+            accessory_id,
+            accessory_ltpk,
+            controller_id: self.controller_id.to_string(),
+            controller_ltsk,
+        })
     }
 
     async fn send_m1(&self, url: &str) -> Result<Vec<(TlvType, Vec<u8>)>, Box<dyn Error>> {
@@ -152,7 +197,8 @@ impl<'a> PairSetup<'a> {
         Ok((salt, public_key))
     }
 
-    async fn send_m3(&self, url: &str, setup_code: &str, salt: &[u8], public_key: &[u8]) -> Result<Vec<(TlvType, Vec<u8>)>, Box<dyn Error>> {
+    /// Also returns the SRP session key, which the exchange in M5 and M6 is secured with.
+    async fn send_m3(&self, url: &str, setup_code: &str, salt: &[u8], public_key: &[u8]) -> Result<(Vec<(TlvType, Vec<u8>)>, Vec<u8>), Box<dyn Error>> {
         debug!("Preparing M3 request with setup code: {}", setup_code);
 
         // Format the setup code
@@ -214,65 +260,73 @@ impl<'a> PairSetup<'a> {
 
         let response_bytes = response.bytes().await?;
         let reader = Tlv8Reader::new(&response_bytes);
-        Ok(reader.read()?)
-    }
+        let items = reader.read()?;
 
-    fn handle_m4(&self, response: Vec<(TlvType, Vec<u8>)>) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
-        debug!("Handling M4 response");
-        
-        let mut state: Option<u8> = None;
-        let mut server_proof: Option<Vec<u8>> = None;
-        let mut encrypted_data: Option<Vec<u8>> = None;
-        let mut error: Option<u8> = None;
-
-        for (tlv_type, value) in response {
-            match tlv_type {
-                TlvType::State => {
-                    debug!("M4 State: {}", value[0]);
-                    state = Some(value[0]);
-                },
-                TlvType::Proof => {
-                    debug!("M4 Server Proof length: {} bytes", value.len());
-                    server_proof = Some(value);
-                },
-                TlvType::EncryptedData => {
-                    debug!("M4 Encrypted Data length: {} bytes", value.len());
-                    encrypted_data = Some(value);
-                },
-                TlvType::Error => {
-                    debug!("M4 Error: {}", value[0]);
-                    error = Some(value[0]);
-                },
-                _ => {
-                    debug!("Unexpected TLV type in M4 response: {:?}", tlv_type);
-                },
-            }
+        // the accessory proves it knows the setup code too before anything is exchanged
+        if let Some(server_proof) = field(&items, TlvType::Proof) {
+            verifier.verify_server(server_proof)
+                .map_err(|e| format!("Accessory failed to prove the setup code: {:?}", e))?;
         }
+        Ok((items, verifier.key().to_vec()))
+    }
 
-        if let Some(error) = error {
+    /// M4 carries the accessory's SRP proof, checked as soon as it arrives in `send_m3`.
+    fn handle_m4(&self, response: Vec<(TlvType, Vec<u8>)>) -> Result<(), Box<dyn Error>> {
+        debug!("Handling M4 response");
+        if let Some(&[error, ..]) = field(&response, TlvType::Error) {
             PairSetup::handle_error(error)?;
         }
-
-        let state = state.ok_or("M4 response missing state")?;
-        if state != PairingState::M4 as u8 {
-            return Err(format!("Unexpected state in M4 response: {}", state).into());
-        }
-
-        let server_proof = server_proof.ok_or("M4 response missing server_proof")?;
-        let encrypted_data = encrypted_data.ok_or("M4 response missing encrypted_data")?;
-
-        Ok((server_proof, encrypted_data))
+        expect_state(&response, PairingState::M4 as u8)?;
+        field(&response, TlvType::Proof).ok_or("M4 response missing server proof")?;
+        Ok(())
     }
 
-    async fn send_m5(&self, url: &str) -> Result<Vec<(TlvType, Vec<u8>)>, Box<dyn Error>> {
+    /// Sends our identifier and long-term public key, signed and encrypted with the session key.
+    async fn send_m5(&self, url: &str, session_key: &[u8], controller_ltsk: &[u8; 32]) -> Result<Vec<(TlvType, Vec<u8>)>, Box<dyn Error>> {
         debug!("Preparing M5 request");
-        // Implement M5 sending logic
-        todo!()
+        let signing_key = SigningKey::from_bytes(controller_ltsk);
+        let controller_ltpk = signing_key.verifying_key().to_bytes();
+        let controller_x = derive_key(session_key, b"Pair-Setup-Controller-Sign-Salt", b"Pair-Setup-Controller-Sign-Info");
+        let signature = signing_key.sign(&[&controller_x[..], self.controller_id.as_bytes(), &controller_ltpk].concat());
+
+        let mut sub_tlv = Tlv8Writer::new();
+        sub_tlv.add(TlvType::Identifier, self.controller_id.as_bytes());
+        sub_tlv.add(TlvType::PublicKey, &controller_ltpk);
+        sub_tlv.add(TlvType::Signature, &signature.to_bytes());
+        let encryption_key = derive_key(session_key, b"Pair-Setup-Encrypt-Salt", b"Pair-Setup-Encrypt-Info");
+        let encrypted = seal(&encryption_key, &nonce(b"PS-Msg05"), &[], &sub_tlv.to_vec());
+
+        let mut payload = Tlv8Writer::new();
+        payload.add(TlvType::State, &[PairingState::M5.into()]);
+        payload.add(TlvType::EncryptedData, &encrypted);
+
+        let response = self.http_client.post(url)
+            .body(payload.to_vec())
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(format!("Expected 200 OK response to M5, got {}", response.status()).into());
+        }
+        let response_bytes = response.bytes().await?;
+        Ok(Tlv8Reader::new(&response_bytes).read()?)
     }
 
-    fn handle_m6(&self, response: Vec<(TlvType, Vec<u8>)>) -> Result<(), Box<dyn Error>> {
-        // Handle M6 logic
-        Ok(())
+    /// Decrypts the accessory's identifier and long-term public key, and checks its signature over them.
+    fn handle_m6(&self, response: Vec<(TlvType, Vec<u8>)>, session_key: &[u8]) -> Result<(String, [u8; 32]), Box<dyn Error>> {
+        expect_state(&response, PairingState::M6 as u8)?;
+        let encrypted = field(&response, TlvType::EncryptedData).ok_or("M6 response missing encrypted data")?;
+        let encryption_key = derive_key(session_key, b"Pair-Setup-Encrypt-Salt", b"Pair-Setup-Encrypt-Info");
+        let sub_tlv = Tlv8Reader::new(&open(&encryption_key, &nonce(b"PS-Msg06"), &[], encrypted)?).read()?;
+
+        let accessory_id = field(&sub_tlv, TlvType::Identifier).ok_or("M6 missing accessory identifier")?;
+        let accessory_ltpk = key32(field(&sub_tlv, TlvType::PublicKey).ok_or("M6 missing accessory public key")?, "Accessory public key")?;
+        let signature = Signature::from_slice(field(&sub_tlv, TlvType::Signature).ok_or("M6 missing signature")?)?;
+        let accessory_x = derive_key(session_key, b"Pair-Setup-Accessory-Sign-Salt", b"Pair-Setup-Accessory-Sign-Info");
+        VerifyingKey::from_bytes(&accessory_ltpk)?
+            .verify(&[&accessory_x[..], accessory_id, &accessory_ltpk].concat(), &signature)
+            .map_err(|_| "Accessory signature in M6 doesn't match its public key")?;
+
+        Ok((String::from_utf8(accessory_id.to_vec())?, accessory_ltpk))
     }
 
     fn handle_error(error_code: u8) -> Result<(), Box<dyn Error>> {
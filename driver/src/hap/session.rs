@@ -0,0 +1,381 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::net::IpAddr;
+use std::time::Duration;
+use domus_core::CapabilityValue;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use log::debug;
+use rand::rngs::OsRng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+use crate::hap::characteristic::{CharacteristicAddress, CharacteristicType, CharacteristicWrite, characteristic_values};
+use crate::hap::crypto::{derive_key, expect_state, field, key32, nonce, open, seal};
+use crate::hap::pairing::{HapPairing, PairingState};
+use crate::hap::tlv8::{Tlv8Reader, Tlv8Writer, TlvType};
+
+/// How long connecting, verifying or a single request may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest plaintext in one encrypted frame.
+const MAX_FRAME: usize = 1024;
+const TAG_LENGTH: usize = 16;
+
+/// An HTTP message as HAP sends it: a response, an `EVENT/1.0` notification, or in tests a request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HapMessage {
+    /// The first line, e.g. `HTTP/1.1 200 OK` or `EVENT/1.0 200 OK`.
+    pub start: String,
+    pub body: Vec<u8>,
+}
+
+impl HapMessage {
+    pub fn is_event(&self) -> bool {
+        self.start.starts_with("EVENT/")
+    }
+
+    /// The status code, 0 for requests.
+    pub fn status(&self) -> u16 {
+        self.start.split(' ').nth(1).and_then(|status| status.parse().ok()).unwrap_or(0)
+    }
+}
+
+/// Keys and counters of the encrypted half of a session, one direction each.
+struct Cipher {
+    write_key: [u8; 32],
+    read_key: [u8; 32],
+    written: u64,
+    read: u64,
+}
+
+fn counter_nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+/// Splits the first complete message off `buffer`, with the length it took up, its body sized by
+/// `Content-Length` or sent chunked. `None` until all of it is there.
+fn parse(buffer: &[u8]) -> Result<Option<(HapMessage, usize)>, Box<dyn Error>> {
+    let Some(head_end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") else {
+        return Ok(None);
+    };
+    let head = std::str::from_utf8(&buffer[..head_end])?;
+    let start = head.split("\r\n").next().unwrap_or_default().to_string();
+    let header = |name: &str| head.split("\r\n").skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim());
+
+    let mut offset = head_end + 4;
+    if !header("Transfer-Encoding").is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked")) {
+        let length: usize = header("Content-Length").map(|length| length.parse()).transpose()?.unwrap_or(0);
+        if buffer.len() < offset + length {
+            return Ok(None);
+        }
+        return Ok(Some((HapMessage { start, body: buffer[offset..offset + length].to_vec() }, offset + length)));
+    }
+    let mut body = Vec::new();
+    loop {
+        let Some(line_end) = buffer[offset..].windows(2).position(|window| window == b"\r\n") else {
+            return Ok(None);
+        };
+        let size = std::str::from_utf8(&buffer[offset..offset + line_end])?.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| format!("Invalid chunk size {:?}", size))?;
+        let chunk = offset + line_end + 2;
+        if buffer.len() < chunk + size + 2 {
+            return Ok(None);
+        }
+        body.extend_from_slice(&buffer[chunk..chunk + size]);
+        offset = chunk + size + 2;
+        if size == 0 {
+            return Ok(Some((HapMessage { start, body }, offset)));
+        }
+    }
+}
+
+/// HTTP over a TCP connection, plain until the keys of a verified session are installed.
+///
+/// Receiving only takes what has fully arrived, so a receive that is given up on loses nothing.
+struct Connection {
+    stream: TcpStream,
+    cipher: Option<Cipher>,
+    /// Received frames not yet complete enough to decrypt.
+    encrypted: Vec<u8>,
+    /// Received plaintext not yet taken as a message.
+    buffer: Vec<u8>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        Connection { stream, cipher: None, encrypted: Vec::new(), buffer: Vec::new() }
+    }
+
+    async fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        let Some(cipher) = &mut self.cipher else {
+            self.stream.write_all(message).await?;
+            return Ok(());
+        };
+        let mut frames = Vec::with_capacity(message.len() + message.len() / MAX_FRAME * (2 + TAG_LENGTH) + 2 + TAG_LENGTH);
+        for chunk in message.chunks(MAX_FRAME) {
+            let length = (chunk.len() as u16).to_le_bytes();
+            frames.extend_from_slice(&length);
+            frames.extend(seal(&cipher.write_key, &counter_nonce(cipher.written), &length, chunk));
+            cipher.written += 1;
+        }
+        self.stream.write_all(&frames).await?;
+        Ok(())
+    }
+
+    /// Reads what the other side sent next, decrypting the frames that are complete once the session is encrypted.
+    async fn fill(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut chunk = [0u8; 4096];
+        let read = self.stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err("Accessory closed the connection".into());
+        }
+        let Some(cipher) = &mut self.cipher else {
+            self.buffer.extend_from_slice(&chunk[..read]);
+            return Ok(());
+        };
+        self.encrypted.extend_from_slice(&chunk[..read]);
+        while self.encrypted.len() >= 2 {
+            let frame = 2 + u16::from_le_bytes([self.encrypted[0], self.encrypted[1]]) as usize + TAG_LENGTH;
+            if self.encrypted.len() < frame {
+                break;
+            }
+            self.buffer.extend(open(&cipher.read_key, &counter_nonce(cipher.read), &self.encrypted[..2], &self.encrypted[2..frame])?);
+            self.encrypted.drain(..frame);
+            cipher.read += 1;
+        }
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<HapMessage, Box<dyn Error>> {
+        loop {
+            if let Some((message, length)) = parse(&self.buffer)? {
+                self.buffer.drain(..length);
+                return Ok(message);
+            }
+            self.fill().await?;
+        }
+    }
+}
+
+fn request(method: &str, path: &str, content_type: &str, body: &[u8]) -> Vec<u8> {
+    let mut message = format!("{} {} HTTP/1.1\r\nHost: hap\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n", method, path, content_type, body.len()).into_bytes();
+    message.extend_from_slice(body);
+    message
+}
+
+/// An encrypted session with a paired accessory, set up by pair-verify with the keys from pair-setup.
+///
+/// Accessories only take control requests such as `/pairings` and `/characteristics` over a verified session.
+pub struct HapSession {
+    connection: Connection,
+    /// Notifications that arrived while waiting for a response.
+    events: VecDeque<HapMessage>,
+}
+
+impl HapSession {
+    /// Connects and runs pair-verify, proving both sides still hold the keys they exchanged when pairing.
+    pub async fn verify(address: IpAddr, port: u16, pairing: &HapPairing) -> Result<Self, Box<dyn Error>> {
+        tokio::time::timeout(REQUEST_TIMEOUT, Self::verify_now(address, port, pairing)).await
+            .map_err(|_| format!("Pair-verify with {}:{} timed out", address, port))?
+    }
+
+    async fn verify_now(address: IpAddr, port: u16, pairing: &HapPairing) -> Result<Self, Box<dyn Error>> {
+        debug!("Verifying session with {} at {}:{}", pairing.accessory_id, address, port);
+        let mut connection = Connection::new(TcpStream::connect((address, port)).await?);
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let controller_public = PublicKey::from(&secret);
+
+        // M1: our ephemeral key
+        let mut m1 = Tlv8Writer::new();
+        m1.add(TlvType::State, &[PairingState::M1.into()]);
+        m1.add(TlvType::PublicKey, controller_public.as_bytes());
+        let m2 = Self::exchange(&mut connection, m1).await?;
+
+        // M2: the accessory's ephemeral key, and proof it holds its long-term key
+        expect_state(&m2, PairingState::M2 as u8)?;
+        let accessory_public = key32(field(&m2, TlvType::PublicKey).ok_or("M2 missing public key")?, "Accessory ephemeral key")?;
+        let shared = secret.diffie_hellman(&PublicKey::from(accessory_public));
+        let session_key = derive_key(shared.as_bytes(), b"Pair-Verify-Encrypt-Salt", b"Pair-Verify-Encrypt-Info");
+        let encrypted = field(&m2, TlvType::EncryptedData).ok_or("M2 missing encrypted data")?;
+        let sub_tlv = Tlv8Reader::new(&open(&session_key, &nonce(b"PV-Msg02"), &[], encrypted)?).read()?;
+        let accessory_id = field(&sub_tlv, TlvType::Identifier).ok_or("M2 missing accessory identifier")?;
+        if accessory_id != pairing.accessory_id.as_bytes() {
+            return Err(format!("Expected accessory {}, found {}", pairing.accessory_id, String::from_utf8_lossy(accessory_id)).into());
+        }
+        let signature = Signature::from_slice(field(&sub_tlv, TlvType::Signature).ok_or("M2 missing signature")?)?;
+        VerifyingKey::from_bytes(&pairing.accessory_ltpk)?
+            .verify(&[&accessory_public[..], accessory_id, controller_public.as_bytes()].concat(), &signature)
+            .map_err(|_| "Accessory signature doesn't match the key it was paired with")?;
+
+        // M3: our proof
+        let signing_key = SigningKey::from_bytes(&pairing.controller_ltsk);
+        let signature = signing_key.sign(&[controller_public.as_bytes(), pairing.controller_id.as_bytes(), &accessory_public[..]].concat());
+        let mut sub_tlv = Tlv8Writer::new();
+        sub_tlv.add(TlvType::Identifier, pairing.controller_id.as_bytes());
+        sub_tlv.add(TlvType::Signature, &signature.to_bytes());
+        let mut m3 = Tlv8Writer::new();
+        m3.add(TlvType::State, &[PairingState::M3.into()]);
+        m3.add(TlvType::EncryptedData, &seal(&session_key, &nonce(b"PV-Msg03"), &[], &sub_tlv.to_vec()));
+        expect_state(&Self::exchange(&mut connection, m3).await?, PairingState::M4 as u8)?;
+
+        connection.cipher = Some(Cipher {
+            write_key: derive_key(shared.as_bytes(), b"Control-Salt", b"Control-Write-Encryption-Key"),
+            read_key: derive_key(shared.as_bytes(), b"Control-Salt", b"Control-Read-Encryption-Key"),
+            written: 0,
+            read: 0,
+        });
+        debug!("Session with {} verified", pairing.accessory_id);
+        Ok(HapSession { connection, events: VecDeque::new() })
+    }
+
+    async fn exchange(connection: &mut Connection, payload: Tlv8Writer) -> Result<Vec<(TlvType, Vec<u8>)>, Box<dyn Error>> {
+        connection.send(&request("POST", "/pair-verify", "application/pairing+tlv8", &payload.to_vec())).await?;
+        let response = connection.receive().await?;
+        if response.status() != 200 {
+            return Err(format!("Expected 200 OK response to pair-verify, got {}", response.start).into());
+        }
+        Tlv8Reader::new(&response.body).read()
+    }
+
+    /// Sends a request over the session and waits for its response, keeping notifications that come first.
+    pub async fn request(&mut self, method: &str, path: &str, content_type: &str, body: &[u8]) -> Result<HapMessage, Box<dyn Error>> {
+        tokio::time::timeout(REQUEST_TIMEOUT, async {
+            self.connection.send(&request(method, path, content_type, body)).await?;
+            loop {
+                let message = self.connection.receive().await?;
+                if !message.is_event() {
+                    return Ok(message);
+                }
+                self.events.push_back(message);
+            }
+        }).await.map_err(|_| format!("{} {} timed out", method, path))?
+    }
+
+    /// Writes characteristic values. HAP answers 204 when all were taken, 207 with a status per characteristic otherwise.
+    pub async fn write_characteristics(&mut self, writes: &[CharacteristicWrite]) -> Result<(), Box<dyn Error>> {
+        let body = serde_json::to_vec(&serde_json::json!({ "characteristics": writes }))?;
+        let response = self.request("PUT", "/characteristics", "application/hap+json", &body).await?;
+        match response.status() {
+            204 => Ok(()),
+            207 => Err(format!("Accessory refused some writes: {}", String::from_utf8_lossy(&response.body)).into()),
+            _ => Err(format!("Accessory refused the writes: {}", response.start).into()),
+        }
+    }
+
+    /// Sets a capability through the accessory's characteristics, as found by `writable_characteristics`.
+    pub async fn set(&mut self, characteristics: &HashMap<CharacteristicType, CharacteristicAddress>, target: CapabilityValue) -> Result<(), Box<dyn Error>> {
+        let values = characteristic_values(target).ok_or_else(|| format!("{} can't be written to a HAP accessory", target.capability()))?;
+        let writes = values.into_iter()
+            .map(|(kind, value)| {
+                let address = *characteristics.get(&kind).ok_or_else(|| format!("The accessory has no writable {:?} characteristic", kind))?;
+                Ok(CharacteristicWrite { address, value })
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        self.write_characteristics(&writes).await
+    }
+
+    /// Waits for the next notification of a subscribed characteristic. Safe to give up on, say for a timeout.
+    pub async fn next_event(&mut self) -> Result<HapMessage, Box<dyn Error>> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(event);
+        }
+        loop {
+            let message = self.connection.receive().await?;
+            if message.is_event() {
+                return Ok(message);
+            }
+            debug!("Ignoring response nothing was waiting for: {}", message.start);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// The accessory's half of pair-verify, then one request answered.
+    async fn fake_accessory(listener: TcpListener, accessory_key: SigningKey, controller_key: VerifyingKey) -> HapMessage {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut connection = Connection::new(stream);
+        let respond = |body: Vec<u8>| {
+            let mut response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/pairing+tlv8\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+            response.extend(body);
+            response
+        };
+
+        let m1 = Tlv8Reader::new(&connection.receive().await.unwrap().body).read().unwrap();
+        let controller_public = key32(field(&m1, TlvType::PublicKey).unwrap(), "key").unwrap();
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let accessory_public = PublicKey::from(&secret);
+        let shared = secret.diffie_hellman(&PublicKey::from(controller_public));
+        let session_key = derive_key(shared.as_bytes(), b"Pair-Verify-Encrypt-Salt", b"Pair-Verify-Encrypt-Info");
+        let signature = accessory_key.sign(&[accessory_public.as_bytes(), &b"AA:BB"[..], &controller_public[..]].concat());
+        let mut sub_tlv = Tlv8Writer::new();
+        sub_tlv.add(TlvType::Identifier, b"AA:BB");
+        sub_tlv.add(TlvType::Signature, &signature.to_bytes());
+        let mut m2 = Tlv8Writer::new();
+        m2.add(TlvType::State, &[2]);
+        m2.add(TlvType::PublicKey, accessory_public.as_bytes());
+        m2.add(TlvType::EncryptedData, &seal(&session_key, &nonce(b"PV-Msg02"), &[], &sub_tlv.to_vec()));
+        connection.send(&respond(m2.to_vec())).await.unwrap();
+
+        let m3 = Tlv8Reader::new(&connection.receive().await.unwrap().body).read().unwrap();
+        let sub_tlv = Tlv8Reader::new(&open(&session_key, &nonce(b"PV-Msg03"), &[], field(&m3, TlvType::EncryptedData).unwrap()).unwrap()).read().unwrap();
+        let signature = Signature::from_slice(field(&sub_tlv, TlvType::Signature).unwrap()).unwrap();
+        controller_key.verify(&[&controller_public[..], b"Domus", accessory_public.as_bytes()].concat(), &signature).unwrap();
+        let mut m4 = Tlv8Writer::new();
+        m4.add(TlvType::State, &[4]);
+        connection.send(&respond(m4.to_vec())).await.unwrap();
+
+        // the accessory reads with the controller's write key and the other way round
+        connection.cipher = Some(Cipher {
+            write_key: derive_key(shared.as_bytes(), b"Control-Salt", b"Control-Read-Encryption-Key"),
+            read_key: derive_key(shared.as_bytes(), b"Control-Salt", b"Control-Write-Encryption-Key"),
+            written: 0,
+            read: 0,
+        });
+        let request = connection.receive().await.unwrap();
+        connection.send(b"EVENT/1.0 200 OK\r\nContent-Length: 2\r\n\r\n{}").await.unwrap();
+        let body = vec![b'x'; 3000];
+        let mut response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nbb8\r\n".to_vec();
+        response.extend(&body);
+        response.extend(b"\r\n0\r\n\r\n");
+        connection.send(&response).await.unwrap();
+        request
+    }
+
+    #[tokio::test]
+    async fn test_verified_session() {
+        let accessory_key = SigningKey::from_bytes(&[7; 32]);
+        let pairing = HapPairing {
+            accessory_id: "AA:BB".to_string(),
+            accessory_ltpk: accessory_key.verifying_key().to_bytes(),
+            controller_id: "Domus".to_string(),
+            controller_ltsk: [42; 32],
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accessory = tokio::spawn(fake_accessory(listener, accessory_key, SigningKey::from_bytes(&[42; 32]).verifying_key()));
+
+        let mut session = HapSession::verify("127.0.0.1".parse().unwrap(), port, &pairing).await.unwrap();
+        let response = session.request("POST", "/pairings", "application/pairing+tlv8", b"remove").await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body.len(), 3000);
+        assert_eq!(session.next_event().await.unwrap().body, b"{}");
+
+        let request = accessory.await.unwrap();
+        assert_eq!(request.start, "POST /pairings HTTP/1.1");
+        assert_eq!(request.body, b"remove");
+
+        // someone else's keys don't verify
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(fake_accessory(listener, SigningKey::from_bytes(&[8; 32]), SigningKey::from_bytes(&[42; 32]).verifying_key()));
+        assert!(HapSession::verify("127.0.0.1".parse().unwrap(), port, &pairing).await.is_err());
+    }
+}
@@ -1,13 +1,19 @@
 pub mod hap;
 
 pub mod aqara_fp2;
-pub use aqara_fp2::{AqaraFP2Discovery, AqaraFP2, AqaraFP2Driver};
+pub use aqara_fp2::{AqaraFP2Discovery, AqaraFP2Properties, AqaraFP2, AqaraFP2Driver};
 
+pub mod registry;
+pub use registry::DriverRegistry;
 
-use core::{Device, LifeCycle};
+pub mod sim;
+pub use sim::{Scenario, SimContactSensor, SimCurtains, SimLight, SimMotionSensor, SimThermometer};
 
 
-#[derive(Debug)]
+use domus_core::{Capabilities, Device, LifeCycle};
+
+
+#[derive(Debug, Default)]
 pub struct DummyDevice {
     pub device_type: &'static str,
     pub name: &'static str,
@@ -15,6 +21,8 @@ pub struct DummyDevice {
 
 impl Device for DummyDevice {}
 
+impl Capabilities for DummyDevice {}
+
 impl LifeCycle for DummyDevice {
     async fn init(&self) -> Result<(), Box<dyn std::error::Error>> {
         log::info!("Initializing {} device: {}", self.device_type, self.name);
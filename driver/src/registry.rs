@@ -0,0 +1,53 @@
+use domus_core::{DiscoveryInfo, DeviceProperties, Device, Driver, DriverInfo, DriverAdapter, DynDriver};
+use crate::AqaraFP2Driver;
+
+pub struct DriverRegistry {
+    drivers: Vec<Box<dyn DynDriver>>,
+}
+
+impl DriverRegistry {
+    pub fn new() -> Self {
+        DriverRegistry { drivers: Vec::new() }
+    }
+
+    /// A registry holding every driver that ships with domus.
+    pub fn builtin() -> Self {
+        let mut registry = DriverRegistry::new();
+        registry.register(AqaraFP2Driver::INFO, AqaraFP2Driver::new());
+        registry
+    }
+
+    pub fn register<T, I, P, D>(&mut self, info: DriverInfo, driver: T)
+    where
+        T: Driver<I, P, D> + 'static,
+        I: DiscoveryInfo + Send + 'static,
+        P: DeviceProperties + 'static,
+        D: Device + 'static,
+    {
+        if self.get(info.name).is_some() {
+            log::warn!("Driver {} is already registered, replacing it", info.name);
+            self.drivers.retain(|d| d.name() != info.name);
+        }
+        self.drivers.push(Box::new(DriverAdapter::new(info, driver)));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn DynDriver> {
+        self.drivers.iter()
+            .find(|d| d.name() == name)
+            .map(|d| d.as_ref())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn DynDriver> {
+        self.drivers.iter().map(|d| d.as_ref())
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.drivers.iter().map(|d| d.info().name).collect()
+    }
+}
+
+impl Default for DriverRegistry {
+    fn default() -> Self {
+        DriverRegistry::new()
+    }
+}
@@ -0,0 +1,389 @@
+use std::error::Error;
+use std::time::Duration;
+use domus_core::{
+    Brightness, Capabilities, Capability, CapabilityState, CapabilityValue, Contact, ContactState, Controllable, Device,
+    DynDevice, EventPublisher, Humidity, LifeCycle, LocalBoxFuture, Occupancy, OnOff, Space, Temperature, WindowCovering,
+};
+
+// Virtual devices for developing automations without the physical house. They behave like the real
+// drivers' devices, taking commands and reporting state, with the readings scripted by a `Scenario`.
+
+/// A light that follows its commands, dimmable unless `dimmable` is false.
+#[derive(Debug)]
+pub struct SimLight {
+    pub name: String,
+    pub dimmable: bool,
+    pub state: CapabilityState,
+}
+
+impl Default for SimLight {
+    fn default() -> Self {
+        SimLight { name: String::new(), dimmable: true, state: CapabilityState::default() }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SimMotionSensor {
+    pub name: String,
+    pub state: CapabilityState,
+}
+
+#[derive(Debug, Default)]
+pub struct SimContactSensor {
+    pub name: String,
+    pub state: CapabilityState,
+}
+
+/// Reads 21 °C and 45% humidity until a scenario says otherwise.
+#[derive(Debug, Default)]
+pub struct SimThermometer {
+    pub name: String,
+    pub state: CapabilityState,
+}
+
+/// Curtains that open and close at `speed` percent a second.
+#[derive(Debug)]
+pub struct SimCurtains {
+    pub name: String,
+    pub speed: u8,
+    pub state: CapabilityState,
+}
+
+impl Default for SimCurtains {
+    fn default() -> Self {
+        SimCurtains { name: String::new(), speed: 20, state: CapabilityState::default() }
+    }
+}
+
+/// State of a simulated device, `None` for any other device.
+fn simulated(device: &dyn DynDevice) -> Option<&CapabilityState> {
+    let device = device.as_any();
+    device.downcast_ref::<SimLight>().map(|d| &d.state)
+        .or_else(|| device.downcast_ref::<SimMotionSensor>().map(|d| &d.state))
+        .or_else(|| device.downcast_ref::<SimContactSensor>().map(|d| &d.state))
+        .or_else(|| device.downcast_ref::<SimThermometer>().map(|d| &d.state))
+        .or_else(|| device.downcast_ref::<SimCurtains>().map(|d| &d.state))
+}
+
+/// Has a simulated device report `value` as if it had sensed it, `false` if the device isn't simulated.
+pub fn inject(device: &dyn DynDevice, value: CapabilityValue) -> bool {
+    simulated(device).map(|state| state.set(value)).is_some()
+}
+
+/// A simulated device standing in for one of a driver with `capabilities`, e.g. to replay a recording
+/// of the real house. `None` if none of the simulated devices can.
+pub fn stand_in(name: &str, capabilities: &[Capability]) -> Option<Box<dyn DynDevice>> {
+    let name = name.to_string();
+    let has = |capability| capabilities.contains(&capability);
+    Some(if has(Capability::WindowCovering) {
+        Box::new(SimCurtains { name, ..Default::default() })
+    } else if has(Capability::OnOff) || has(Capability::Brightness) {
+        Box::new(SimLight { name, dimmable: has(Capability::Brightness), ..Default::default() })
+    } else if has(Capability::Occupancy) {
+        Box::new(SimMotionSensor { name, ..Default::default() })
+    } else if has(Capability::Contact) {
+        Box::new(SimContactSensor { name, ..Default::default() })
+    } else if has(Capability::Temperature) || has(Capability::Humidity) {
+        Box::new(SimThermometer { name, ..Default::default() })
+    } else {
+        return None;
+    })
+}
+
+/// Implements `LifeCycle` and `Device` for a simulated device, reporting `initial` on init for what wasn't restored.
+macro_rules! simulated_device {
+    ($device:ident, [$($initial:expr),*]) => {
+        impl LifeCycle for $device {
+            async fn init(&self) -> Result<(), Box<dyn Error>> {
+                log::info!("Initializing simulated {}: {}", stringify!($device), self.name);
+                for initial in [$($initial),*] {
+                    if self.state.get(initial.capability()).is_none() {
+                        self.state.set(initial);
+                    }
+                }
+                Ok(())
+            }
+
+            async fn dispose(&self) -> Result<(), Box<dyn Error>> {
+                log::info!("Disposing simulated {}: {}", stringify!($device), self.name);
+                Ok(())
+            }
+        }
+
+        impl Device for $device {
+            fn bind_events(&self, publisher: EventPublisher) {
+                self.state.bind(publisher);
+            }
+
+            fn restore(&self, value: CapabilityValue) {
+                self.state.set(value);
+            }
+        }
+    };
+}
+
+simulated_device!(SimLight, [CapabilityValue::OnOff(false), CapabilityValue::Brightness(100)]);
+simulated_device!(SimMotionSensor, [CapabilityValue::Occupancy(false)]);
+simulated_device!(SimContactSensor, [CapabilityValue::Contact(ContactState::Closed)]);
+simulated_device!(SimThermometer, [CapabilityValue::Temperature(21.0), CapabilityValue::Humidity(45.0)]);
+simulated_device!(SimCurtains, [CapabilityValue::WindowCovering(0)]);
+
+impl OnOff for SimLight {
+    fn is_on(&self) -> Option<bool> {
+        self.state.is_on()
+    }
+}
+
+impl Brightness for SimLight {
+    fn brightness(&self) -> Option<u8> {
+        self.state.brightness()
+    }
+}
+
+impl Controllable for SimLight {
+    fn execute(&self, target: CapabilityValue) -> LocalBoxFuture<'_, Result<(), Box<dyn Error>>> {
+        Box::pin(async move {
+            match target {
+                CapabilityValue::OnOff(_) => {
+                    self.state.set(target);
+                },
+                CapabilityValue::Brightness(brightness) if self.dimmable => {
+                    self.state.set(target);
+                    self.state.set(CapabilityValue::OnOff(brightness > 0));
+                },
+                _ => return Err(format!("{} can't be set on a simulated light", target.capability()).into()),
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Capabilities for SimLight {
+    fn as_on_off(&self) -> Option<&dyn OnOff> { Some(self) }
+    fn as_brightness(&self) -> Option<&dyn Brightness> { self.dimmable.then_some(self) }
+    fn as_controllable(&self) -> Option<&dyn Controllable> { Some(self) }
+}
+
+impl Occupancy for SimMotionSensor {
+    fn occupied(&self) -> Option<bool> {
+        self.state.occupied()
+    }
+}
+
+impl Capabilities for SimMotionSensor {
+    fn as_occupancy(&self) -> Option<&dyn Occupancy> { Some(self) }
+}
+
+impl Contact for SimContactSensor {
+    fn contact(&self) -> Option<ContactState> {
+        self.state.contact()
+    }
+}
+
+impl Capabilities for SimContactSensor {
+    fn as_contact(&self) -> Option<&dyn Contact> { Some(self) }
+}
+
+impl Temperature for SimThermometer {
+    fn temperature(&self) -> Option<f32> {
+        self.state.temperature()
+    }
+}
+
+impl Humidity for SimThermometer {
+    fn humidity(&self) -> Option<f32> {
+        self.state.humidity()
+    }
+}
+
+impl Capabilities for SimThermometer {
+    fn as_temperature(&self) -> Option<&dyn Temperature> { Some(self) }
+    fn as_humidity(&self) -> Option<&dyn Humidity> { Some(self) }
+}
+
+impl WindowCovering for SimCurtains {
+    fn position(&self) -> Option<u8> {
+        self.state.position()
+    }
+}
+
+impl Controllable for SimCurtains {
+    /// Moves a step a second towards the target, reporting every position on the way.
+    fn execute(&self, target: CapabilityValue) -> LocalBoxFuture<'_, Result<(), Box<dyn Error>>> {
+        Box::pin(async move {
+            let CapabilityValue::WindowCovering(target) = target else {
+                return Err(format!("{} can't be set on simulated curtains", target.capability()).into());
+            };
+            let target = target.min(100);
+            let mut position = self.state.position().unwrap_or(0);
+            while position != target {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                position = if position < target {
+                    position.saturating_add(self.speed).min(target)
+                } else {
+                    position.saturating_sub(self.speed).max(target)
+                };
+                self.state.set(CapabilityValue::WindowCovering(position));
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Capabilities for SimCurtains {
+    fn as_window_covering(&self) -> Option<&dyn WindowCovering> { Some(self) }
+    fn as_controllable(&self) -> Option<&dyn Controllable> { Some(self) }
+}
+
+#[derive(Debug, Clone)]
+enum Step {
+    Report { device: String, value: CapabilityValue },
+    /// Every motion sensor directly in the space.
+    Motion { space: String, occupied: bool },
+}
+
+/// A script of what happens in a simulated house, e.g. someone walking from the hallway to the kitchen.
+///
+/// Delays are on the runtime's clock, so a scenario played on a paused clock runs as fast as that moves.
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    pub name: String,
+    steps: Vec<(Duration, Step)>,
+}
+
+impl Scenario {
+    pub fn new(name: &str) -> Self {
+        Scenario { name: name.to_string(), steps: Vec::new() }
+    }
+
+    /// The simulated device at `device` reports `value`, `delay` after the previous step.
+    pub fn report(mut self, delay: Duration, device: &str, value: CapabilityValue) -> Self {
+        self.steps.push((delay, Step::Report { device: device.to_string(), value }));
+        self
+    }
+
+    /// Someone enters the space, setting off its motion sensors.
+    pub fn enter(mut self, delay: Duration, space: &str) -> Self {
+        self.steps.push((delay, Step::Motion { space: space.to_string(), occupied: true }));
+        self
+    }
+
+    /// The last person leaves the space, its motion sensors going quiet.
+    pub fn leave(mut self, delay: Duration, space: &str) -> Self {
+        self.steps.push((delay, Step::Motion { space: space.to_string(), occupied: false }));
+        self
+    }
+
+    /// Someone walks through `spaces` in order, staying `dwell` in each before moving on.
+    pub fn walk(mut self, delay: Duration, spaces: &[&str], dwell: Duration) -> Self {
+        let mut previous: Option<&str> = None;
+        for (i, space) in spaces.iter().enumerate() {
+            self = self.enter(if i == 0 { delay } else { dwell }, space);
+            if let Some(previous) = previous {
+                self = self.leave(Duration::ZERO, previous);
+            }
+            previous = Some(space);
+        }
+        self
+    }
+
+    /// Virtual time from the start to the last step.
+    pub fn duration(&self) -> Duration {
+        self.steps.iter().map(|(delay, _)| *delay).sum()
+    }
+
+    /// Plays the scenario against `root`, failing on the first step that names something the tree doesn't simulate.
+    pub async fn run(&self, root: &dyn Space) -> Result<(), Box<dyn Error>> {
+        log::info!("Playing scenario {} ({:?})", self.name, self.duration());
+        for (delay, step) in &self.steps {
+            tokio::time::sleep(*delay).await;
+            match step {
+                Step::Report { device, value } => {
+                    let state = root.find_device(device).and_then(simulated)
+                        .ok_or_else(|| format!("Scenario {}: {} isn't a simulated device", self.name, device))?;
+                    state.set(*value);
+                },
+                Step::Motion { space, occupied } => {
+                    let sensors: Vec<_> = root.find_space(space).into_iter()
+                        .flat_map(|space| space.devices())
+                        .filter_map(|(_, device)| device.as_any().downcast_ref::<SimMotionSensor>())
+                        .collect();
+                    if sensors.is_empty() {
+                        return Err(format!("Scenario {}: {} has no simulated motion sensor", self.name, space).into());
+                    }
+                    for sensor in sensors {
+                        sensor.state.set(CapabilityValue::Occupancy(*occupied));
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domus_core::{Metadata, SpacePresence};
+
+    #[derive(Default)]
+    struct Room {
+        presence: SpacePresence,
+        metadata: Metadata,
+        motion_sensor: SimMotionSensor,
+        curtains: SimCurtains,
+        rooms: Vec<(&'static str, Room)>,
+    }
+
+    impl LifeCycle for Room {
+        async fn init(&self) -> Result<(), Box<dyn Error>> { Ok(()) }
+        async fn dispose(&self) -> Result<(), Box<dyn Error>> { Ok(()) }
+    }
+
+    impl Space for Room {
+        fn name(&self) -> &str { "Room" }
+        fn presence(&self) -> &SpacePresence { &self.presence }
+        fn metadata(&self) -> &Metadata { &self.metadata }
+        fn device_metadata(&self, _key: &str) -> Option<&Metadata> { None }
+
+        fn sub_spaces(&self) -> Vec<(&str, &dyn Space)> {
+            self.rooms.iter().map(|(key, room)| (*key, room as &dyn Space)).collect()
+        }
+
+        fn devices(&self) -> Vec<(&str, &dyn DynDevice)> {
+            vec![("motion_sensor", &self.motion_sensor), ("curtains", &self.curtains)]
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scenario() {
+        let house = Room {
+            rooms: vec![("hallway", Room::default()), ("kitchen", Room::default())],
+            ..Default::default()
+        };
+        let root: &dyn Space = &house;
+        let occupied = |path: &str| root.find_device(path).unwrap().as_occupancy().unwrap().occupied();
+
+        let scenario = Scenario::new("Morning")
+            .walk(Duration::from_secs(60), &["hallway", "kitchen"], Duration::from_secs(120))
+            .report(Duration::from_secs(30), "kitchen/curtains", CapabilityValue::WindowCovering(50));
+        assert_eq!(scenario.duration(), Duration::from_secs(210));
+
+        let start = tokio::time::Instant::now();
+        scenario.run(root).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(210));
+        assert_eq!(occupied("hallway/motion_sensor"), Some(false));
+        assert_eq!(occupied("kitchen/motion_sensor"), Some(true));
+        assert_eq!(house.rooms[1].1.curtains.position(), Some(50));
+
+        let missing = Scenario::new("Garden").enter(Duration::ZERO, "garden");
+        assert!(missing.run(root).await.is_err());
+
+        // curtains travel at their speed
+        let curtains: &dyn DynDevice = &house.rooms[1].1.curtains;
+        let start = tokio::time::Instant::now();
+        curtains.command(CapabilityValue::WindowCovering(100), Default::default()).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+        assert_eq!(curtains.as_window_covering().unwrap().position(), Some(100));
+    }
+}
@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote, quote_spanned, ToTokens};
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::{braced, Attribute, Error, Expr, Ident, LitStr, Path, Token};
+
+/// Fields every generated space has, so they can't name a space or device.
+const RESERVED: [&str; 3] = ["presence", "metadata", "device_metadata"];
+
+/// Tags and metadata given as `#[tags("a", "b")]` and `#[metadata(key = "value")]`.
+#[derive(Default)]
+struct Metadata {
+    tags: Vec<LitStr>,
+    values: Vec<(Ident, LitStr)>,
+}
+
+struct MetadataValue {
+    key: Ident,
+    value: LitStr,
+}
+
+impl Parse for MetadataValue {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key = input.parse()?;
+        input.parse::<Token![=]>()?;
+        Ok(MetadataValue { key, value: input.parse()? })
+    }
+}
+
+impl Metadata {
+    fn parse(attributes: &[Attribute]) -> syn::Result<Self> {
+        let mut metadata = Metadata::default();
+        for attribute in attributes {
+            if attribute.path().is_ident("tags") {
+                metadata.tags.extend(attribute.parse_args_with(Punctuated::<LitStr, Token![,]>::parse_terminated)?);
+            } else if attribute.path().is_ident("metadata") {
+                let values = attribute.parse_args_with(Punctuated::<MetadataValue, Token![,]>::parse_terminated)?;
+                metadata.values.extend(values.into_iter().map(|value| (value.key, value.value)));
+            } else {
+                return Err(Error::new_spanned(attribute.path(), "Unknown attribute, expected `tags` or `metadata`"));
+            }
+        }
+        Ok(metadata)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.values.is_empty()
+    }
+
+    fn expand(&self) -> TokenStream2 {
+        if self.is_empty() {
+            return quote!(domus_core::Metadata::default());
+        }
+        let tags = &self.tags;
+        let keys = self.values.iter().map(|(key, _)| key.to_string());
+        let values = self.values.iter().map(|(_, value)| value);
+        quote! {
+            domus_core::Metadata {
+                tags: vec![#(#tags.to_string()),*],
+                values: [#((#keys.to_string(), #values.to_string())),*].into_iter().collect(),
+            }
+        }
+    }
+}
+
+struct Field {
+    metadata: Metadata,
+    key: Ident,
+    node: Node,
+}
+
+enum Node {
+    Space(SpaceNode),
+    /// A `DeviceGroup`, parsed like a space whose fields are its members.
+    Group(SpaceNode),
+    Device(DeviceNode),
+}
+
+struct SpaceNode {
+    name: Expr,
+    fields: Vec<Field>,
+}
+
+struct DeviceNode {
+    device_type: Path,
+    properties: Vec<(Ident, Expr)>,
+}
+
+/// Parses the inside of a space: its `name` and its fields, in any order.
+/// `span` is where a missing name is reported.
+fn parse_space(input: ParseStream, span: Span, what: &str) -> syn::Result<SpaceNode> {
+    let mut name = None;
+    let mut fields: Vec<Field> = Vec::new();
+    while !input.is_empty() {
+        let attributes = input.call(Attribute::parse_outer)?;
+        let key: Ident = input.parse()?;
+        input.parse::<Token![:]>()?;
+
+        if key == "name" {
+            if let Some(attribute) = attributes.first() {
+                return Err(Error::new_spanned(attribute, "Attributes go on spaces and devices, not on `name`"));
+            }
+            if name.is_some() {
+                return Err(Error::new(key.span(), "Duplicate `name`"));
+            }
+            name = Some(input.parse()?);
+        } else {
+            if RESERVED.contains(&key.to_string().as_str()) {
+                return Err(Error::new(key.span(), format!("`{}` is taken by the generated space, pick another name", key)));
+            }
+            if fields.iter().any(|field| field.key == key) {
+                return Err(Error::new(key.span(), format!("Duplicate field `{}`", key)));
+            }
+            let metadata = Metadata::parse(&attributes)?;
+            let field_type: Path = input.parse()?;
+            let content;
+            braced!(content in input);
+            let node = if field_type.is_ident("Space") {
+                Node::Space(parse_space(&content, field_type.span(), &format!("Space `{}`", key))?)
+            } else if field_type.is_ident("Group") {
+                Node::Group(parse_group(&content, field_type.span(), &key)?)
+            } else {
+                Node::Device(parse_device(&content, field_type)?)
+            };
+            fields.push(Field { metadata, key, node });
+        }
+
+        if input.is_empty() {
+            break;
+        }
+        input.parse::<Token![,]>()?;
+    }
+    let name = name.ok_or_else(|| Error::new(span, format!("{} needs a `name`", what)))?;
+    Ok(SpaceNode { name, fields })
+}
+
+fn parse_group(input: ParseStream, span: Span, key: &Ident) -> syn::Result<SpaceNode> {
+    let group = parse_space(input, span, &format!("Group `{}`", key))?;
+    for member in &group.fields {
+        if let Node::Space(_) = member.node {
+            return Err(Error::new(member.key.span(), format!("Group `{}` can only hold devices", key)));
+        }
+        if !member.metadata.is_empty() {
+            return Err(Error::new(member.key.span(), "Tags and metadata go on the group, not on its members"));
+        }
+    }
+    Ok(group)
+}
+
+fn parse_device(input: ParseStream, device_type: Path) -> syn::Result<DeviceNode> {
+    let mut properties: Vec<(Ident, Expr)> = Vec::new();
+    while !input.is_empty() {
+        let key: Ident = input.parse()?;
+        if properties.iter().any(|(k, _)| *k == key) {
+            return Err(Error::new(key.span(), format!("Duplicate property `{}`", key)));
+        }
+        input.parse::<Token![:]>()?;
+        properties.push((key, input.parse()?));
+
+        if input.is_empty() {
+            break;
+        }
+        input.parse::<Token![,]>()?;
+    }
+    Ok(DeviceNode { device_type, properties })
+}
+
+struct Domus {
+    metadata: Metadata,
+    space: SpaceNode,
+}
+
+impl Parse for Domus {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let metadata = Metadata::parse(&input.call(Attribute::parse_outer)?)?;
+        let space = parse_space(input, Span::call_site(), "The house")?;
+        Ok(Domus { metadata, space })
+    }
+}
+
+/// `living_room` becomes `LivingRoom`.
+fn camel_case(key: &Ident) -> Ident {
+    let name: String = key.to_string().split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next().map(|first| first.to_uppercase().chain(chars).collect::<String>()).unwrap_or_default()
+        })
+        .collect();
+    format_ident!("{}", name, span = key.span())
+}
+
+/// The expression building a device, or a group with its members.
+fn device_value(node: &Node) -> TokenStream2 {
+    match node {
+        Node::Device(device) => {
+            let device_type = &device.device_type;
+            let (properties, property_values): (Vec<_>, Vec<_>) = device.properties.iter().map(|(k, v)| (k, v)).unzip();
+            // properties already of the field's type go through `into` as well
+            quote_spanned! {device_type.span()=>
+                {
+                    #[allow(clippy::useless_conversion)]
+                    let device = #device_type {
+                        #(#properties: (#property_values).into(),)*
+                        ..domus_core::declared_defaults()
+                    };
+                    device
+                }
+            }
+        },
+        Node::Group(group) => {
+            let name = &group.name;
+            let keys = group.fields.iter().map(|member| member.key.to_string());
+            let members = group.fields.iter().map(|member| device_value(&member.node));
+            quote_spanned! {name.span()=>
+                domus_core::DeviceGroup::new(#name)
+                    #(.member(#keys, #members))*
+            }
+        },
+        Node::Space(_) => unreachable!("spaces aren't devices"),
+    }
+}
+
+/// `Office` gets its handles in `OfficeHandle`.
+fn handle_name(struct_name: &Ident) -> Ident {
+    format_ident!("{}Handle", struct_name)
+}
+
+struct Expansion {
+    items: Vec<TokenStream2>,
+    /// Generated struct names, with the field that claimed each.
+    structs: HashMap<String, Option<Ident>>,
+}
+
+impl Expansion {
+    /// Generates the struct for a space and everything below it, returning the expression building it.
+    fn space(&mut self, struct_name: Ident, path: &str, space: &SpaceNode, metadata: &Metadata, root: bool) -> syn::Result<TokenStream2> {
+        let mut keys = Vec::new();
+        let mut handle_types = Vec::new();
+        let mut handles = Vec::new();
+        let mut types = Vec::new();
+        let mut values = Vec::new();
+        let mut sub_spaces = Vec::new();
+        let mut devices = Vec::new();
+        let mut device_metadata = Vec::new();
+
+        for field in &space.fields {
+            let key = &field.key;
+            let key_name = key.to_string();
+            let key_path = if path.is_empty() { key_name.clone() } else { format!("{}/{}", path, key_name) };
+            match &field.node {
+                Node::Space(sub_space) => {
+                    let sub_struct = camel_case(key);
+                    let sub_handle = handle_name(&sub_struct);
+                    for name in [&sub_struct, &sub_handle] {
+                        if let Some(other) = self.structs.insert(name.to_string(), Some(key.clone())) {
+                            let other = other.map(|other| format!("`{}`", other)).unwrap_or_else(|| "the house".to_string());
+                            return Err(Error::new(key.span(), format!("Space `{}` would generate struct `{}` like {} does, rename one of them", key, name, other)));
+                        }
+                    }
+                    values.push(self.space(sub_struct.clone(), &key_path, sub_space, &field.metadata, false)?);
+                    types.push(quote!(#sub_struct));
+                    handle_types.push(quote!(#sub_handle));
+                    handles.push(quote!(#sub_struct::HANDLE));
+                    sub_spaces.push(quote!((#key_name, &self.#key as &dyn domus_core::Space)));
+                },
+                Node::Group(_) | Node::Device(_) => {
+                    let device_type = match &field.node {
+                        Node::Device(device) => device.device_type.to_token_stream(),
+                        _ => quote!(domus_core::DeviceGroup),
+                    };
+                    values.push(device_value(&field.node));
+                    handle_types.push(quote!(domus_core::DeviceHandle<#device_type>));
+                    handles.push(quote!(domus_core::DeviceHandle::new(#key_path)));
+                    devices.push(quote_spanned!(device_type.span()=> (#key_name, &self.#key as &dyn domus_core::DynDevice)));
+                    types.push(device_type);
+                    let metadata = field.metadata.expand();
+                    device_metadata.push(quote!((#key_name, #metadata)));
+                },
+            }
+            keys.push(key);
+        }
+
+        let (name_type, visibility) = if root { (quote!(String), quote!()) } else { (quote!(&'static str), quote!(pub)) };
+        let display_name = &space.name;
+        let name = if root { quote!((#display_name).to_string()) } else { quote!(#display_name) };
+        let metadata = metadata.expand();
+        let handle_struct = handle_name(&struct_name);
+
+        self.items.push(quote! {
+            #[derive(Debug)]
+            #[allow(unused)]
+            struct #struct_name {
+                name: #name_type,
+                presence: domus_core::SpacePresence,
+                metadata: domus_core::Metadata,
+                device_metadata: Vec<(&'static str, domus_core::Metadata)>,
+                #(#visibility #keys: #types,)*
+            }
+
+            /// Path and typed handles of the space and everything below it, by field.
+            #[derive(Debug, Clone, Copy)]
+            #[allow(unused)]
+            struct #handle_struct {
+                #(pub #keys: #handle_types,)*
+            }
+
+            #[allow(unused)]
+            impl #handle_struct {
+                pub const PATH: &'static str = #path;
+
+                pub const fn path(&self) -> &'static str {
+                    Self::PATH
+                }
+
+                pub fn resolve<'a>(&self, root: &'a dyn domus_core::Space) -> Option<&'a dyn domus_core::Space> {
+                    root.find_space(Self::PATH)
+                }
+            }
+
+            impl domus_core::SpaceHandle for #handle_struct {
+                fn path(&self) -> &'static str {
+                    Self::PATH
+                }
+            }
+
+            #[allow(unused)]
+            impl #struct_name {
+                pub const PATH: &'static str = #path;
+                pub const HANDLE: #handle_struct = #handle_struct {
+                    #(#keys: #handles,)*
+                };
+
+                /// Handles to everything below this space, checked against the tree at compile time.
+                pub const fn handle(&self) -> #handle_struct {
+                    Self::HANDLE
+                }
+            }
+
+            impl domus_core::Space for #struct_name {
+                fn name(&self) -> &str {
+                    &self.name
+                }
+
+                fn presence(&self) -> &domus_core::SpacePresence {
+                    &self.presence
+                }
+
+                fn metadata(&self) -> &domus_core::Metadata {
+                    &self.metadata
+                }
+
+                fn device_metadata(&self, key: &str) -> Option<&domus_core::Metadata> {
+                    self.device_metadata.iter()
+                        .find(|(k, _)| *k == key)
+                        .map(|(_, metadata)| metadata)
+                }
+
+                fn sub_spaces(&self) -> Vec<(&str, &dyn domus_core::Space)> {
+                    vec![#(#sub_spaces),*]
+                }
+
+                fn devices(&self) -> Vec<(&str, &dyn domus_core::DynDevice)> {
+                    vec![#(#devices),*]
+                }
+            }
+
+            impl domus_core::LifeCycle for #struct_name {
+                async fn init(&self) -> Result<(), Box<dyn std::error::Error>> {
+                    log::info!("Initializing {}", self.name);
+                    #(domus_core::DynLifeCycle::init(&self.#keys).await?;)*
+                    Ok(())
+                }
+
+                async fn dispose(&self) -> Result<(), Box<dyn std::error::Error>> {
+                    log::info!("Disposing {}", self.name);
+                    #(domus_core::DynLifeCycle::dispose(&self.#keys).await?;)*
+                    Ok(())
+                }
+            }
+        });
+
+        Ok(quote! {
+            #struct_name {
+                name: #name,
+                presence: Default::default(),
+                metadata: #metadata,
+                device_metadata: vec![#(#device_metadata),*],
+                #(#keys: #values,)*
+            }
+        })
+    }
+}
+
+fn expand(domus: &Domus) -> syn::Result<TokenStream2> {
+    let root = format_ident!("Domus");
+    let mut expansion = Expansion {
+        items: Vec::new(),
+        structs: HashMap::from([(root.to_string(), None), (handle_name(&root).to_string(), None)]),
+    };
+    let value = expansion.space(root, "", &domus.space, &domus.metadata, true)?;
+    let items = expansion.items;
+    Ok(quote! {
+        {
+            #(#items)*
+            #value
+        }
+    })
+}
+
+/// Describes a house as nested spaces and devices, evaluating to a value implementing `Space`.
+///
+/// ```ignore
+/// let apartment = domus! {
+///     name: "Apartment",
+///
+///     #[tags("work")]
+///     office: Space {
+///         name: "Office",
+///         #[metadata(zone = "desk")]
+///         motion_sensor: AqaraFP2 {
+///             name: "Office motion sensor"
+///         }
+///     }
+/// };
+/// ```
+///
+/// Every space becomes a struct named after its field, devices are built from their properties and
+/// `Default` for the rest. `handle()` on a space gives typed handles to everything below it, e.g.
+/// `apartment.handle().office.motion_sensor` is a `DeviceHandle<AqaraFP2>` for `"office/motion_sensor"`.
+///
+/// `curtains: Group { name: "Curtains", window: Curtain {}, balcony: Curtain {} }` declares a
+/// `DeviceGroup` acting for its members, which are written like the devices of a space.
+#[proc_macro]
+pub fn domus(input: TokenStream) -> TokenStream {
+    let domus = syn::parse_macro_input!(input as Domus);
+    expand(&domus).unwrap_or_else(Error::into_compile_error).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(input: TokenStream2) -> String {
+        match syn::parse2::<Domus>(input).and_then(|domus| expand(&domus)) {
+            Ok(_) => panic!("Expected an error"),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let empty = syn::parse2::<Domus>(quote!(name: "Apartment", hallway: Space { name: "Hallway" }, lamp: Lamp {})).unwrap();
+        assert!(expand(&empty).is_ok());
+
+        assert_eq!(error(quote!(office: Space { name: "Office" })), "The house needs a `name`");
+        assert_eq!(error(quote!(name: "Apartment", office: Space { lamp: Lamp {} })), "Space `office` needs a `name`");
+        assert_eq!(error(quote!(name: "Apartment", lamp: Lamp {}, lamp: Lamp {})), "Duplicate field `lamp`");
+        assert_eq!(error(quote!(name: "Apartment", lamp: Lamp { ip: "a", ip: "b" })), "Duplicate property `ip`");
+        assert_eq!(error(quote!(name: "Apartment", #[colour("red")] lamp: Lamp {})), "Unknown attribute, expected `tags` or `metadata`");
+        assert_eq!(
+            error(quote!(name: "Apartment", kitchen: Space { name: "Kitchen" }, hall: Space { name: "Hall", kitchen: Space { name: "Kitchenette" } })),
+            "Space `kitchen` would generate struct `Kitchen` like `kitchen` does, rename one of them",
+        );
+        assert_eq!(
+            error(quote!(name: "Apartment", curtains: Group { name: "Curtains", kitchen: Space { name: "Kitchen" } })),
+            "Group `curtains` can only hold devices",
+        );
+        assert_eq!(
+            error(quote!(name: "Apartment", office: Space { name: "Office" }, office_handle: Space { name: "Office handle" })),
+            "Space `office_handle` would generate struct `OfficeHandle` like `office` does, rename one of them",
+        );
+    }
+}